@@ -0,0 +1,159 @@
+//! Built-in rules: a target with `rule = "<name>"` is expanded into ready-made
+//! targets with their commands, inputs, outputs and depfiles filled in
+//!
+//! - `cc_library` / `cc_binary`: see [`cc`](super::cc)
+//! - `rust_library` / `rust_binary` / `cargo_crate`: see [`rust`](super::rust)
+//!
+//! Expansion runs after `matrix`, so each variant is expanded on its own.
+
+use crate::core::{BuildSpecError, RULE_SEPARATOR, cc, replace_in_strings, rust};
+use crate::utils::template::shell_quote;
+use std::path::Path;
+
+/** Every built-in rule, in the order they are listed in errors */
+const BUILTIN_RULES: &[&str] = &[
+    "cargo_crate",
+    "cc_binary",
+    "cc_library",
+    "rust_binary",
+    "rust_library",
+];
+
+/**
+ * Fields every built-in rule sets itself; `cwd` is among them since sources
+ * are relative to the project root
+ */
+const GENERATED_FIELDS: &[&str] = &["cmd", "inputs", "depfile", "kind", "cwd"];
+
+/**
+ * Expand a target table using a built-in rule, or return it unchanged when it
+ * has no `rule`. `replacements` (`{config}`, `{out_dir}`) are applied up front,
+ * as the generated commands escape every other brace.
+ */
+pub(crate) fn expand_builtin(
+    name: &str,
+    mut target: toml::Table,
+    root: Option<&Path>,
+    replacements: &[(String, String)],
+) -> Result<Vec<(String, toml::Table)>, BuildSpecError> {
+    let rule = match target.remove("rule") {
+        None => return Ok(vec![(name.to_string(), target)]),
+        Some(toml::Value::String(rule)) => rule,
+        Some(_) => return Err(invalid(name, "'rule' must be a string")),
+    };
+    if !BUILTIN_RULES.contains(&rule.as_str()) {
+        return Err(invalid(
+            name,
+            &format!(
+                "uses unknown rule '{rule}' (available: {})",
+                BUILTIN_RULES.join(", ")
+            ),
+        ));
+    }
+
+    if let Some(field) = GENERATED_FIELDS.iter().find(|f| target.contains_key(**f)) {
+        return Err(invalid(
+            name,
+            &format!("can't set '{field}' when using rule '{rule}'"),
+        ));
+    }
+
+    for (_, value) in target.iter_mut() {
+        replace_in_strings(value, replacements);
+    }
+
+    match rule.as_str() {
+        "cc_library" => cc::expand_cc(name, cc::Rule::Library, target, root),
+        "cc_binary" => cc::expand_cc(name, cc::Rule::Binary, target, root),
+        "rust_library" => rust::expand_rustc(name, rust::Rule::Library, target),
+        "rust_binary" => rust::expand_rustc(name, rust::Rule::Binary, target),
+        _ => rust::expand_cargo(name, target, root),
+    }
+}
+
+/**
+ * Name a target as a single path component, for files it generates
+ */
+pub(crate) fn file_name(name: &str) -> String {
+    name.replace([RULE_SEPARATOR, '/'], "_")
+}
+
+/**
+ * A user-supplied word as it should appear in a generated command: shell
+ * quoted, with braces escaped so they aren't read as placeholders
+ */
+pub(crate) fn literal(word: &str) -> String {
+    shell_quote(word).replace('{', "{{").replace('}', "}}")
+}
+
+pub(crate) fn string(s: String) -> toml::Value {
+    toml::Value::String(s)
+}
+
+pub(crate) fn strings(items: impl IntoIterator<Item = String>) -> toml::Value {
+    toml::Value::Array(items.into_iter().map(toml::Value::String).collect())
+}
+
+/**
+ * Remove an optional string field from a rule's table
+ */
+pub(crate) fn take_string(
+    name: &str,
+    target: &mut toml::Table,
+    field: &str,
+) -> Result<Option<String>, BuildSpecError> {
+    match target.remove(field) {
+        None => Ok(None),
+        Some(toml::Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(invalid(name, &format!("'{field}' must be a string"))),
+    }
+}
+
+/**
+ * Remove an optional list-of-strings field from a rule's table
+ */
+pub(crate) fn take_string_list(
+    name: &str,
+    target: &mut toml::Table,
+    field: &str,
+) -> Result<Vec<String>, BuildSpecError> {
+    match target.remove(field) {
+        None => Ok(Vec::new()),
+        Some(toml::Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                toml::Value::String(s) => Ok(s),
+                _ => Err(invalid(name, &format!("'{field}' must contain strings"))),
+            })
+            .collect(),
+        Some(_) => Err(invalid(
+            name,
+            &format!("'{field}' must be an array of strings"),
+        )),
+    }
+}
+
+/**
+ * Key a program a generated command picks with `${VAR:-default}`: `VAR` joins
+ * `pass_env` and the program it selects joins `tools`
+ */
+pub(crate) fn add_host_tool(target: &mut toml::Table, var: &str, default: &str) {
+    for (field, item) in [
+        ("pass_env", var.to_string()),
+        ("tools", format!("${{{var}:-{default}}}")),
+    ] {
+        let entry = target
+            .entry(field)
+            .or_insert_with(|| toml::Value::Array(Vec::new()));
+        if let toml::Value::Array(items) = entry {
+            let item = toml::Value::String(item);
+            if !items.contains(&item) {
+                items.push(item);
+            }
+        }
+    }
+}
+
+pub(crate) fn invalid(name: &str, msg: &str) -> BuildSpecError {
+    BuildSpecError::InvalidTarget(format!("Target '{name}' {msg}"))
+}
@@ -0,0 +1,342 @@
+//! Built-in C/C++ rules: `rule = "cc_library"` and `rule = "cc_binary"`
+//!
+//! ```toml
+//! [utils]
+//! rule = "cc_library"
+//! srcs = ["src/utils.c"]
+//! hdrs = ["include/utils.h"]
+//! copts = ["-Iinclude", "-O2"]
+//!
+//! [app]
+//! rule = "cc_binary"
+//! srcs = ["src/main.c"]
+//! copts = ["-Iinclude"]
+//! linkopts = ["-lm"]
+//! deps = ["utils"]
+//! ```
+//!
+//! Each source becomes a compile target named `<target>:<src>` that writes
+//! `{out_dir}/obj/<target>/<src>.o` and a depfile, so included headers are
+//! tracked. The target itself archives its objects into
+//! `{out_dir}/lib/lib<target>.a` (a lib) or links them, with every transitive
+//! lib dep, into `{out_dir}/bin/<target>` (a binary); set `outputs` to choose
+//! another path. `srcs` may use globs; headers listed in `srcs` are private
+//! and, like `hdrs`, are inputs of every compile. C++ sources are compiled with
+//! `$CXX`, C with `$CC`, and archives are made with `$AR`; each of these is
+//! in the generated targets' `pass_env`, and the program it picks in `tools`.
+//!
+//! Deps are only ordered before the link, so headers generated by another
+//! target should be listed in `hdrs`.
+
+use crate::core::builtin::{
+    add_host_tool, file_name, invalid, literal, string, strings, take_string_list,
+};
+use crate::core::{BuildSpecError, RULE_SEPARATOR, stays_inside};
+use std::path::Path;
+
+const CXX_EXTENSIONS: &[&str] = &["cc", "cpp", "cxx", "c++", "C"];
+const COMPILED_EXTENSIONS: &[&str] = &["c", "s", "S"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Rule {
+    Library,
+    Binary,
+}
+
+/**
+ * Expand a `cc_library` or `cc_binary` target into its compile targets and
+ * the archive or link target
+ */
+pub(crate) fn expand_cc(
+    name: &str,
+    rule: Rule,
+    mut target: toml::Table,
+    root: Option<&Path>,
+) -> Result<Vec<(String, toml::Table)>, BuildSpecError> {
+    let srcs = expand_srcs(name, &take_string_list(name, &mut target, "srcs")?, root)?;
+    let hdrs = take_string_list(name, &mut target, "hdrs")?;
+    let copts = take_string_list(name, &mut target, "copts")?;
+    let linkopts = take_string_list(name, &mut target, "linkopts")?;
+
+    if rule == Rule::Library && !linkopts.is_empty() {
+        return Err(invalid(name, "sets 'linkopts', which only cc_binary uses"));
+    }
+
+    let (compiled, private_hdrs): (Vec<String>, Vec<String>) =
+        srcs.into_iter().partition(|src| is_compiled(src));
+    if compiled.is_empty() {
+        return Err(invalid(name, "has no sources to compile in 'srcs'"));
+    }
+    if let Some(src) = compiled.iter().find(|src| !stays_inside(Path::new(src))) {
+        return Err(invalid(
+            name,
+            &format!("has source '{src}' outside the project"),
+        ));
+    }
+
+    let deps = take_string_list(name, &mut target, "deps")?;
+    let outputs = target.remove("outputs");
+    let file_name = file_name(name);
+
+    let mut targets = Vec::new();
+    let mut objects = Vec::new();
+    let mut compile_names = Vec::new();
+    for src in &compiled {
+        let object = format!("{{out_dir}}/obj/{file_name}/{src}.o");
+        let (var, default) = if is_cxx(src) {
+            ("CXX", "c++")
+        } else {
+            ("CC", "cc")
+        };
+        let compiler = format!("${{{var}:-{default}}}");
+        let flags: String = copts
+            .iter()
+            .map(|opt| format!("{} ", literal(opt)))
+            .collect();
+
+        let mut compile = target.clone();
+        add_host_tool(&mut compile, var, default);
+        compile.insert(
+            "cmd".to_string(),
+            string(format!(
+                "{compiler} {flags}-MMD -c {} -o {{out}}",
+                literal(src)
+            )),
+        );
+        let inputs = std::iter::once(src).chain(&hdrs).chain(&private_hdrs);
+        compile.insert("inputs".to_string(), strings(inputs.cloned()));
+        compile.insert("outputs".to_string(), strings([object.clone()]));
+        compile.insert(
+            "depfile".to_string(),
+            string(format!("{{out_dir}}/obj/{file_name}/{src}.d")),
+        );
+        compile.insert("kind".to_string(), string("object".to_string()));
+
+        let compile_name = format!("{name}{RULE_SEPARATOR}{src}");
+        compile_names.push(compile_name.clone());
+        objects.push(object);
+        targets.push((compile_name, compile));
+    }
+
+    let mut link = target;
+    let (cmd, kind, default_output) = match rule {
+        Rule::Library => {
+            add_host_tool(&mut link, "AR", "ar");
+            (
+                "rm -f {out} && ${AR:-ar} rcs {out} {in}".to_string(),
+                "lib",
+                format!("{{out_dir}}/lib/lib{file_name}.a"),
+            )
+        }
+        Rule::Binary => {
+            let (var, default) = if compiled.iter().any(|src| is_cxx(src)) {
+                ("CXX", "c++")
+            } else {
+                ("CC", "cc")
+            };
+            add_host_tool(&mut link, var, default);
+            let linker = format!("${{{var}:-{default}}}");
+            let opts: String = linkopts
+                .iter()
+                .map(|opt| format!(" {}", literal(opt)))
+                .collect();
+            (
+                format!("{linker} -o {{out}} {{in}} {{deps.transitive_libs}}{opts}"),
+                "binary",
+                format!("{{out_dir}}/bin/{file_name}"),
+            )
+        }
+    };
+    link.insert("cmd".to_string(), string(cmd));
+    link.insert("inputs".to_string(), strings(objects));
+    link.insert(
+        "outputs".to_string(),
+        outputs.unwrap_or_else(|| strings([default_output])),
+    );
+    link.insert("kind".to_string(), string(kind.to_string()));
+
+    link.insert(
+        "deps".to_string(),
+        strings(compile_names.into_iter().chain(deps)),
+    );
+
+    targets.push((name.to_string(), link));
+    Ok(targets)
+}
+
+/**
+ * Expand globs in `srcs` against the project root; plain paths are kept as written
+ */
+fn expand_srcs(
+    name: &str,
+    srcs: &[String],
+    root: Option<&Path>,
+) -> Result<Vec<String>, BuildSpecError> {
+    let mut expanded = Vec::new();
+    for src in srcs {
+        if !src.contains(['*', '?', '[']) {
+            expanded.push(src.clone());
+            continue;
+        }
+
+        let root = root.ok_or_else(|| {
+            invalid(
+                name,
+                &format!("can't expand '{src}' in a spec loaded without its directory"),
+            )
+        })?;
+        let pattern = root.join(src);
+        let entries = glob::glob(&pattern.to_string_lossy())
+            .map_err(|e| invalid(name, &format!("has invalid source pattern '{src}': {e}")))?;
+        let mut matched: Vec<String> = entries
+            .filter_map(Result::ok)
+            .filter(|path| path.is_file())
+            .filter_map(|path| Some(path.strip_prefix(root).ok()?.to_string_lossy().into_owned()))
+            .collect();
+        matched.sort();
+        expanded.extend(matched);
+    }
+    Ok(expanded)
+}
+
+fn extension(src: &str) -> &str {
+    Path::new(src)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+}
+
+fn is_cxx(src: &str) -> bool {
+    CXX_EXTENSIONS.contains(&extension(src))
+}
+
+fn is_compiled(src: &str) -> bool {
+    is_cxx(src) || COMPILED_EXTENSIONS.contains(&extension(src))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{BuildSpec, TargetKind};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bagel_cc_test_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_cc_rules_expand_into_compile_and_link_targets() {
+        let dir = temp_dir("expand");
+        for file in ["src/main.c", "src/args.c", "src/private.h", "src/fmt.cc"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let toml = r#"
+            [fmt]
+            rule = "cc_library"
+            srcs = ["src/fmt.cc"]
+            hdrs = ["include/fmt.h"]
+            copts = ["-O2", "-DNAME={config}"]
+
+            [app]
+            rule = "cc_binary"
+            srcs = ["src/*.c", "src/private.h"]
+            linkopts = ["-lm"]
+            deps = ["fmt"]
+            timeout = 30
+            pass_env = ["LANG"]
+        "#;
+
+        let spec = BuildSpec::from_toml_in(toml, &dir).unwrap();
+        assert_eq!(spec.targets.len(), 5);
+
+        let compile = spec.get_target("fmt:src/fmt.cc").unwrap();
+        assert_eq!(compile.kind, TargetKind::Object);
+        assert_eq!(
+            compile.cmd.to_string(),
+            "${CXX:-c++} -O2 -DNAME=default -MMD -c src/fmt.cc -o {out}"
+        );
+        assert_eq!(compile.inputs, vec!["src/fmt.cc", "include/fmt.h"]);
+        assert_eq!(
+            compile.outputs,
+            vec!["bagel-out/default/obj/fmt/src/fmt.cc.o"]
+        );
+        assert_eq!(
+            compile.depfile.as_deref(),
+            Some("bagel-out/default/obj/fmt/src/fmt.cc.d")
+        );
+        assert_eq!(compile.pass_env, vec!["CXX"]);
+        assert_eq!(compile.tools, vec!["${CXX:-c++}"]);
+
+        let fmt = spec.get_target("fmt").unwrap();
+        assert_eq!(fmt.kind, TargetKind::Lib);
+        assert_eq!(fmt.outputs, vec!["bagel-out/default/lib/libfmt.a"]);
+        assert_eq!(fmt.deps, vec!["fmt:src/fmt.cc"]);
+        assert_eq!(fmt.pass_env, vec!["AR"]);
+        assert_eq!(fmt.tools, vec!["${AR:-ar}"]);
+
+        // Globs are sorted; headers in `srcs` are inputs of every compile
+        let args = spec.get_target("app:src/args.c").unwrap();
+        assert_eq!(args.inputs, vec!["src/args.c", "src/private.h"]);
+        assert_eq!(args.timeout, Some(30));
+        assert!(args.deps.is_empty());
+        assert_eq!(args.pass_env, vec!["LANG", "CC"]);
+
+        let app = spec.get_target("app").unwrap();
+        assert_eq!(app.kind, TargetKind::Binary);
+        assert_eq!(
+            app.cmd.to_string(),
+            "${CC:-cc} -o {out} {in} {deps.transitive_libs} -lm"
+        );
+        assert_eq!(
+            app.inputs,
+            vec![
+                "bagel-out/default/obj/app/src/args.c.o",
+                "bagel-out/default/obj/app/src/main.c.o"
+            ]
+        );
+        assert_eq!(app.outputs, vec!["bagel-out/default/bin/app"]);
+        assert_eq!(app.tools, vec!["${CC:-cc}"]);
+        assert_eq!(app.deps, vec!["app:src/args.c", "app:src/main.c", "fmt"]);
+        assert_eq!(spec.transitive_libs("app"), ["fmt"]);
+        assert!(spec.warnings().is_empty());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_cc_rules_reject_invalid_targets() {
+        let dir = temp_dir("invalid");
+        std::fs::write(dir.join("src/main.c"), "").unwrap();
+
+        for (toml, expected) in [
+            (
+                "[a]\nrule = \"cc_program\"\nsrcs = [\"src/main.c\"]",
+                "unknown rule 'cc_program'",
+            ),
+            (
+                "[a]\nrule = \"cc_binary\"\nsrcs = [\"src/main.c\"]\ncmd = \"cc\"",
+                "can't set 'cmd' when using rule 'cc_binary'",
+            ),
+            (
+                "[a]\nrule = \"cc_library\"\nsrcs = [\"src/main.c\"]\nlinkopts = [\"-lm\"]",
+                "only cc_binary uses",
+            ),
+            (
+                "[a]\nrule = \"cc_binary\"\nsrcs = [\"src/*.h\"]",
+                "no sources to compile",
+            ),
+            (
+                "[a]\nrule = \"cc_binary\"\nsrcs = [\"../main.c\"]",
+                "outside the project",
+            ),
+        ] {
+            let err = BuildSpec::from_toml_in(toml, &dir).unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
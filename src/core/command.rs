@@ -0,0 +1,227 @@
+//! The forms a target's `cmd` can take
+//!
+//! ```toml
+//! cmd = "gcc -c {in} -o {out}"                 # run by the shell
+//! cmd = ["gcc", "-c", "{in}", "-o", "{out}"]   # argv, run without a shell
+//! cmd = { steps = ["mkdir -p build", ["gcc", "-o", "{out}", "{in}"]] }
+//! ```
+//!
+//! Steps run in order and stop at the first failure, like `set -e`. In an argv
+//! element that is exactly one placeholder, each path becomes its own argument.
+
+use crate::utils::template::shell_quote;
+use serde::{Deserialize, Serialize};
+
+/** A single command: a shell string or an argv list */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CommandStep {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl CommandStep {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            CommandStep::Shell(cmd) => cmd.trim().is_empty(),
+            CommandStep::Argv(argv) => argv.first().is_none_or(|program| program.is_empty()),
+        }
+    }
+
+    /** Every string that may contain placeholders */
+    pub fn templates(&self) -> Vec<&str> {
+        match self {
+            CommandStep::Shell(cmd) => vec![cmd.as_str()],
+            CommandStep::Argv(argv) => argv.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for CommandStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandStep::Shell(cmd) => write!(f, "{cmd}"),
+            CommandStep::Argv(argv) => {
+                let quoted: Vec<String> = argv.iter().map(|arg| shell_quote(arg)).collect();
+                write!(f, "{}", quoted.join(" "))
+            }
+        }
+    }
+}
+
+/**
+ * A target's `shell`: a line, split on whitespace, or a list taken as written
+ * for programs or flags containing spaces. A lone program is given `-c`.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Shell {
+    Line(String),
+    Argv(Vec<String>),
+}
+
+impl Shell {
+    /** The program and leading arguments that run a command string */
+    pub fn argv(&self) -> Vec<String> {
+        let mut argv = match self {
+            Shell::Line(line) => line.split_whitespace().map(String::from).collect(),
+            Shell::Argv(argv) => argv.clone(),
+        };
+        if argv.len() == 1 {
+            argv.push("-c".to_string());
+        }
+        argv
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argv()
+            .first()
+            .is_none_or(|program| program.trim().is_empty())
+    }
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Shell::Line(line) => write!(f, "{line}"),
+            Shell::Argv(argv) => {
+                let quoted: Vec<String> = argv.iter().map(|arg| shell_quote(arg)).collect();
+                write!(f, "{}", quoted.join(" "))
+            }
+        }
+    }
+}
+
+/** A target's `cmd` */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum TargetCommand {
+    Shell(String),
+    Argv(Vec<String>),
+    Steps { steps: Vec<CommandStep> },
+}
+
+impl TargetCommand {
+    /** The steps to run, in order; a plain command is a single step */
+    pub fn steps(&self) -> Vec<CommandStep> {
+        match self {
+            TargetCommand::Shell(cmd) => vec![CommandStep::Shell(cmd.clone())],
+            TargetCommand::Argv(argv) => vec![CommandStep::Argv(argv.clone())],
+            TargetCommand::Steps { steps } => steps.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let steps = self.steps();
+        steps.is_empty() || steps.iter().any(CommandStep::is_empty)
+    }
+}
+
+impl Default for TargetCommand {
+    fn default() -> Self {
+        TargetCommand::Shell(String::new())
+    }
+}
+
+impl From<&str> for TargetCommand {
+    fn from(cmd: &str) -> Self {
+        TargetCommand::Shell(cmd.to_string())
+    }
+}
+
+impl std::fmt::Display for TargetCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let steps: Vec<String> = self.steps().iter().map(ToString::to_string).collect();
+        write!(f, "{}", steps.join(" && "))
+    }
+}
+
+impl PartialEq<str> for TargetCommand {
+    fn eq(&self, other: &str) -> bool {
+        matches!(self, TargetCommand::Shell(cmd) if cmd == other)
+    }
+}
+
+impl PartialEq<&str> for TargetCommand {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{BuildSpec, CommandStep, Shell, TargetCommand};
+
+    #[test]
+    fn test_cmd_forms() {
+        let toml = r#"
+            [shell]
+            cmd = "gcc -c {in} -o {out}"
+            inputs = ["main.c"]
+            outputs = ["main.o"]
+
+            [argv]
+            cmd = ["gcc", "-c", "{in}", "-o", "{out}"]
+            inputs = ["main.c"]
+            outputs = ["argv.o"]
+
+            [steps]
+            cmd = { steps = ["mkdir -p build", ["gcc", "-o", "{out}", "{in}"]] }
+            inputs = ["main.c"]
+            outputs = ["build/app"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        assert_eq!(
+            spec.get_target("shell").unwrap().cmd,
+            "gcc -c {in} -o {out}"
+        );
+        assert_eq!(
+            spec.get_target("argv").unwrap().cmd,
+            TargetCommand::Argv(
+                ["gcc", "-c", "{in}", "-o", "{out}"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(
+            spec.get_target("steps").unwrap().cmd.steps(),
+            vec![
+                CommandStep::Shell("mkdir -p build".to_string()),
+                CommandStep::Argv(["gcc", "-o", "{out}", "{in}"].map(String::from).to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_commands_rejected() {
+        for cmd in ["[]", "{ steps = [] }", "{ steps = [\"ls\", []] }", "[\"\"]"] {
+            let toml = format!("[app]\ncmd = {cmd}\ninputs = [\"main.c\"]\noutputs = [\"app\"]\n");
+            assert!(
+                BuildSpec::from_toml(&toml).is_err(),
+                "{cmd} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_shell_forms() {
+        let shell = |value: &str| -> Shell {
+            toml::from_str::<toml::Table>(&format!("shell = {value}")).unwrap()["shell"]
+                .clone()
+                .try_into()
+                .unwrap()
+        };
+
+        assert_eq!(shell("\"bash\"").argv(), ["bash", "-c"]);
+        assert_eq!(shell("\"python3 -c\"").argv(), ["python3", "-c"]);
+
+        // A list isn't split, so paths may contain spaces
+        let spaced = shell("[\"/opt/my tools/sh\"]");
+        assert_eq!(spaced.argv(), ["/opt/my tools/sh", "-c"]);
+        assert_eq!(spaced.to_string(), "'/opt/my tools/sh'");
+
+        assert!(shell("\" \"").is_empty());
+        assert!(shell("[]").is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! Dynamic dependencies reported by a target while the build is running
+//!
+//! A target with `dyndep = "<path>"` writes a TOML file during its build that
+//! adds inputs and dependencies to targets depending on it:
+//!
+//! ```toml
+//! [consumer]
+//! inputs = ["generated/foo.mod"]
+//! deps = ["foo_mod"]
+//! ```
+//!
+//! The executors apply it once the producing target finishes, before any of its
+//! dependents start.
+
+use crate::core::{BuildSpec, BuildSpecError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/** Additions for a single target */
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DyndepEntry {
+    #[serde(default)]
+    pub inputs: Vec<String>,
+
+    #[serde(default)]
+    pub deps: Vec<String>,
+}
+
+/** Contents of a dyndep file, keyed by the target being extended */
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct DyndepFile {
+    #[serde(flatten)]
+    pub targets: HashMap<String, DyndepEntry>,
+}
+
+impl DyndepFile {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, BuildSpecError> {
+        let content = std::fs::read_to_string(path)?;
+        Self::from_toml(&content)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, BuildSpecError> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+impl BuildSpec {
+    /**
+     * Apply a dyndep file written by `producer`. Only targets that depend on the
+     * producer may be extended, since nothing else is guaranteed not to have
+     * started yet. Deps outside a [`BuildSpec::closure`] are pulled in along with
+     * their own deps, and the graph is revalidated afterwards.
+     */
+    pub fn apply_dyndep(
+        &mut self,
+        producer: &str,
+        dyndep: &DyndepFile,
+    ) -> Result<(), BuildSpecError> {
+        for (name, entry) in &dyndep.targets {
+            if !self.has_target(name) {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Dyndep file of '{producer}' refers to unknown target '{name}'"
+                )));
+            }
+            if !self.depends_on(name, producer) {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Dyndep file of '{producer}' updates '{name}', which does not depend on it"
+                )));
+            }
+
+            let target = self.targets.get_mut(name).expect("checked above");
+            for input in &entry.inputs {
+                if !target.inputs.contains(input) {
+                    target.inputs.push(input.clone());
+                }
+            }
+            for dep in &entry.deps {
+                if !target.deps.contains(dep) {
+                    target.deps.push(dep.clone());
+                }
+            }
+            for dep in &entry.deps {
+                self.select(dep);
+            }
+        }
+
+        self.validate()
+    }
+
+    /**
+     * Move an unselected target and everything it depends on into the spec
+     */
+    fn select(&mut self, name: &str) {
+        let mut stack = vec![name.to_string()];
+        while let Some(name) = stack.pop() {
+            if let Some(target) = self.unselected.remove(&name) {
+                stack.extend(target.deps.iter().cloned());
+                self.targets.insert(name, target);
+            }
+        }
+    }
+
+    /**
+     * Whether `target` depends on `dep`, directly or transitively
+     */
+    pub fn depends_on(&self, target: &str, dep: &str) -> bool {
+        let mut stack = vec![target];
+        let mut seen = std::collections::HashSet::new();
+
+        while let Some(curr) = stack.pop() {
+            if !seen.insert(curr) {
+                continue;
+            }
+            if let Some(spec) = self.targets.get(curr) {
+                for d in &spec.deps {
+                    if d == dep {
+                        return true;
+                    }
+                    stack.push(d);
+                }
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+        [scan]
+        cmd = "scan"
+        inputs = ["main.f90"]
+        outputs = ["main.dd"]
+        dyndep = "main.dd"
+
+        [foo_mod]
+        cmd = "compile foo"
+        inputs = ["foo.f90"]
+        outputs = ["foo.mod"]
+
+        [main]
+        cmd = "compile main"
+        inputs = ["main.f90"]
+        outputs = ["main.o"]
+        deps = ["scan"]
+    "#;
+
+    #[test]
+    fn test_apply_dyndep_extends_dependents() {
+        let mut spec = BuildSpec::from_toml(SPEC).unwrap();
+        let dyndep = DyndepFile::from_toml(
+            r#"
+            [main]
+            inputs = ["foo.mod"]
+            deps = ["foo_mod"]
+            "#,
+        )
+        .unwrap();
+
+        spec.apply_dyndep("scan", &dyndep).unwrap();
+
+        let main = spec.get_target("main").unwrap();
+        assert_eq!(main.inputs, vec!["main.f90", "foo.mod"]);
+        assert_eq!(main.deps, vec!["scan", "foo_mod"]);
+    }
+
+    #[test]
+    fn test_apply_dyndep_rejects_non_dependents_and_cycles() {
+        let mut spec = BuildSpec::from_toml(SPEC).unwrap();
+
+        let not_dependent = DyndepFile::from_toml("[foo_mod]\ninputs = [\"x\"]").unwrap();
+        assert!(spec.apply_dyndep("scan", &not_dependent).is_err());
+
+        let cycle = DyndepFile::from_toml("[main]\ndeps = [\"main\"]").unwrap();
+        assert!(spec.apply_dyndep("scan", &cycle).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Matrix targets: a `matrix` table expands a target into one variant per combination
+//!
+//! ```toml
+//! [app]
+//! cmd = "gcc -{matrix.opt} -o {out} main.c"
+//! inputs = ["main.c"]
+//! outputs = ["build/{matrix.arch}/{matrix.opt}/app"]
+//! matrix = { opt = ["O0", "O2"], arch = ["x86_64", "aarch64"] }
+//! ```
+//!
+//! Each variant is named `<target>@<values>`, with values joined by `,` in
+//! alphabetical order of their axis names (e.g. `app@aarch64,O2`); values
+//! can't contain `,`, so no two variants share a name.
+//! `{matrix.<axis>}` is replaced in every string of the target, including `deps`
+//! and whatever it gets from `[defaults]`, and depending on the base name
+//! depends on all variants.
+
+use crate::core::{BuildSpecError, replace_in_strings};
+
+/** Separates a matrix target's name from its axis values */
+pub const MATRIX_SEPARATOR: char = '@';
+
+/** Joins the axis values in a variant's name */
+const VALUE_SEPARATOR: char = ',';
+
+/** A variant's `{matrix.<axis>}` placeholders and the values they stand for */
+pub(crate) type MatrixValues = Vec<(String, String)>;
+
+/**
+ * Expand a raw target table into its variants, or return it unchanged
+ * when it declares no matrix. Each variant comes with its values, to be
+ * substituted again once defaults are merged in.
+ */
+pub(crate) fn expand_matrix(
+    name: &str,
+    mut target: toml::Table,
+) -> Result<Vec<(String, toml::Table, MatrixValues)>, BuildSpecError> {
+    let Some(matrix) = target.remove("matrix") else {
+        return Ok(vec![(name.to_string(), target, Vec::new())]);
+    };
+
+    let toml::Value::Table(matrix) = matrix else {
+        return Err(invalid(name, "'matrix' must be a table"));
+    };
+
+    if name.contains(MATRIX_SEPARATOR) {
+        return Err(invalid(
+            name,
+            &format!("name cannot contain '{MATRIX_SEPARATOR}' when it has a matrix"),
+        ));
+    }
+
+    // Axes iterate in key order, which keeps variant names predictable
+    let mut axes: Vec<(String, Vec<String>)> = Vec::new();
+    for (axis, values) in matrix {
+        let toml::Value::Array(values) = values else {
+            return Err(invalid(
+                name,
+                &format!("matrix axis '{axis}' must be an array"),
+            ));
+        };
+        if values.is_empty() {
+            return Err(invalid(
+                name,
+                &format!("matrix axis '{axis}' has no values"),
+            ));
+        }
+
+        let values = values
+            .into_iter()
+            .map(|v| match v {
+                toml::Value::String(s) => Ok(s),
+                toml::Value::Integer(i) => Ok(i.to_string()),
+                toml::Value::Boolean(b) => Ok(b.to_string()),
+                _ => Err(invalid(
+                    name,
+                    &format!("matrix axis '{axis}' must contain strings, integers or booleans"),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(value) = values.iter().find(|v| v.contains(VALUE_SEPARATOR)) {
+            return Err(invalid(
+                name,
+                &format!(
+                    "matrix axis '{axis}' has value '{value}', which can't contain '{VALUE_SEPARATOR}'"
+                ),
+            ));
+        }
+
+        axes.push((axis, values));
+    }
+
+    let mut variants: Vec<(String, toml::Table, MatrixValues)> = Vec::new();
+    for combination in combinations(&axes) {
+        let suffix: Vec<&str> = combination.iter().map(|(_, v)| *v).collect();
+        let variant_name = format!(
+            "{name}{MATRIX_SEPARATOR}{}",
+            suffix.join(&VALUE_SEPARATOR.to_string())
+        );
+
+        // Only an axis listing a value twice can get here
+        if variants
+            .iter()
+            .any(|(existing, _, _)| *existing == variant_name)
+        {
+            return Err(invalid(
+                name,
+                &format!("matrix produces the variant name '{variant_name}' more than once"),
+            ));
+        }
+
+        let replacements: MatrixValues = combination
+            .iter()
+            .map(|(axis, value)| (format!("{{matrix.{axis}}}"), value.to_string()))
+            .collect();
+
+        let mut variant = toml::Value::Table(target.clone());
+        replace_in_strings(&mut variant, &replacements);
+        if let toml::Value::Table(variant) = variant {
+            variants.push((variant_name, variant, replacements));
+        }
+    }
+
+    Ok(variants)
+}
+
+/**
+ * Cartesian product of all axes, as (axis, value) pairs
+ */
+fn combinations(axes: &[(String, Vec<String>)]) -> Vec<Vec<(&str, &str)>> {
+    let mut result: Vec<Vec<(&str, &str)>> = vec![Vec::new()];
+    for (axis, values) in axes {
+        result = result
+            .into_iter()
+            .flat_map(|prefix| {
+                values.iter().map(move |value| {
+                    let mut combination = prefix.clone();
+                    combination.push((axis.as_str(), value.as_str()));
+                    combination
+                })
+            })
+            .collect();
+    }
+    result
+}
+
+fn invalid(name: &str, msg: &str) -> BuildSpecError {
+    BuildSpecError::InvalidTarget(format!("Target '{name}' {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::BuildSpec;
+
+    #[test]
+    fn test_matrix_expands_every_combination() {
+        let toml = r#"
+            [app]
+            cmd = "gcc -{matrix.opt} -march={matrix.arch} -o {out} main.c"
+            inputs = ["main.c"]
+            outputs = ["build/{matrix.arch}/{matrix.opt}/app"]
+            matrix = { opt = ["O0", "O2"], arch = ["x86_64", "aarch64"] }
+
+            [app.env]
+            OPT = "{matrix.opt}"
+
+            [release]
+            cmd = "tar czf {out} {deps.outputs}"
+            inputs = ["README.md"]
+            outputs = ["release.tgz"]
+            deps = ["app"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        assert_eq!(spec.targets.len(), 5);
+
+        let variant = spec.get_target("app@aarch64,O2").unwrap();
+        assert_eq!(variant.cmd, "gcc -O2 -march=aarch64 -o {out} main.c");
+        assert_eq!(variant.outputs, vec!["build/aarch64/O2/app"]);
+        assert_eq!(variant.env.get("OPT"), Some(&"O2".to_string()));
+
+        // Variants follow axis name order, then each axis's declared value order
+        let release = spec.get_target("release").unwrap();
+        assert_eq!(
+            release.deps,
+            vec![
+                "app@x86_64,O0",
+                "app@x86_64,O2",
+                "app@aarch64,O0",
+                "app@aarch64,O2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matrix_variants_can_depend_on_variants() {
+        let toml = r#"
+            [lib]
+            cmd = "gcc -c -{matrix.opt} lib.c -o {out}"
+            inputs = ["lib.c"]
+            outputs = ["lib-{matrix.opt}.o"]
+            matrix = { opt = ["O0", "O2"] }
+
+            [app]
+            cmd = "gcc -o {out} {deps.outputs}"
+            inputs = ["main.c"]
+            outputs = ["app-{matrix.opt}"]
+            deps = ["lib@{matrix.opt}"]
+            matrix = { opt = ["O0", "O2"] }
+        "#;
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        assert_eq!(spec.get_target("app@O2").unwrap().deps, vec!["lib@O2"]);
+    }
+
+    #[test]
+    fn test_matrix_values_reach_defaults() {
+        let toml = r#"
+            [defaults]
+            inputs = ["flags/{matrix.opt}.txt"]
+
+            [defaults.env]
+            OPT = "-{matrix.opt}"
+
+            [app]
+            cmd = "gcc $OPT -o {out} main.c"
+            inputs = ["main.c"]
+            outputs = ["build/{matrix.opt}/app"]
+            matrix = { opt = ["O0", "O2"] }
+        "#;
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let variant = spec.get_target("app@O2").unwrap();
+        assert_eq!(variant.inputs, vec!["main.c", "flags/O2.txt"]);
+        assert_eq!(variant.env.get("OPT"), Some(&"-O2".to_string()));
+    }
+
+    #[test]
+    fn test_matrix_variant_names_are_unambiguous() {
+        // Joined with `-`, both would have been named `app@a-b-c`
+        let toml = r#"
+            [app]
+            cmd = "echo {matrix.x} {matrix.y} > {out}"
+            inputs = ["main.c"]
+            outputs = ["{matrix.x}/{matrix.y}"]
+            matrix = { x = ["a-b", "a"], y = ["c", "b-c"] }
+        "#;
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        assert!(spec.get_target("app@a-b,c").is_some());
+        assert!(spec.get_target("app@a,b-c").is_some());
+
+        let toml = toml.replace("\"a-b\"", "\"a,b\"");
+        let err = BuildSpec::from_toml(&toml).unwrap_err();
+        assert!(err.to_string().contains("can't contain ','"), "{err}");
+    }
+
+    #[test]
+    fn test_matrix_rejects_empty_axis() {
+        let toml = r#"
+            [app]
+            cmd = "gcc main.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+            matrix = { opt = [] }
+        "#;
+
+        assert!(BuildSpec::from_toml(toml).is_err());
+    }
+}
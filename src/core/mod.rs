@@ -0,0 +1,1933 @@
+mod builtin;
+mod cc;
+mod command;
+mod dyndep;
+mod matrix;
+mod rules;
+mod rust;
+
+pub use command::{CommandStep, Shell, TargetCommand};
+pub use dyndep::{DyndepEntry, DyndepFile};
+pub use matrix::MATRIX_SEPARATOR;
+pub use rules::RULE_SEPARATOR;
+
+use crate::utils::HashAlgorithm;
+use crate::utils::template::{self, Placeholder};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/** Errors that can occur during build spec parsing */
+#[derive(Error, Debug)]
+pub enum BuildSpecError {
+    #[error("Failed to read build spec file: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to parse TOML: {0}")]
+    TomlError(#[from] toml::de::Error),
+    #[error("Invalid target specification: {0}")]
+    InvalidTarget(String),
+}
+
+/** Kind of build target */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Default)]
+pub enum TargetKind {
+    #[default]
+    Binary,
+    Lib,
+    /** Runs under `bagel test` rather than `bagel build`; needs no outputs */
+    Test,
+    /** An intermediate compile output, such as those generated by the cc rules */
+    Object,
+}
+
+impl std::fmt::Display for TargetKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetKind::Binary => write!(f, "binary"),
+            TargetKind::Lib => write!(f, "lib"),
+            TargetKind::Test => write!(f, "test"),
+            TargetKind::Object => write!(f, "object"),
+        }
+    }
+}
+
+/**
+ * How a target decides whether it is out of date.
+ * `hash` re-hashes every input byte on each build; `mtime` compares
+ * modification times like make; `stat` hashes contents but only re-reads
+ * inputs whose size, mtime or inode changed since the last recorded hash.
+ */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RebuildStrategy {
+    #[default]
+    Hash,
+    Mtime,
+    Stat,
+}
+
+impl std::fmt::Display for RebuildStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RebuildStrategy::Hash => write!(f, "hash"),
+            RebuildStrategy::Mtime => write!(f, "mtime"),
+            RebuildStrategy::Stat => write!(f, "stat"),
+        }
+    }
+}
+
+/**
+ * Specification for a single build target.
+ * `cmd` may use placeholders such as `{inputs}` and `{deps.<dep>.outputs}`;
+ * see [`crate::utils::template`].
+ *
+ * A target with only `deps` (no `cmd`, inputs or outputs) is a group: it runs
+ * nothing and is up to date once its deps are.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetSpec {
+    /** Command executed for this target: a shell string, an argv list or steps; see [`TargetCommand`] */
+    #[serde(default)]
+    pub cmd: TargetCommand,
+
+    /**
+     * Directory the command runs in, relative to the project root. Paths
+     * substituted into the command are rebased onto it.
+     */
+    #[serde(default)]
+    pub cwd: Option<String>,
+
+    /**
+     * Program that runs string commands instead of `sh -c`, e.g. `bash` or
+     * `python3 -c`; a lone program name gets `-c` appended. A string is split
+     * on whitespace, so a path with spaces needs the list form, e.g.
+     * `["/opt/my tools/sh", "-c"]`. Argv commands don't go through a shell.
+     */
+    #[serde(default)]
+    pub shell: Option<Shell>,
+
+    /**
+     * Files, directories or globs to hash for change detection; directories hash
+     * their whole tree. `!pattern` excludes matches, and globs never pick up
+     * `.bagel/`, other targets' outputs or paths in `.bagelignore`.
+     */
+    #[serde(default)]
+    pub inputs: Vec<String>,
+
+    /** Files or directories treated as the artifact & cache key */
+    #[serde(default)]
+    pub outputs: Vec<String>,
+
+    /**
+     * The target names an action rather than a file (e.g. `test`, `deploy`):
+     * it needs no inputs, declares no outputs and runs on every build.
+     * Nothing is cached for it, so it never triggers its dependents.
+     */
+    #[serde(default)]
+    pub phony: bool,
+
+    /**
+     * Run on every build even if nothing changed; inputs are optional. Its
+     * outputs are still hashed, so unchanged results cut dependents off.
+     */
+    #[serde(default)]
+    pub always: bool,
+
+    /** Other targets that must finish first */
+    #[serde(default)]
+    pub deps: Vec<String>,
+
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /**
+     * Host environment variables whose values are part of the cache key;
+     * in hermetic mode these are also the only extra host variables passed through
+     */
+    #[serde(default)]
+    pub pass_env: Vec<String>,
+
+    /**
+     * Run the command with a clean environment: a small allowlist of host
+     * variables, `pass_env`, `env` and the variables bagel injects
+     */
+    #[serde(default)]
+    pub hermetic: bool,
+
+    /**
+     * Executables the command runs (e.g. `gcc`), resolved on `PATH`; their
+     * contents are part of the cache key, so upgrading one rebuilds the target.
+     * `${CC:-cc}` names whichever program `CC` selects, falling back to `cc`.
+     */
+    #[serde(default)]
+    pub tools: Vec<String>,
+
+    /** Kind of target (binary or lib) */
+    #[serde(default)]
+    pub kind: TargetKind,
+
+    /** Seconds the command may run before it is killed */
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    /** Change detection strategy for this target's inputs */
+    #[serde(default)]
+    pub rebuild: RebuildStrategy,
+
+    /** Digest used for this target's inputs, outputs and discovered inputs */
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+
+    /** Also leave paths matched by the project's `.gitignore` out of input globs */
+    #[serde(default)]
+    pub gitignore: bool,
+
+    /**
+     * Makefile-format depfile written by the command (e.g. `gcc -MD`); the
+     * files it lists are tracked as extra inputs on the next build
+     */
+    #[serde(default)]
+    pub depfile: Option<String>,
+
+    /**
+     * Dyndep file written by the command, adding inputs and deps to the
+     * targets that depend on this one; see [`DyndepFile`]
+     */
+    #[serde(default)]
+    pub dyndep: Option<String>,
+}
+
+impl TargetSpec {
+    /** Whether this target only groups its deps; see [`TargetSpec`] */
+    pub fn is_group(&self) -> bool {
+        self.cmd.is_empty() && self.inputs.is_empty() && self.outputs.is_empty()
+    }
+
+    pub fn is_test(&self) -> bool {
+        self.kind == TargetKind::Test
+    }
+
+    /**
+     * Every placeholder used in the command's templates
+     */
+    fn placeholders(&self) -> Result<Vec<Placeholder>, template::TemplateError> {
+        let steps = self.cmd.steps();
+        let templates = steps.iter().flat_map(CommandStep::templates);
+        Ok(templates
+            .map(template::placeholders)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    /**
+     * The program `bagel run` executes: the single output of a binary target
+     */
+    pub fn runnable_output(&self, target_name: &str) -> Result<&str, BuildSpecError> {
+        match self.kind {
+            TargetKind::Lib | TargetKind::Object => {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{target_name}' is a {} and can't be run",
+                    self.kind
+                )));
+            }
+            TargetKind::Test => {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{target_name}' is a test; use `bagel test {target_name}`"
+                )));
+            }
+            TargetKind::Binary => {}
+        }
+
+        match self.outputs.as_slice() {
+            [output] => Ok(output),
+            [] => Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has no outputs to run"
+            ))),
+            outputs => Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has {} outputs; `bagel run` needs exactly one",
+                outputs.len()
+            ))),
+        }
+    }
+
+    /** Whether the target runs on every build, regardless of the cache */
+    pub fn always_runs(&self) -> bool {
+        self.phony || self.always
+    }
+
+    pub fn validate(&self, target_name: &str) -> Result<(), BuildSpecError> {
+        if self.is_group() && !self.always_runs() {
+            if self.deps.is_empty() {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{target_name}' has no command and no deps"
+                )));
+            }
+            return self.validate_deps(target_name);
+        }
+
+        if self.cmd.is_empty() {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty command"
+            )));
+        }
+
+        if self
+            .inputs
+            .iter()
+            .any(|s| s.trim().is_empty() || s.trim() == "!")
+        {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty input file"
+            )));
+        }
+
+        if self.outputs.iter().any(|s| s.trim().is_empty()) {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty output file"
+            )));
+        }
+
+        self.placeholders()
+            .map_err(|e| BuildSpecError::InvalidTarget(format!("Target '{target_name}': {e}")))?;
+
+        if self
+            .pass_env
+            .iter()
+            .any(|s| s.trim().is_empty() || s.contains('='))
+        {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has an invalid pass_env variable name"
+            )));
+        }
+
+        if self.tools.iter().any(|s| s.trim().is_empty()) {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty tool name"
+            )));
+        }
+
+        if let Some(cwd) = &self.cwd {
+            if cwd.trim().is_empty() {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{target_name}' has empty cwd"
+                )));
+            }
+            if !stays_inside(Path::new(cwd)) {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{target_name}' has cwd '{cwd}' outside the project"
+                )));
+            }
+        }
+
+        if self.shell.as_ref().is_some_and(Shell::is_empty) {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty shell"
+            )));
+        }
+
+        self.validate_deps(target_name)?;
+
+        // A test is keyed on its deps' outputs, so it needn't list inputs
+        if self.inputs.is_empty() && !self.always_runs() && !self.is_test() {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has no inputs specified"
+            )));
+        }
+
+        if self.phony && !self.outputs.is_empty() {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Phony target '{target_name}' declares outputs; use `always = true` instead"
+            )));
+        }
+
+        if self.outputs.is_empty() && !self.phony && !self.is_test() {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has no outputs specified"
+            )));
+        }
+
+        if self.depfile.as_ref().is_some_and(|d| d.trim().is_empty()) {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty depfile path"
+            )));
+        }
+
+        if self.dyndep.as_ref().is_some_and(|d| d.trim().is_empty()) {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty dyndep path"
+            )));
+        }
+
+        if self.timeout == Some(0) {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has a zero timeout"
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn validate_deps(&self, target_name: &str) -> Result<(), BuildSpecError> {
+        if self.deps.iter().any(|s| s.trim().is_empty()) {
+            return Err(BuildSpecError::InvalidTarget(format!(
+                "Target '{target_name}' has empty dependency name"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Whether a relative path stays under the directory it is relative to,
+ * judged from its components alone
+ */
+fn stays_inside(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(up) => depth = up,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/**
+ * The directory a target's `cwd` names under `root`. It must exist and, with
+ * symlinks resolved, still be inside the project.
+ */
+pub(crate) fn resolve_cwd(name: &str, cwd: &str, root: &Path) -> Result<PathBuf, BuildSpecError> {
+    let dir = root.join(cwd);
+    if !dir.is_dir() {
+        return Err(BuildSpecError::InvalidTarget(format!(
+            "Target '{name}' has cwd '{cwd}', which is not a directory"
+        )));
+    }
+
+    let inside = match (dir.canonicalize(), root.canonicalize()) {
+        (Ok(dir), Ok(root)) => dir.starts_with(root),
+        _ => false,
+    };
+    if !inside {
+        return Err(BuildSpecError::InvalidTarget(format!(
+            "Target '{name}' has cwd '{cwd}', which resolves outside the project"
+        )));
+    }
+
+    Ok(dir)
+}
+
+/**
+ * Settings from the reserved `[defaults]` table, applied to every target.
+ * Tables (e.g. `env`) are merged with the target's entries taking precedence,
+ * arrays (e.g. `inputs`) are appended to the target's own, and any other
+ * field is only used when the target does not set it. Groups, which have no
+ * `cmd`, are left alone.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TargetDefaults {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deps: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pass_env: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hermetic: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<Shell>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<TargetKind>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rebuild: Option<RebuildStrategy>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_algorithm: Option<HashAlgorithm>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitignore: Option<bool>,
+}
+
+impl TargetDefaults {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/**
+ * A named build configuration from `[configs.<name>]`, selected with `--config`.
+ * Its env overrides every target's, and `{out_dir}` in any target string
+ * expands to the configuration's own output directory.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigSpec {
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /**
+     * Flags for `{config.flags}`. An array element that is exactly the
+     * placeholder becomes one element per flag (e.g. in `copts` or an argv
+     * `cmd`); elsewhere the flags are shell-quoted and joined with spaces.
+     */
+    #[serde(default)]
+    pub flags: Vec<String>,
+
+    /** Output directory; defaults to `bagel-out/<name>` */
+    #[serde(default)]
+    pub out_dir: Option<String>,
+}
+
+impl ConfigSpec {
+    /**
+     * Output directory of this configuration when selected under `name`
+     */
+    pub fn out_dir_for(&self, name: &str) -> String {
+        self.out_dir
+            .clone()
+            .unwrap_or_else(|| format!("{OUT_ROOT}/{name}"))
+    }
+}
+
+/** Root of the per-configuration output directories */
+pub const OUT_ROOT: &str = "bagel-out";
+
+/** Configuration name used for `{config}` and `{out_dir}` when none is selected */
+pub const DEFAULT_CONFIG: &str = "default";
+
+/** Top-level tables with special meaning; these can't be used as target names */
+pub const RESERVED_TABLES: &[&str] = &["defaults", "rules", "configs"];
+
+/** Build spec containing all targets */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildSpec {
+    #[serde(default, skip_serializing_if = "TargetDefaults::is_empty")]
+    pub defaults: TargetDefaults,
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub configs: HashMap<String, ConfigSpec>,
+
+    /** Configuration the targets were loaded with, if any */
+    #[serde(skip)]
+    pub config: Option<String>,
+
+    /** Rule and matrix names mapped to the targets they expanded into */
+    #[serde(skip)]
+    pub groups: HashMap<String, Vec<String>>,
+
+    #[serde(flatten)]
+    pub targets: HashMap<String, TargetSpec>,
+
+    /** Targets [`BuildSpec::closure`] left out, from which dyndep files can pull deps */
+    #[serde(skip)]
+    pub unselected: HashMap<String, TargetSpec>,
+}
+
+impl BuildSpec {
+    /**
+     * Load a spec, resolving pattern rules relative to the file's directory
+     */
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, BuildSpecError> {
+        Self::from_file_with_config(path, None)
+    }
+
+    /**
+     * Load a spec with the named `[configs.<name>]` applied to every target
+     */
+    pub fn from_file_with_config<P: AsRef<Path>>(
+        path: P,
+        config: Option<&str>,
+    ) -> Result<Self, BuildSpecError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let root = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        Self::load(&content, root, config)
+    }
+
+    /**
+     * Parse a spec that has no directory. Anything that looks at the
+     * project's files (pattern rules, source globs) is an error; use
+     * [`BuildSpec::from_toml_in`] for those.
+     */
+    pub fn from_toml(content: &str) -> Result<Self, BuildSpecError> {
+        Self::parse(content, None, None)
+    }
+
+    /**
+     * Parse a spec whose pattern rules match files under `root`
+     */
+    pub fn from_toml_in(content: &str, root: &Path) -> Result<Self, BuildSpecError> {
+        Self::load(content, root, None)
+    }
+
+    /**
+     * Parse a spec under `root`, applying the named configuration (if any)
+     */
+    pub fn load(content: &str, root: &Path, config: Option<&str>) -> Result<Self, BuildSpecError> {
+        Self::parse(content, Some(root), config)
+    }
+
+    fn parse(
+        content: &str,
+        root: Option<&Path>,
+        config: Option<&str>,
+    ) -> Result<Self, BuildSpecError> {
+        let mut table: toml::Table = toml::from_str(content)?;
+
+        let defaults_table = take_reserved_table(&mut table, "defaults")?;
+        let defaults: TargetDefaults = toml::Value::Table(defaults_table.clone()).try_into()?;
+
+        let configs: HashMap<String, ConfigSpec> =
+            toml::Value::Table(take_reserved_table(&mut table, "configs")?).try_into()?;
+
+        // The default config needn't be declared; selecting it is the same as selecting none
+        let active =
+            match config.filter(|name| *name != DEFAULT_CONFIG || configs.contains_key(*name)) {
+                Some(name) => configs.get(name).cloned().ok_or_else(|| {
+                    let mut known: Vec<&str> = configs.keys().map(String::as_str).collect();
+                    known.sort();
+                    BuildSpecError::InvalidTarget(format!(
+                        "Unknown config '{name}' (available: {})",
+                        if known.is_empty() {
+                            "none".to_string()
+                        } else {
+                            known.join(", ")
+                        }
+                    ))
+                })?,
+                None => ConfigSpec::default(),
+            };
+        let config_name = config.unwrap_or(DEFAULT_CONFIG);
+        let out_dir = active.out_dir_for(config_name);
+        let replacements = [
+            ("{config}".to_string(), config_name.to_string()),
+            ("{out_dir}".to_string(), out_dir),
+        ];
+
+        let rules = rules::expand_rules(take_reserved_table(&mut table, "rules")?, root)?;
+        let mut groups = rules.groups;
+
+        let mut raw_targets = Vec::new();
+        let mut crates = rust::Crates::default();
+        for (name, value) in table {
+            let toml::Value::Table(target_table) = value else {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{name}' must be a table"
+                )));
+            };
+            if groups.contains_key(&name) {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{name}' has the same name as a rule"
+                )));
+            }
+
+            let variants = matrix::expand_matrix(&name, target_table)?;
+            if variants.len() > 1 || variants[0].0 != name {
+                groups.insert(name, variants.iter().map(|(n, _, _)| n.clone()).collect());
+            }
+            for (variant, mut variant_table, matrix_values) in variants {
+                for (_, value) in variant_table.iter_mut() {
+                    splice_config_flags(value, &active.flags);
+                }
+                crates.note(&variant, &variant_table);
+                let expanded =
+                    builtin::expand_builtin(&variant, variant_table, root, &replacements)?;
+                raw_targets.extend(
+                    expanded
+                        .into_iter()
+                        .map(|(name, table)| (name, table, matrix_values.clone())),
+                );
+            }
+        }
+        raw_targets.extend(
+            rules
+                .targets
+                .into_iter()
+                .map(|(name, table)| (name, table, Vec::new())),
+        );
+        crates.validate()?;
+
+        let mut targets = HashMap::new();
+        for (name, mut target_table, matrix_values) in raw_targets {
+            if target_table.contains_key("cmd") {
+                merge_defaults(&name, &mut target_table, &defaults_table, &groups);
+            }
+            apply_config_env(&mut target_table, &active.env);
+
+            let mut target_value = toml::Value::Table(target_table);
+            splice_config_flags(&mut target_value, &active.flags);
+            replace_in_strings(&mut target_value, &replacements);
+            // Defaults and config env were merged after the matrix was expanded
+            replace_in_strings(&mut target_value, &matrix_values);
+            let mut target: TargetSpec = target_value.try_into()?;
+
+            // A dependency on a rule or matrix is a dependency on every target it generated
+            target.deps = target
+                .deps
+                .into_iter()
+                .flat_map(|dep| match groups.get(&dep) {
+                    Some(generated) => generated.clone(),
+                    None => vec![dep],
+                })
+                .collect();
+
+            if targets.insert(name.clone(), target).is_some() {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "Target '{name}' is defined more than once"
+                )));
+            }
+        }
+
+        let spec = BuildSpec {
+            defaults,
+            configs,
+            config: config.map(str::to_string),
+            groups,
+            targets,
+            unselected: HashMap::new(),
+        };
+        spec.validate()?;
+        if let Some(root) = root {
+            spec.validate_in(root)?;
+        }
+        Ok(spec)
+    }
+
+    pub fn validate(&self) -> Result<(), BuildSpecError> {
+        for (name, target) in &self.targets {
+            if RESERVED_TABLES.contains(&name.as_str()) {
+                return Err(BuildSpecError::InvalidTarget(format!(
+                    "'{name}' is a reserved table and cannot be used as a target name"
+                )));
+            }
+            target.validate(name)?;
+        }
+
+        self.validate_dependencies()?;
+
+        Ok(())
+    }
+
+    /**
+     * Checks that need the project's files: every `cwd` must be a directory
+     * inside `root`
+     */
+    fn validate_in(&self, root: &Path) -> Result<(), BuildSpecError> {
+        for (name, target) in &self.targets {
+            if let Some(cwd) = &target.cwd {
+                resolve_cwd(name, cwd, root)?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Validate no circular or non-existent deps.
+     */
+    fn validate_dependencies(&self) -> Result<(), BuildSpecError> {
+        for (target_name, target) in &self.targets {
+            for placeholder in target.placeholders().unwrap_or_default() {
+                if let Placeholder::DepOutputs(dep) = &placeholder
+                    && !self.depends_on_all(target, dep)
+                {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "Target '{target_name}' uses outputs of '{dep}' without depending on it"
+                    )));
+                }
+            }
+
+            for dep in &target.deps {
+                if !self.targets.contains_key(dep) {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "Target '{target_name}' depends on non-existent target '{dep}'"
+                    )));
+                }
+
+                if dep == target_name {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "Target '{target_name}' cannot depend on itself"
+                    )));
+                }
+
+                if self.targets[dep].is_test() && target.is_test() {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "Test '{target_name}' depends on test '{dep}'; tests run \
+                         independently, so depend on what '{dep}' checks instead"
+                    )));
+                }
+
+                if self.targets[dep].is_test() && !target.is_group() {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "Target '{target_name}' depends on test target '{dep}'"
+                    )));
+                }
+            }
+        }
+
+        /** Run topological sort to detect any cycles */
+        #[derive(PartialEq)]
+        enum State {
+            Unvisited,
+            Visiting,
+            Visited,
+        }
+
+        let mut state: HashMap<&str, State> = self
+            .targets
+            .keys()
+            .map(|k| (k.as_str(), State::Unvisited))
+            .collect();
+
+        fn dfs<'a>(
+            curr: &'a str,
+            spec: &'a BuildSpec,
+            state: &mut HashMap<&'a str, State>,
+        ) -> Result<(), BuildSpecError> {
+            match state.get(curr) {
+                Some(State::Visiting) => {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "Circular dependency detected involving target '{curr}'"
+                    )));
+                }
+                Some(State::Visited) => return Ok(()),
+                _ => {}
+            }
+
+            state.insert(curr, State::Visiting);
+
+            if let Some(target) = spec.targets.get(curr) {
+                for dep in &target.deps {
+                    dfs(dep, spec, state)?;
+                }
+            }
+
+            state.insert(curr, State::Visited);
+            Ok(())
+        }
+
+        for target_name in self.targets.keys() {
+            if state.get((target_name).as_str()) == Some(&State::Unvisited) {
+                dfs(target_name, self, &mut state)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Output directory of the configuration the spec was loaded with
+     */
+    pub fn out_dir(&self) -> String {
+        match &self.config {
+            Some(name) => self
+                .configs
+                .get(name)
+                .cloned()
+                .unwrap_or_default()
+                .out_dir_for(name),
+            None => ConfigSpec::default().out_dir_for(DEFAULT_CONFIG),
+        }
+    }
+
+    pub fn get_target(&self, name: &str) -> Option<&TargetSpec> {
+        self.targets.get(name)
+    }
+
+    pub fn target_names(&self) -> Vec<&String> {
+        self.targets.keys().collect()
+    }
+
+    pub fn has_target(&self, name: &str) -> bool {
+        self.targets.contains_key(name)
+    }
+
+    /**
+     * Resolve a name given on the command line: a target, or a rule/matrix
+     * name standing for all of its targets
+     */
+    pub fn resolve_name(&self, name: &str) -> Option<Vec<String>> {
+        if self.targets.contains_key(name) {
+            Some(vec![name.to_string()])
+        } else {
+            self.groups.get(name).cloned()
+        }
+    }
+
+    /**
+     * Restrict the spec to the named targets and everything they depend on
+     */
+    pub fn closure(&self, names: &[String]) -> Result<BuildSpec, BuildSpecError> {
+        let mut stack = Vec::new();
+        for name in names {
+            let resolved = self.resolve_name(name).ok_or_else(|| {
+                BuildSpecError::InvalidTarget(format!("No target or rule named '{name}'"))
+            })?;
+            stack.extend(resolved);
+        }
+
+        let mut unselected = self.targets.clone();
+        unselected.extend(self.unselected.clone());
+        let mut targets = HashMap::new();
+        while let Some(name) = stack.pop() {
+            if let Some(target) = unselected.remove(&name) {
+                stack.extend(target.deps.iter().cloned());
+                targets.insert(name, target);
+            }
+        }
+
+        Ok(BuildSpec {
+            defaults: self.defaults.clone(),
+            configs: self.configs.clone(),
+            config: self.config.clone(),
+            groups: self.groups.clone(),
+            targets,
+            unselected,
+        })
+    }
+
+    /**
+     * Lib targets whose outputs `name` links through `{deps.transitive_libs}`:
+     * its lib deps, their lib deps and so on, looking through groups but not
+     * past other kinds. Each lib comes before the libs it depends on, and
+     * otherwise in `deps` order.
+     */
+    pub fn transitive_libs(&self, name: &str) -> Vec<String> {
+        fn visit(
+            spec: &BuildSpec,
+            name: &str,
+            seen: &mut HashSet<String>,
+            postorder: &mut Vec<String>,
+        ) {
+            let Some(target) = spec.targets.get(name) else {
+                return;
+            };
+            // Visited last-to-first so that reversing the postorder keeps `deps` order
+            for dep in target.deps.iter().rev() {
+                let Some(dep_target) = spec.targets.get(dep) else {
+                    continue;
+                };
+                let propagates = dep_target.kind == TargetKind::Lib || dep_target.is_group();
+                if propagates && seen.insert(dep.clone()) {
+                    visit(spec, dep, seen, postorder);
+                    if dep_target.kind == TargetKind::Lib {
+                        postorder.push(dep.clone());
+                    }
+                }
+            }
+        }
+
+        let mut postorder = Vec::new();
+        visit(self, name, &mut HashSet::new(), &mut postorder);
+        postorder.reverse();
+        postorder
+    }
+
+    /**
+     * Problems that don't stop a build but are probably mistakes. A binary
+     * that links `{deps.transitive_libs}` silently skips deps that are
+     * binaries, so those are flagged; other binary-on-binary deps (e.g. a
+     * code generator) are fine.
+     */
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (name, target) in &self.targets {
+            let is_binary =
+                |t: &TargetSpec| t.kind == TargetKind::Binary && !t.is_group() && !t.phony;
+            let links_libs = target
+                .placeholders()
+                .is_ok_and(|p| p.contains(&Placeholder::TransitiveLibs));
+            if !is_binary(target) || !links_libs {
+                continue;
+            }
+            for dep in &target.deps {
+                if is_binary(&self.targets[dep]) {
+                    warnings.push(format!(
+                        "Binary '{name}' depends on binary '{dep}'; mark '{dep}' as kind = \"lib\" if it is linked in"
+                    ));
+                }
+            }
+        }
+        warnings.sort();
+        warnings
+    }
+
+    /**
+     * Whether `target` depends on `dep`, or on every target of the rule or
+     * matrix named `dep`
+     */
+    fn depends_on_all(&self, target: &TargetSpec, dep: &str) -> bool {
+        target.deps.iter().any(|d| d == dep)
+            || self
+                .groups
+                .get(dep)
+                .is_some_and(|members| members.iter().all(|m| target.deps.contains(m)))
+    }
+
+    /**
+     * The spec without its test targets, as built by `bagel build`. Groups
+     * drop their test members.
+     */
+    pub fn without_tests(&self) -> BuildSpec {
+        let is_test = |name: &str| {
+            let target = self.targets.get(name).or(self.unselected.get(name));
+            target.is_some_and(TargetSpec::is_test)
+        };
+
+        let mut spec = self.clone();
+        for targets in [&mut spec.targets, &mut spec.unselected] {
+            targets.retain(|_, target| !target.is_test());
+            for target in targets.values_mut() {
+                target.deps.retain(|dep| !is_test(dep));
+            }
+        }
+        for members in spec.groups.values_mut() {
+            members.retain(|member| spec.targets.contains_key(member));
+        }
+        spec
+    }
+
+    /**
+     * Test targets selected by `bagel test`, sorted. Each pattern is a target,
+     * rule or matrix name (selecting the tests within it) or a glob over test
+     * names; no patterns selects every test.
+     */
+    pub fn tests_matching(&self, patterns: &[String]) -> Result<Vec<String>, BuildSpecError> {
+        let mut tests: Vec<String> = if patterns.is_empty() {
+            self.targets
+                .iter()
+                .filter(|(_, target)| target.is_test())
+                .map(|(name, _)| name.clone())
+                .collect()
+        } else {
+            let mut tests = Vec::new();
+            for pattern in patterns {
+                let matched = match self.resolve_name(pattern) {
+                    Some(_) => self
+                        .closure(std::slice::from_ref(pattern))?
+                        .targets
+                        .into_iter()
+                        .filter(|(_, target)| target.is_test())
+                        .map(|(name, _)| name)
+                        .collect(),
+                    None => {
+                        let glob = glob::Pattern::new(pattern).map_err(|e| {
+                            BuildSpecError::InvalidTarget(format!(
+                                "Invalid test pattern '{pattern}': {e}"
+                            ))
+                        })?;
+                        self.targets
+                            .iter()
+                            .filter(|(name, target)| target.is_test() && glob.matches(name))
+                            .map(|(name, _)| name.clone())
+                            .collect::<Vec<_>>()
+                    }
+                };
+                if matched.is_empty() {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "No test target matches '{pattern}'"
+                    )));
+                }
+                tests.extend(matched);
+            }
+            tests
+        };
+
+        tests.sort();
+        tests.dedup();
+        Ok(tests)
+    }
+
+    pub fn topological_sort(&self) -> Result<Vec<String>, BuildSpecError> {
+        #[derive(PartialEq, Clone, Copy)]
+        enum State {
+            Unvisited,
+            Visiting,
+            Visited,
+        }
+
+        let mut state: HashMap<&str, State> = self
+            .targets
+            .keys()
+            .map(|k| (k.as_str(), State::Unvisited))
+            .collect();
+
+        let mut result: Vec<String> = Vec::new();
+
+        // dfs; add nodes in post-order (after all dependencies are visited)
+        fn dfs<'a>(
+            curr: &'a str,
+            spec: &'a BuildSpec,
+            state: &mut HashMap<&'a str, State>,
+            result: &mut Vec<String>,
+        ) -> Result<(), BuildSpecError> {
+            match state.get(curr) {
+                Some(State::Visiting) => {
+                    return Err(BuildSpecError::InvalidTarget(format!(
+                        "Circular dependency detected involving target '{curr}'"
+                    )));
+                }
+                Some(State::Visited) => return Ok(()),
+                _ => {}
+            }
+
+            state.insert(curr, State::Visiting);
+
+            // Visit all dependencies first
+            if let Some(target) = spec.targets.get(curr) {
+                for dep in &target.deps {
+                    dfs(dep, spec, state, result)?;
+                }
+            }
+
+            state.insert(curr, State::Visited);
+            result.push(curr.to_string());
+
+            Ok(())
+        }
+
+        for target_name in self.targets.keys() {
+            if state.get(target_name.as_str()) == Some(&State::Unvisited) {
+                dfs(target_name, self, &mut state, &mut result)?;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/*
+ * Remove a reserved top-level table, which must be a table if present
+ */
+fn take_reserved_table(table: &mut toml::Table, key: &str) -> Result<toml::Table, BuildSpecError> {
+    match table.remove(key) {
+        Some(toml::Value::Table(t)) => Ok(t),
+        Some(_) => Err(BuildSpecError::InvalidTarget(format!(
+            "'{key}' must be a table"
+        ))),
+        None => Ok(toml::Table::new()),
+    }
+}
+
+/*
+ * Layer a configuration's env over the target's, with the configuration winning
+ */
+fn apply_config_env(target: &mut toml::Table, env: &HashMap<String, String>) {
+    if env.is_empty() {
+        return;
+    }
+
+    let target_env = target
+        .entry("env")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if let toml::Value::Table(target_env) = target_env {
+        for (key, value) in env {
+            target_env.insert(key.clone(), toml::Value::String(value.clone()));
+        }
+    }
+}
+
+/** Placeholder for the selected configuration's `flags` */
+const CONFIG_FLAGS: &str = "{config.flags}";
+
+/**
+ * Expand `{config.flags}` in every string nested in `value`; see [`ConfigSpec::flags`]
+ */
+fn splice_config_flags(value: &mut toml::Value, flags: &[String]) {
+    match value {
+        toml::Value::String(s) if s.contains(CONFIG_FLAGS) => {
+            let joined: Vec<String> = flags
+                .iter()
+                .map(|flag| template::shell_quote(flag))
+                .collect();
+            *s = s.replace(CONFIG_FLAGS, &joined.join(" "));
+        }
+        toml::Value::Array(items) => {
+            let mut spliced = Vec::with_capacity(items.len());
+            for mut item in std::mem::take(items) {
+                if item.as_str() == Some(CONFIG_FLAGS) {
+                    spliced.extend(flags.iter().cloned().map(toml::Value::String));
+                } else {
+                    splice_config_flags(&mut item, flags);
+                    spliced.push(item);
+                }
+            }
+            *items = spliced;
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                splice_config_flags(item, flags);
+            }
+        }
+        _ => {}
+    }
+}
+
+/*
+ * Replace each (pattern, value) pair in every string nested in `value`
+ */
+pub(crate) fn replace_in_strings(value: &mut toml::Value, replacements: &[(String, String)]) {
+    match value {
+        toml::Value::String(s) => {
+            for (pattern, replacement) in replacements {
+                if s.contains(pattern.as_str()) {
+                    *s = s.replace(pattern.as_str(), replacement);
+                }
+            }
+        }
+        toml::Value::Array(items) => {
+            for item in items {
+                replace_in_strings(item, replacements);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                replace_in_strings(item, replacements);
+            }
+        }
+        _ => {}
+    }
+}
+
+/*
+ * Fold the raw `[defaults]` table into a target's table before it is deserialized,
+ * so defaults can also supply fields the target would otherwise be required to set.
+ * A default dep naming the target itself, or a rule or matrix it belongs to, is
+ * skipped for that target, so `deps = ["utils"]` doesn't make `utils` depend on itself.
+ */
+fn merge_defaults(
+    name: &str,
+    target: &mut toml::Table,
+    defaults: &toml::Table,
+    groups: &HashMap<String, Vec<String>>,
+) {
+    for (key, default_value) in defaults {
+        let mut default_value = default_value.clone();
+        if key == "deps"
+            && let toml::Value::Array(deps) = &mut default_value
+        {
+            deps.retain(|dep| {
+                dep.as_str().is_none_or(|dep| {
+                    dep != name && !groups.get(dep).is_some_and(|g| g.iter().any(|m| m == name))
+                })
+            });
+        }
+
+        match (target.get_mut(key), &default_value) {
+            (None, _) => {
+                target.insert(key.clone(), default_value);
+            }
+            // A shell given as a list is still replaced whole
+            (Some(_), _) if key == "shell" => {}
+            (Some(toml::Value::Table(own)), toml::Value::Table(default_table)) => {
+                for (k, v) in default_table {
+                    own.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+            }
+            (Some(toml::Value::Array(own)), toml::Value::Array(default_items)) => {
+                for item in default_items {
+                    if !own.contains(item) {
+                        own.push(item.clone());
+                    }
+                }
+            }
+            // Scalars set on the target override the default
+            (Some(_), _) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_simple_target() {
+        let toml_content = r#"
+            [hello_world]
+            cmd = "gcc -o hello hello.c"
+            inputs = ["hello.c"]
+            outputs = ["hello"]
+            "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert_eq!(spec.targets.len(), 1);
+
+        let target = spec.get_target("hello_world").unwrap();
+        assert_eq!(target.cmd, "gcc -o hello hello.c");
+        assert_eq!(target.inputs, vec!["hello.c"]);
+        assert_eq!(target.outputs, vec!["hello"]);
+        assert_eq!(target.kind, TargetKind::Binary);
+    }
+
+    #[test]
+    fn test_parse_complex_target() {
+        let toml_content = r#"
+            [my_library]
+            cmd = "cargo build --lib"
+            inputs = ["src/**/*.rs", "Cargo.toml"]
+            outputs = ["target/debug/libmy_library.rlib"]
+            deps = ["codegen"]
+            kind = "lib"
+
+            [my_library.env]
+            RUSTFLAGS = "-C opt-level=2"
+            CARGO_TARGET_DIR = "custom_target"
+
+            [codegen]
+            cmd = "python generate_code.py"
+            inputs = ["templates/*.j2", "schema.yaml"]
+            outputs = ["src/generated.rs"]
+            "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert_eq!(spec.targets.len(), 2);
+
+        let lib_target = spec.get_target("my_library").unwrap();
+        assert_eq!(lib_target.kind, TargetKind::Lib);
+        assert_eq!(lib_target.deps, vec!["codegen"]);
+        assert_eq!(
+            lib_target.env.get("RUSTFLAGS"),
+            Some(&"-C opt-level=2".to_string())
+        );
+        assert_eq!(
+            lib_target.env.get("CARGO_TARGET_DIR"),
+            Some(&"custom_target".to_string())
+        );
+    }
+
+    #[test]
+    fn test_invalid_target_no_required_params() {
+        let toml_content = r#"
+            [invalid_lib]
+            inputs = ["src/**/*.rs", "Cargo.toml"]
+            outputs = ["target/debug/libmy_library.rlib"]
+            deps = []
+            kind = "lib"
+
+            [invalid_lib.env]
+            RUSTFLAGS = "-C opt-level=2"
+        "#;
+
+        let spec_result = BuildSpec::from_toml(toml_content);
+        assert!(spec_result.is_err(), "cmd is required");
+    }
+
+    #[test]
+    fn test_invalid_target_circular_deps() {
+        let toml_content = r#"
+            [circular_lib]
+            cmd = "cargo build --lib"
+            inputs = ["src/**/*.rs", "Cargo.toml"]
+            outputs = ["target/debug/libmy_library.rlib"]
+            deps = ["a_dep"]
+
+            [a_dep]
+            cmd = "python generate_code.py"
+            inputs = ["templates/*.j2", "schema.yaml"]
+            outputs = ["src/compiled.ts"]
+            deps = ["b_dep"]
+
+            [b_dep]
+            cmd = "tsc src/*.ts" 
+            inputs = ["src/**/*.ts", "Cargo.toml"]
+            outputs = ["src/compiled.ts"]
+            deps = ["circular_lib"]
+        "#;
+
+        let spec_result = BuildSpec::from_toml(toml_content);
+        assert!(
+            spec_result.is_err(),
+            "Circular dependency should be detected"
+        )
+    }
+
+    #[test]
+    fn test_defaults_merged_into_targets() {
+        let toml_content = r#"
+            [defaults]
+            inputs = ["common.h"]
+            kind = "lib"
+            timeout = 60
+            rebuild = "stat"
+            shell = ["bash", "-eu"]
+
+            [defaults.env]
+            CC = "gcc"
+            CFLAGS = "-Wall"
+
+            [utils]
+            cmd = "gcc -c utils.c"
+            inputs = ["utils.c"]
+            outputs = ["utils.o"]
+
+            [app]
+            cmd = "gcc -o app main.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+            kind = "binary"
+            timeout = 5
+            rebuild = "hash"
+            shell = ["zsh"]
+
+            [app.env]
+            CFLAGS = "-O2"
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert_eq!(spec.targets.len(), 2);
+        assert_eq!(spec.defaults.timeout, Some(60));
+
+        let utils = spec.get_target("utils").unwrap();
+        assert_eq!(utils.inputs, vec!["utils.c", "common.h"]);
+        assert_eq!(utils.kind, TargetKind::Lib);
+        assert_eq!(utils.timeout, Some(60));
+        assert_eq!(utils.rebuild, RebuildStrategy::Stat);
+        assert_eq!(utils.env.get("CFLAGS"), Some(&"-Wall".to_string()));
+        assert_eq!(utils.shell.as_ref().unwrap().argv(), ["bash", "-eu"]);
+
+        // Target's own fields override the defaults; tables are merged
+        let app = spec.get_target("app").unwrap();
+        assert_eq!(app.kind, TargetKind::Binary);
+        assert_eq!(app.timeout, Some(5));
+        assert_eq!(app.rebuild, RebuildStrategy::Hash);
+        assert_eq!(app.env.get("CC"), Some(&"gcc".to_string()));
+        assert_eq!(app.env.get("CFLAGS"), Some(&"-O2".to_string()));
+        assert_eq!(app.shell.as_ref().unwrap().argv(), ["zsh", "-c"]);
+    }
+
+    #[test]
+    fn test_default_deps_skip_the_dep_itself() {
+        let toml_content = r#"
+            [defaults]
+            deps = ["utils"]
+
+            [utils]
+            cmd = "gcc -c utils.c"
+            inputs = ["utils.c"]
+            outputs = ["utils.o"]
+
+            [app]
+            cmd = "gcc -o app main.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert!(spec.get_target("utils").unwrap().deps.is_empty());
+        assert_eq!(spec.get_target("app").unwrap().deps, ["utils"]);
+
+        // A matrix's variants don't depend on each other through its name
+        let toml_content = r#"
+            [defaults]
+            deps = ["gen"]
+
+            [gen]
+            cmd = "./gen {matrix.part}"
+            inputs = ["gen.py"]
+            outputs = ["{matrix.part}.c"]
+            matrix = { part = ["a", "b"] }
+
+            [app]
+            cmd = "gcc -o app a.c b.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert!(spec.get_target("gen@a").unwrap().deps.is_empty());
+        assert_eq!(spec.get_target("app").unwrap().deps, ["gen@a", "gen@b"]);
+    }
+
+    #[test]
+    fn test_defaults_rejects_unknown_fields() {
+        let toml_content = r#"
+            [defaults]
+            inptus = ["common.h"]
+
+            [utils]
+            cmd = "gcc -c utils.c"
+            inputs = ["utils.c"]
+            outputs = ["utils.o"]
+        "#;
+
+        assert!(BuildSpec::from_toml(toml_content).is_err());
+    }
+
+    #[test]
+    fn test_placeholders_validated() {
+        let toml_content = r#"
+            [app]
+            cmd = "gcc -o {out} {in} {deps.utils.outputs}"
+            inputs = ["main.c"]
+            outputs = ["app"]
+        "#;
+
+        let err = BuildSpec::from_toml(toml_content).unwrap_err();
+        assert!(err.to_string().contains("without depending on it"));
+
+        let toml_content = r#"
+            [app]
+            cmd = "gcc -o {out} {in} {deps.utils}"
+            inputs = ["main.c"]
+            outputs = ["app"]
+        "#;
+
+        let err = BuildSpec::from_toml(toml_content).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Unknown placeholder '{deps.utils}'")
+        );
+
+        let toml_content = r#"
+            [app]
+            cmd = "awk '{print}' {in} > {out}"
+            inputs = ["words.txt"]
+            outputs = ["app"]
+        "#;
+
+        assert!(BuildSpec::from_toml(toml_content).is_ok());
+    }
+
+    #[test]
+    fn test_config_flags_are_spliced() {
+        let toml = r#"
+            [app]
+            cmd = "cc {config.flags} -o {out} {in}"
+            inputs = ["main.c"]
+            outputs = ["app"]
+
+            [tool]
+            cmd = ["cc", "{config.flags}", "-o", "{out}", "{in}"]
+            inputs = ["tool.c"]
+            outputs = ["tool"]
+
+            [configs.release]
+            flags = ["-O2", "-DNAME=my app"]
+        "#;
+
+        let spec = BuildSpec::load(toml, Path::new("."), Some("release")).unwrap();
+        assert_eq!(
+            spec.get_target("app").unwrap().cmd.to_string(),
+            "cc -O2 '-DNAME=my app' -o {out} {in}"
+        );
+        assert_eq!(
+            spec.get_target("tool").unwrap().cmd,
+            TargetCommand::Argv(
+                ["cc", "-O2", "-DNAME=my app", "-o", "{out}", "{in}"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+
+        // Without flags the placeholder disappears
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        assert_eq!(
+            spec.get_target("app").unwrap().cmd.to_string(),
+            "cc  -o {out} {in}"
+        );
+    }
+
+    #[test]
+    fn test_cwd_must_stay_inside_project() {
+        let target = |cwd: &str| {
+            format!(
+                "[gen]\ncmd = \"./gen.sh\"\ninputs = [\"gen.sh\"]\noutputs = [\"out.c\"]\ncwd = \"{cwd}\"\n"
+            )
+        };
+
+        for cwd in ["scripts", "./scripts", "scripts/../tools", "."] {
+            assert!(
+                BuildSpec::from_toml(&target(cwd)).is_ok(),
+                "{cwd} is inside"
+            );
+        }
+        for cwd in ["..", "scripts/../..", "/tmp"] {
+            let err = BuildSpec::from_toml(&target(cwd)).unwrap_err();
+            assert!(err.to_string().contains("outside the project"), "{cwd}");
+        }
+        assert!(BuildSpec::from_toml(&target("")).is_err());
+
+        // With its root known, the loader also checks the directory itself
+        let dir = std::env::temp_dir().join("bagel_core_test_cwd");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("scripts")).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("escape")).unwrap();
+
+        assert!(BuildSpec::from_toml_in(&target("scripts"), &dir).is_ok());
+        let err = BuildSpec::from_toml_in(&target("tools"), &dir).unwrap_err();
+        assert!(err.to_string().contains("not a directory"), "{err}");
+        #[cfg(unix)]
+        {
+            let err = BuildSpec::from_toml_in(&target("escape"), &dir).unwrap_err();
+            assert!(err.to_string().contains("resolves outside"), "{err}");
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_phony_always_and_group_targets() {
+        let toml_content = r#"
+            [defaults]
+            inputs = ["common.h"]
+
+            [app]
+            cmd = "gcc -o {out} main.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+
+            [test]
+            cmd = "./app --self-test"
+            phony = true
+            deps = ["app"]
+
+            [version]
+            cmd = "git describe > {out}"
+            outputs = ["version.txt"]
+            always = true
+
+            [all]
+            deps = ["app", "version"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert!(spec.get_target("test").unwrap().always_runs());
+        assert!(spec.get_target("version").unwrap().always_runs());
+        let all = spec.get_target("all").unwrap();
+        assert!(all.is_group());
+        assert!(all.inputs.is_empty(), "defaults are not applied to groups");
+
+        let invalid = [
+            // phony targets produce nothing
+            (
+                "[t]\ncmd = \"true\"\nphony = true\noutputs = [\"x\"]\n",
+                "declares outputs",
+            ),
+            // a group needs something to group
+            ("[t]\nkind = \"lib\"\n", "no command and no deps"),
+            // only phony and always targets may skip inputs
+            (
+                "[t]\ncmd = \"true\"\noutputs = [\"x\"]\n",
+                "no inputs specified",
+            ),
+            // phony still needs a command
+            ("[t]\nphony = true\n", "empty command"),
+        ];
+        for (toml_content, expected) in invalid {
+            let err = BuildSpec::from_toml(toml_content).unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_test_targets() {
+        let toml_content = r#"
+            [app]
+            cmd = "gcc -o {out} main.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+
+            [app_unit_test]
+            cmd = "./app --unit"
+            kind = "test"
+            deps = ["app"]
+
+            [app_smoke_test]
+            cmd = "./app --smoke"
+            kind = "test"
+            deps = ["app"]
+
+            [all]
+            deps = ["app", "app_smoke_test"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert_eq!(
+            spec.tests_matching(&[]).unwrap(),
+            ["app_smoke_test", "app_unit_test"]
+        );
+        assert_eq!(
+            spec.tests_matching(&["*unit*".to_string()]).unwrap(),
+            ["app_unit_test"]
+        );
+        assert_eq!(
+            spec.tests_matching(&["all".to_string()]).unwrap(),
+            ["app_smoke_test"]
+        );
+        assert!(spec.tests_matching(&["nope*".to_string()]).is_err());
+
+        let built = spec.without_tests();
+        assert_eq!(built.targets.len(), 2);
+        assert_eq!(built.get_target("all").unwrap().deps, ["app"]);
+
+        let toml_content = r#"
+            [check]
+            cmd = "true"
+            kind = "test"
+
+            [app]
+            cmd = "gcc -o {out} main.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+            deps = ["check"]
+        "#;
+        let err = BuildSpec::from_toml(toml_content).unwrap_err();
+        assert!(err.to_string().contains("depends on test target 'check'"));
+
+        let toml_content = r#"
+            [check]
+            cmd = "true"
+            kind = "test"
+
+            [check_more]
+            cmd = "true"
+            kind = "test"
+            deps = ["check"]
+        "#;
+        let err = BuildSpec::from_toml(toml_content).unwrap_err();
+        assert!(err.to_string().contains("depends on test 'check'"));
+    }
+
+    #[test]
+    fn test_transitive_libs_in_link_order() {
+        let toml_content = r#"
+            [defaults]
+            kind = "lib"
+
+            [app]
+            cmd = "cc -o {out} main.o {deps.transitive_libs}"
+            inputs = ["main.o"]
+            outputs = ["app"]
+            deps = ["net", "codegen", "core_libs"]
+            kind = "binary"
+
+            [net]
+            cmd = "ar rcs {out} net.o"
+            inputs = ["net.o"]
+            outputs = ["libnet.a"]
+            deps = ["utils"]
+
+            [core_libs]
+            deps = ["fmt", "utils"]
+
+            [fmt]
+            cmd = "ar rcs {out} fmt.o"
+            inputs = ["fmt.o"]
+            outputs = ["libfmt.a"]
+            deps = ["utils"]
+
+            [utils]
+            cmd = "ar rcs {out} utils.o"
+            inputs = ["utils.o"]
+            outputs = ["libutils.a"]
+
+            [codegen]
+            cmd = "cc -o {out} gen.c"
+            inputs = ["gen.c"]
+            outputs = ["gen"]
+            deps = ["fmt"]
+            kind = "binary"
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        // The codegen binary isn't linked, nor is anything only it depends on
+        assert_eq!(spec.transitive_libs("app"), ["net", "fmt", "utils"]);
+        assert_eq!(spec.transitive_libs("fmt"), ["utils"]);
+        assert!(spec.transitive_libs("utils").is_empty());
+
+        assert_eq!(
+            spec.warnings(),
+            [
+                "Binary 'app' depends on binary 'codegen'; mark 'codegen' as kind = \"lib\" if it is linked in"
+            ]
+        );
+
+        // Without {deps.transitive_libs} nothing is skipped, so a binary dep is fine
+        let toml_content = r#"
+            [gen]
+            cmd = "cc -o {out} gen.c"
+            inputs = ["gen.c"]
+            outputs = ["gen"]
+
+            [app]
+            cmd = "./gen > app.c && cc -o {out} app.c"
+            inputs = ["main.c"]
+            outputs = ["app"]
+            deps = ["gen"]
+        "#;
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        assert!(spec.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_closure_keeps_only_transitive_deps() {
+        let toml_content = r#"
+            [app]
+            cmd = "echo app"
+            inputs = ["app.c"]
+            outputs = ["app"]
+            deps = ["lib"]
+
+            [lib]
+            cmd = "echo lib"
+            inputs = ["lib.c"]
+            outputs = ["lib.o"]
+
+            [docs]
+            cmd = "echo docs"
+            inputs = ["README.md"]
+            outputs = ["docs.html"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        let closure = spec.closure(&["app".to_string()]).unwrap();
+
+        let mut names = closure.target_names();
+        names.sort();
+        assert_eq!(names, vec!["app", "lib"]);
+
+        assert!(spec.closure(&["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_topological_sort_simple() {
+        // Linear dependency chain: A -> B -> C
+        let toml_content = r#"
+            [A]
+            cmd = "echo A"
+            inputs = ["a.txt"]
+            outputs = ["a.out"]
+            deps = ["B"]
+
+            [B]
+            cmd = "echo B"
+            inputs = ["b.txt"]
+            outputs = ["b.out"]
+            deps = ["C"]
+
+            [C]
+            cmd = "echo C"
+            inputs = ["c.txt"]
+            outputs = ["c.out"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        let order = spec.topological_sort().unwrap();
+
+        // C must come before B, B must come before A
+        let pos_c = order.iter().position(|x| x == "C").unwrap();
+        let pos_b = order.iter().position(|x| x == "B").unwrap();
+        let pos_a = order.iter().position(|x| x == "A").unwrap();
+
+        assert!(pos_c < pos_b, "C should come before B");
+        assert!(pos_b < pos_a, "B should come before A");
+    }
+
+    #[test]
+    fn test_topological_sort_diamond() {
+        // test a damond dependency:
+        //     A
+        //    / \
+        //   B   C
+        //    \ /
+        //     D
+        let toml_content = r#"
+            [A]
+            cmd = "echo A"
+            inputs = ["a.txt"]
+            outputs = ["a.out"]
+            deps = ["B", "C"]
+
+            [B]
+            cmd = "echo B"
+            inputs = ["b.txt"]
+            outputs = ["b.out"]
+            deps = ["D"]
+
+            [C]
+            cmd = "echo C"
+            inputs = ["c.txt"]
+            outputs = ["c.out"]
+            deps = ["D"]
+
+            [D]
+            cmd = "echo D"
+            inputs = ["d.txt"]
+            outputs = ["d.out"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        let order = spec.topological_sort().unwrap();
+
+        // D must come first, B and C can be in any order, A must be last
+        let pos_d = order.iter().position(|x| x == "D").unwrap();
+        let pos_b = order.iter().position(|x| x == "B").unwrap();
+        let pos_c = order.iter().position(|x| x == "C").unwrap();
+        let pos_a = order.iter().position(|x| x == "A").unwrap();
+
+        assert_eq!(pos_d, 0, "D should be first (no deps)");
+        assert!(pos_b < pos_a, "B should come before A");
+        assert!(pos_c < pos_a, "C should come before A");
+        assert_eq!(pos_a, 3, "A should be last");
+    }
+
+    #[test]
+    fn test_topological_sort_independent() {
+        // No dependencies between targets
+        let toml_content = r#"
+            [A]
+            cmd = "echo A"
+            inputs = ["a.txt"]
+            outputs = ["a.out"]
+
+            [B]
+            cmd = "echo B"
+            inputs = ["b.txt"]
+            outputs = ["b.out"]
+
+            [C]
+            cmd = "echo C"
+            inputs = ["c.txt"]
+            outputs = ["c.out"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml_content).unwrap();
+        let order = spec.topological_sort().unwrap();
+
+        // All three targets should be in the result
+        assert_eq!(order.len(), 3);
+        assert!(order.contains(&"A".to_string()));
+        assert!(order.contains(&"B".to_string()));
+        assert!(order.contains(&"C".to_string()));
+    }
+}
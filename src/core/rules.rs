@@ -0,0 +1,327 @@
+//! Pattern rules: `[rules.<name>]` tables that expand into one target per matching file
+//!
+//! ```toml
+//! [rules.compile]
+//! inputs = "src/%.c"
+//! outputs = "build/%.o"
+//! cmd = "gcc -c {in} -o {out}"
+//! ```
+//!
+//! Every file matching the first `%` pattern in `inputs` produces a target named
+//! `<rule>:<stem>` (e.g. `compile:utils`), with `%` replaced by the stem in all
+//! inputs and outputs, and in `depfile`. Depending on the rule's name depends on all of its targets.
+//! Files in `.bagel/`, `bagel-out/` or `.bagelignore` never match, as with glob inputs.
+
+use crate::core::{BuildSpecError, OUT_ROOT};
+use crate::utils::{IgnoreRules, InputFilter};
+use std::collections::HashMap;
+use std::path::Path;
+
+/** Separates a rule's name from the stem in generated target names */
+pub const RULE_SEPARATOR: char = ':';
+
+/**
+ * Targets produced by expanding every rule, as raw tables ready for `[defaults]`
+ * merging, along with the generated target names for each rule.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct ExpandedRules {
+    pub targets: Vec<(String, toml::Table)>,
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+pub(crate) fn expand_rules(
+    rules: toml::Table,
+    root: Option<&Path>,
+) -> Result<ExpandedRules, BuildSpecError> {
+    let mut expanded = ExpandedRules::default();
+
+    for (rule_name, value) in rules {
+        let toml::Value::Table(rule) = value else {
+            return Err(invalid(&rule_name, "must be a table"));
+        };
+        let root = root.ok_or_else(|| {
+            invalid(
+                &rule_name,
+                "can't match files in a spec loaded without its directory",
+            )
+        })?;
+
+        let mut names = Vec::new();
+        for (name, target) in expand_rule(&rule_name, rule, root)? {
+            names.push(name.clone());
+            expanded.targets.push((name, target));
+        }
+        expanded.groups.insert(rule_name, names);
+    }
+
+    Ok(expanded)
+}
+
+fn expand_rule(
+    rule_name: &str,
+    mut rule: toml::Table,
+    root: &Path,
+) -> Result<Vec<(String, toml::Table)>, BuildSpecError> {
+    if rule_name.contains(RULE_SEPARATOR) {
+        return Err(invalid(
+            rule_name,
+            &format!("name cannot contain '{RULE_SEPARATOR}'"),
+        ));
+    }
+
+    let inputs = string_list(rule_name, &mut rule, "inputs")?;
+    let outputs = string_list(rule_name, &mut rule, "outputs")?;
+
+    let pattern = inputs
+        .iter()
+        .find(|p| p.contains('%') && !p.starts_with('!'))
+        .ok_or_else(|| invalid(rule_name, "needs an input pattern containing '%'"))?;
+
+    if inputs
+        .iter()
+        .chain(outputs.iter())
+        .any(|p| p.matches('%').count() > 1)
+    {
+        return Err(invalid(rule_name, "patterns may contain at most one '%'"));
+    }
+
+    let mut targets = Vec::new();
+    for stem in match_stems(pattern, root)? {
+        let substitute = |patterns: &[String]| -> toml::Value {
+            toml::Value::Array(
+                patterns
+                    .iter()
+                    .map(|p| toml::Value::String(p.replace('%', &stem)))
+                    .collect(),
+            )
+        };
+
+        let mut target = rule.clone();
+        target.insert("inputs".to_string(), substitute(&inputs));
+        target.insert("outputs".to_string(), substitute(&outputs));
+        if let Some(toml::Value::String(depfile)) = target.get_mut("depfile") {
+            *depfile = depfile.replace('%', &stem);
+        }
+        targets.push((format!("{rule_name}{RULE_SEPARATOR}{stem}"), target));
+    }
+
+    Ok(targets)
+}
+
+/**
+ * Find every file under `root` matching `pattern` and return the part matched by `%`.
+ * `%` matches any non-empty string, including path separators.
+ */
+fn match_stems(pattern: &str, root: &Path) -> Result<Vec<String>, BuildSpecError> {
+    let (prefix, suffix) = pattern.split_once('%').unwrap_or((pattern, ""));
+
+    // Only walk the directory the prefix names, e.g. `src/` for `src/lib%.c`
+    let search_dir = &prefix[..prefix.rfind('/').map_or(0, |i| i + 1)];
+    let walk = root.join(glob::Pattern::escape(search_dir)).join("**/*");
+
+    let entries = glob::glob(&walk.to_string_lossy())
+        .map_err(|e| BuildSpecError::InvalidTarget(format!("Invalid rule pattern: {e}")))?;
+
+    let filter = InputFilter {
+        excluded: vec![OUT_ROOT.into()],
+        ignore: IgnoreRules::load(root, false),
+    };
+    let mut stems: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let rel_path = path.strip_prefix(root).ok()?;
+            if filter.excludes(rel_path, false) {
+                return None;
+            }
+            let rel = rel_path.to_string_lossy().into_owned();
+            let stem = rel.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            (!stem.is_empty()).then(|| stem.to_string())
+        })
+        .collect();
+
+    stems.sort();
+    Ok(stems)
+}
+
+/**
+ * Read a field that may be written as a single string or an array of strings
+ */
+fn string_list(
+    rule_name: &str,
+    rule: &mut toml::Table,
+    field: &str,
+) -> Result<Vec<String>, BuildSpecError> {
+    let items = match rule.remove(field) {
+        Some(toml::Value::String(s)) => vec![toml::Value::String(s)],
+        Some(toml::Value::Array(items)) => items,
+        Some(_) => {
+            return Err(invalid(
+                rule_name,
+                &format!("'{field}' must be a string or array of strings"),
+            ));
+        }
+        None => Vec::new(),
+    };
+
+    items
+        .into_iter()
+        .map(|item| match item {
+            toml::Value::String(s) => Ok(s),
+            _ => Err(invalid(
+                rule_name,
+                &format!("'{field}' must contain strings"),
+            )),
+        })
+        .collect()
+}
+
+fn invalid(rule_name: &str, msg: &str) -> BuildSpecError {
+    BuildSpecError::InvalidTarget(format!("Rule '{rule_name}' {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::BuildSpec;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bagel_rules_test_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src/net")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rule_expands_per_matching_file() {
+        let dir = temp_dir("expand");
+        for file in [
+            "src/main.c",
+            "src/utils.c",
+            "src/net/http.c",
+            "src/notes.txt",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let toml = r#"
+            [rules.compile]
+            inputs = ["src/%.c", "config.h"]
+            outputs = "build/%.o"
+            cmd = "gcc -c {in} -o {out}"
+
+            [app]
+            cmd = "gcc -o {out} {deps.outputs}"
+            inputs = ["src/main.c"]
+            outputs = ["app"]
+            deps = ["compile"]
+        "#;
+
+        let spec = BuildSpec::from_toml_in(toml, &dir).unwrap();
+        assert_eq!(spec.targets.len(), 4);
+
+        let http = spec.get_target("compile:net/http").unwrap();
+        assert_eq!(http.inputs, vec!["src/net/http.c", "config.h"]);
+        assert_eq!(http.outputs, vec!["build/net/http.o"]);
+
+        // Depending on the rule depends on every target it generated
+        let app = spec.get_target("app").unwrap();
+        assert_eq!(
+            app.deps,
+            vec!["compile:main", "compile:net/http", "compile:utils"]
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rule_outputs_placeholder_covers_every_target() {
+        let dir = temp_dir("group_outputs");
+        for file in ["src/main.c", "src/utils.c"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let toml = r#"
+            [rules.compile]
+            inputs = "src/%.c"
+            outputs = "build/%.o"
+            cmd = "gcc -c {in} -o {out}"
+
+            [app]
+            cmd = "gcc -o {out} {deps.compile.outputs}"
+            inputs = ["src/main.c"]
+            outputs = ["app"]
+            deps = ["compile"]
+        "#;
+
+        let spec = BuildSpec::from_toml_in(toml, &dir).unwrap();
+        let app = spec.get_target("app").unwrap();
+        assert_eq!(app.deps, vec!["compile:main", "compile:utils"]);
+
+        let missing_dep = toml.replace(r#"deps = ["compile"]"#, r#"deps = ["compile:main"]"#);
+        let err = BuildSpec::from_toml_in(&missing_dep, &dir).unwrap_err();
+        assert!(err.to_string().contains("uses outputs of 'compile'"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rule_skips_state_outputs_and_ignored_files() {
+        let dir = temp_dir("excluded");
+        for sub in [".bagel/cache", "bagel-out/default", "vendor"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in [
+            "main.c",
+            ".bagel/cache/stale.c",
+            "bagel-out/default/gen.c",
+            "vendor/lib.c",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        std::fs::write(dir.join(".bagelignore"), "vendor/\n").unwrap();
+
+        let toml = r#"
+            [rules.compile]
+            inputs = "%.c"
+            outputs = "build/%.o"
+            cmd = "gcc -c {in} -o {out}"
+        "#;
+
+        let spec = BuildSpec::from_toml_in(toml, &dir).unwrap();
+        assert_eq!(spec.groups["compile"], vec!["compile:main"]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rule_needs_spec_directory() {
+        let toml = r#"
+            [rules.compile]
+            inputs = "src/%.c"
+            outputs = "build/%.o"
+            cmd = "gcc -c {in} -o {out}"
+        "#;
+
+        let err = BuildSpec::from_toml(toml).unwrap_err();
+        assert!(err.to_string().contains("without its directory"));
+    }
+
+    #[test]
+    fn test_rule_requires_stem_pattern() {
+        let dir = temp_dir("no_stem");
+
+        let toml = r#"
+            [rules.compile]
+            inputs = "src/main.c"
+            outputs = "build/main.o"
+            cmd = "gcc -c {in} -o {out}"
+        "#;
+
+        let err = BuildSpec::from_toml_in(toml, &dir).unwrap_err();
+        assert!(err.to_string().contains("containing '%'"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
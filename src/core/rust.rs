@@ -0,0 +1,442 @@
+//! Built-in Rust rules: `rust_library` and `rust_binary`, which run `rustc`
+//! directly, and `cargo_crate`, which wraps `cargo build`
+//!
+//! ```toml
+//! [fmt]
+//! rule = "rust_library"
+//! crate_root = "fmt/lib.rs"
+//!
+//! [tool]
+//! rule = "rust_binary"
+//! crate_root = "tool/main.rs"
+//! edition = "2024"
+//! rustc_flags = ["-Copt-level=2"]
+//! deps = ["fmt"]
+//!
+//! [server]
+//! rule = "cargo_crate"
+//! manifest = "server/Cargo.toml"
+//! bins = ["server"]
+//! profile = "release"
+//! ```
+//!
+//! `rustc` targets compile their crate root (`src/lib.rs` or `src/main.rs` by
+//! default) with `--emit=dep-info`, so every module file the crate pulls in is
+//! tracked without listing it. A library is written to
+//! `{out_dir}/rust/lib<crate>.rlib`; a binary to `{out_dir}/bin/<target>`
+//! unless `outputs` says otherwise. Crate names are the target name with
+//! anything but letters, digits and `_` replaced by `_`, and each dep must be a
+//! `rust_library`, passed to `rustc` as `--extern` under its own crate name.
+//!
+//! `cargo_crate` builds all of `bins` with one `cargo build` into
+//! `{out_dir}/cargo/<target>`, and its outputs are the binaries in `bins` order.
+//! Cargo writes a `.d` file next to each binary; with several bins they are
+//! joined into one depfile for inputs. `profile` defaults to `dev`, as with cargo.
+//!
+//! `RUSTC` and `CARGO` pick the programs run, and are in the generated targets'
+//! `pass_env`, with the program each picks in `tools`.
+
+use crate::core::builtin::{
+    add_host_tool, file_name, invalid, literal, string, strings, take_string, take_string_list,
+};
+use crate::core::{BuildSpecError, stays_inside};
+use std::collections::HashSet;
+use std::path::Path;
+
+/** Edition `rustc` targets use when they don't set one */
+const DEFAULT_EDITION: &str = "2021";
+
+/** Where `rust_library` targets write their rlibs, so dependents can find transitive crates */
+const RLIB_DIR: &str = "{out_dir}/rust";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Rule {
+    Library,
+    Binary,
+}
+
+/**
+ * Expand a `rust_library` or `rust_binary` target into a single `rustc` invocation
+ */
+pub(crate) fn expand_rustc(
+    name: &str,
+    rule: Rule,
+    mut target: toml::Table,
+) -> Result<Vec<(String, toml::Table)>, BuildSpecError> {
+    let default_root = match rule {
+        Rule::Library => "src/lib.rs",
+        Rule::Binary => "src/main.rs",
+    };
+    let crate_root =
+        take_string(name, &mut target, "crate_root")?.unwrap_or_else(|| default_root.to_string());
+    let edition =
+        take_string(name, &mut target, "edition")?.unwrap_or_else(|| DEFAULT_EDITION.to_string());
+    let flags = take_string_list(name, &mut target, "rustc_flags")?;
+    let deps = take_string_list(name, &mut target, "deps")?;
+
+    if !stays_inside(Path::new(&crate_root)) {
+        return Err(invalid(
+            name,
+            &format!("has crate root '{crate_root}' outside the project"),
+        ));
+    }
+
+    let crate_name = crate_name_of(name);
+    let (crate_type, kind, output) = match rule {
+        Rule::Library => {
+            if target.contains_key("outputs") {
+                return Err(invalid(
+                    name,
+                    "can't set 'outputs'; rust_library writes to {out_dir}/rust",
+                ));
+            }
+            let rlib = format!("{RLIB_DIR}/lib{crate_name}.rlib");
+            ("rlib", "lib", strings([rlib]))
+        }
+        Rule::Binary => {
+            let default = format!("{{out_dir}}/bin/{}", file_name(name));
+            let output = target
+                .remove("outputs")
+                .unwrap_or_else(|| strings([default]));
+            ("bin", "binary", output)
+        }
+    };
+    let depfile = format!("{RLIB_DIR}/{}.d", file_name(name));
+
+    let mut cmd = format!(
+        "${{RUSTC:-rustc}} --edition={} --crate-type={crate_type} --crate-name={crate_name} \
+         --emit=dep-info={depfile},link={{out}} -L dependency={RLIB_DIR}",
+        literal(&edition)
+    );
+    for dep in &deps {
+        cmd.push_str(&format!(
+            " --extern {}={{deps.{dep}.outputs}}",
+            crate_name_of(dep)
+        ));
+    }
+    for flag in &flags {
+        cmd.push_str(&format!(" {}", literal(flag)));
+    }
+    cmd.push_str(&format!(" {}", literal(&crate_root)));
+
+    add_host_tool(&mut target, "RUSTC", "rustc");
+    target.insert("cmd".to_string(), string(cmd));
+    target.insert("inputs".to_string(), strings([crate_root]));
+    target.insert("outputs".to_string(), output);
+    target.insert("depfile".to_string(), string(depfile));
+    target.insert("kind".to_string(), string(kind.to_string()));
+    target.insert("deps".to_string(), strings(deps));
+
+    Ok(vec![(name.to_string(), target)])
+}
+
+/**
+ * Expand a `cargo_crate` target into a single `cargo build` of all its binaries
+ */
+pub(crate) fn expand_cargo(
+    name: &str,
+    mut target: toml::Table,
+    root: Option<&Path>,
+) -> Result<Vec<(String, toml::Table)>, BuildSpecError> {
+    let manifest =
+        take_string(name, &mut target, "manifest")?.unwrap_or_else(|| "Cargo.toml".to_string());
+    let bins = take_string_list(name, &mut target, "bins")?;
+    let profile = take_string(name, &mut target, "profile")?.unwrap_or_else(|| "dev".to_string());
+    let features = take_string_list(name, &mut target, "features")?;
+    let flags = take_string_list(name, &mut target, "cargo_flags")?;
+
+    if target.contains_key("outputs") {
+        return Err(invalid(
+            name,
+            "can't set 'outputs'; cargo_crate writes to {out_dir}/cargo",
+        ));
+    }
+    if bins.is_empty() {
+        return Err(invalid(name, "has no binaries to build in 'bins'"));
+    }
+    if !stays_inside(Path::new(&manifest)) {
+        return Err(invalid(
+            name,
+            &format!("has manifest '{manifest}' outside the project"),
+        ));
+    }
+
+    // The lockfile pins dependency versions, so it is an input when present
+    let mut inputs = vec![manifest.clone()];
+    let lockfile = Path::new(&manifest).with_file_name("Cargo.lock");
+    let root = root.ok_or_else(|| {
+        invalid(
+            name,
+            "can't look for Cargo.lock in a spec loaded without its directory",
+        )
+    })?;
+    if root.join(&lockfile).is_file() {
+        inputs.push(lockfile.to_string_lossy().into_owned());
+    }
+
+    let target_dir = format!("{{out_dir}}/cargo/{}", file_name(name));
+    let profile_dir = match profile.as_str() {
+        "dev" | "test" => "debug",
+        "release" | "bench" => "release",
+        custom => custom,
+    };
+
+    let mut options = String::new();
+    if !features.is_empty() {
+        options.push_str(&format!(" --features {}", literal(&features.join(","))));
+    }
+    for flag in &flags {
+        options.push_str(&format!(" {}", literal(flag)));
+    }
+
+    let outputs: Vec<String> = bins
+        .iter()
+        .map(|bin| format!("{target_dir}/{profile_dir}/{bin}"))
+        .collect();
+    let bin_args: String = bins
+        .iter()
+        .map(|bin| format!(" --bin {}", literal(bin)))
+        .collect();
+    let mut cmd = format!(
+        "${{CARGO:-cargo}} build --manifest-path {} --target-dir {target_dir} --profile {}\
+         {bin_args}{options}",
+        literal(&manifest),
+        literal(&profile)
+    );
+
+    // Cargo writes a `.d` file per binary; several are joined into the target's depfile
+    let depfile = match outputs.as_slice() {
+        [output] => format!("{output}.d"),
+        _ => {
+            let depfile = format!("{target_dir}/{}.d", file_name(name));
+            let parts: Vec<String> = bins
+                .iter()
+                .map(|bin| format!("{target_dir}/{profile_dir}/{}.d", literal(bin)))
+                .collect();
+            cmd.push_str(&format!(" && cat {} > {depfile}", parts.join(" ")));
+            depfile
+        }
+    };
+
+    add_host_tool(&mut target, "CARGO", "cargo");
+    add_host_tool(&mut target, "RUSTC", "rustc");
+    target.insert("cmd".to_string(), string(cmd));
+    target.insert("inputs".to_string(), strings(inputs));
+    target.insert("outputs".to_string(), strings(outputs));
+    target.insert("depfile".to_string(), string(depfile));
+    target.insert("kind".to_string(), string("binary".to_string()));
+
+    Ok(vec![(name.to_string(), target)])
+}
+
+/**
+ * The `rust_library` targets of a spec and the deps each `rustc` target passes
+ * as `--extern`, noted before expansion and checked once every target is known
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Crates {
+    libraries: HashSet<String>,
+    externs: Vec<(String, Vec<String>)>,
+}
+
+impl Crates {
+    /**
+     * Note a target before it is expanded; targets not using a `rustc` rule are ignored
+     */
+    pub(crate) fn note(&mut self, name: &str, target: &toml::Table) {
+        match target.get("rule").and_then(toml::Value::as_str) {
+            Some("rust_library") => {
+                self.libraries.insert(name.to_string());
+            }
+            Some("rust_binary") => {}
+            _ => return,
+        }
+        let deps = target
+            .get("deps")
+            .and_then(toml::Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(toml::Value::as_str)
+            .map(str::to_string)
+            .collect();
+        self.externs.push((name.to_string(), deps));
+    }
+
+    /**
+     * Check that every dep passed as `--extern` is a `rust_library`
+     */
+    pub(crate) fn validate(&self) -> Result<(), BuildSpecError> {
+        for (name, deps) in &self.externs {
+            if let Some(dep) = deps.iter().find(|dep| !self.libraries.contains(*dep)) {
+                return Err(invalid(
+                    name,
+                    &format!("depends on '{dep}', which isn't a rust_library"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * The crate name `rustc` is given for a target, and the name dependents refer to it by
+ */
+fn crate_name_of(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{BuildSpec, TargetKind};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bagel_rust_test_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rustc_rules_pass_deps_as_externs() {
+        let toml = r#"
+            [fmt-utils]
+            rule = "rust_library"
+            edition = "2024"
+
+            [tool]
+            rule = "rust_binary"
+            crate_root = "tool/main.rs"
+            rustc_flags = ["-Copt-level=2", "--cfg=feature=\"x\""]
+            deps = ["fmt-utils"]
+        "#;
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+
+        let lib = spec.get_target("fmt-utils").unwrap();
+        assert_eq!(lib.kind, TargetKind::Lib);
+        assert_eq!(lib.inputs, vec!["src/lib.rs"]);
+        assert_eq!(
+            lib.outputs,
+            vec!["bagel-out/default/rust/libfmt_utils.rlib"]
+        );
+        assert_eq!(
+            lib.depfile.as_deref(),
+            Some("bagel-out/default/rust/fmt-utils.d")
+        );
+
+        let tool = spec.get_target("tool").unwrap();
+        assert_eq!(tool.kind, TargetKind::Binary);
+        assert_eq!(tool.inputs, vec!["tool/main.rs"]);
+        assert_eq!(tool.outputs, vec!["bagel-out/default/bin/tool"]);
+        assert_eq!(tool.deps, vec!["fmt-utils"]);
+        assert_eq!(tool.pass_env, vec!["RUSTC"]);
+        assert_eq!(tool.tools, vec!["${RUSTC:-rustc}"]);
+        assert_eq!(
+            tool.cmd.to_string(),
+            "${RUSTC:-rustc} --edition=2021 --crate-type=bin --crate-name=tool \
+             --emit=dep-info=bagel-out/default/rust/tool.d,link={out} \
+             -L dependency=bagel-out/default/rust \
+             --extern fmt_utils={deps.fmt-utils.outputs} \
+             -Copt-level=2 '--cfg=feature=\"x\"' tool/main.rs"
+        );
+    }
+
+    #[test]
+    fn test_cargo_crate_builds_all_bins_at_once() {
+        let dir = temp_dir("cargo");
+        std::fs::create_dir_all(dir.join("server")).unwrap();
+        std::fs::write(dir.join("server/Cargo.toml"), "").unwrap();
+        std::fs::write(dir.join("server/Cargo.lock"), "").unwrap();
+
+        let toml = r#"
+            [server]
+            rule = "cargo_crate"
+            manifest = "server/Cargo.toml"
+            bins = ["server", "admin"]
+            profile = "release"
+            features = ["tls", "metrics"]
+
+            [cli]
+            rule = "cargo_crate"
+            bins = ["cli"]
+        "#;
+
+        let spec = BuildSpec::from_toml_in(toml, &dir).unwrap();
+
+        // Several bins share one cargo invocation, and their `.d` files are joined
+        let server = spec.get_target("server").unwrap();
+        assert_eq!(spec.targets.len(), 2);
+        assert_eq!(
+            server.cmd.to_string(),
+            "${CARGO:-cargo} build --manifest-path server/Cargo.toml \
+             --target-dir bagel-out/default/cargo/server --profile release \
+             --bin server --bin admin --features tls,metrics \
+             && cat bagel-out/default/cargo/server/release/server.d \
+             bagel-out/default/cargo/server/release/admin.d \
+             > bagel-out/default/cargo/server/server.d"
+        );
+        assert_eq!(
+            server.inputs,
+            vec!["server/Cargo.toml", "server/Cargo.lock"]
+        );
+        assert_eq!(
+            server.outputs,
+            vec![
+                "bagel-out/default/cargo/server/release/server",
+                "bagel-out/default/cargo/server/release/admin"
+            ]
+        );
+        assert_eq!(
+            server.depfile.as_deref(),
+            Some("bagel-out/default/cargo/server/server.d")
+        );
+        assert_eq!(server.pass_env, vec!["CARGO", "RUSTC"]);
+        assert_eq!(server.tools, vec!["${CARGO:-cargo}", "${RUSTC:-rustc}"]);
+
+        let cli = spec.get_target("cli").unwrap();
+        assert_eq!(cli.inputs, vec!["Cargo.toml"]);
+        assert_eq!(cli.outputs, vec!["bagel-out/default/cargo/cli/debug/cli"]);
+        assert_eq!(
+            cli.depfile.as_deref(),
+            Some("bagel-out/default/cargo/cli/debug/cli.d")
+        );
+        assert_eq!(cli.runnable_output("cli").unwrap(), cli.outputs[0]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rust_rules_reject_invalid_targets() {
+        for (toml, expected) in [
+            (
+                "[a]\nrule = \"rust_library\"\noutputs = [\"liba.rlib\"]",
+                "can't set 'outputs'",
+            ),
+            (
+                "[a]\nrule = \"rust_binary\"\ncrate_root = \"../main.rs\"",
+                "outside the project",
+            ),
+            ("[a]\nrule = \"cargo_crate\"", "no binaries to build"),
+            (
+                "[a]\nrule = \"cargo_crate\"\nbins = [\"a\"]\ninputs = [\"src\"]",
+                "can't set 'inputs' when using rule 'cargo_crate'",
+            ),
+            (
+                "[a]\nrule = \"rust_binary\"\ndeps = [\"b\"]\n\
+                 [b]\ncmd = \"true\"\ninputs = [\"b.c\"]\noutputs = [\"b.o\"]",
+                "depends on 'b', which isn't a rust_library",
+            ),
+            (
+                "[a]\nrule = \"rust_binary\"\ndeps = [\"b\"]\n\
+                 [b]\nrule = \"rust_library\"\nmatrix = { opt = [\"0\", \"3\"] }",
+                "depends on 'b', which isn't a rust_library",
+            ),
+        ] {
+            let err = BuildSpec::from_toml(toml).unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+    }
+}
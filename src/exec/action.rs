@@ -0,0 +1,421 @@
+//! Resolution of a target into the concrete action that gets hashed and run
+
+use crate::core::{
+    BuildSpec, BuildSpecError, CommandStep, DyndepFile, RebuildStrategy, Shell, TargetCommand,
+    TargetSpec, resolve_cwd,
+};
+use crate::exec::process::{HERMETIC_FIXED_VARS, HERMETIC_HOST_VARS};
+use crate::exec::types::{ExecConfig, ExecError, TargetResult, TargetStatus};
+use crate::utils::{
+    BuildCache, DigestMemo, FileStat, IgnoreRules, InputFilter, TemplateContext, expand_inputs,
+    parse_depfile,
+    template::{self, Placeholder},
+};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+/**
+ * A target with its input globs resolved, its command placeholders expanded
+ * and the environment its command runs with
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct Action {
+    pub input_files: Vec<PathBuf>,
+    pub steps: Vec<CommandStep>,
+    /** All steps rendered as one shell-like line, for messages */
+    pub cmd: String,
+    /** The command's whole environment */
+    pub env: BTreeMap<OsString, OsString>,
+    pub hermetic: bool,
+    /** Lib targets linked through `{deps.transitive_libs}`; their outputs are keyed like deps' */
+    pub linked_libs: Vec<String>,
+    /** Absolute directory the command runs in */
+    pub cwd: PathBuf,
+    /** Program and leading arguments that run shell steps; empty for the platform shell */
+    pub shell: Vec<String>,
+}
+
+impl Action {
+    pub(crate) fn resolve(
+        name: &str,
+        target: &TargetSpec,
+        spec: &BuildSpec,
+        config: &ExecConfig,
+    ) -> Result<Self, ExecError> {
+        let project_root = config.project_root.as_path();
+        let filter = InputFilter {
+            excluded: spec
+                .targets
+                .iter()
+                .filter(|(other, _)| *other != name)
+                .flat_map(|(_, t)| t.outputs.iter().map(PathBuf::from))
+                .collect(),
+            ignore: IgnoreRules::load(project_root, target.gitignore),
+        };
+        let input_files = expand_inputs(&target.inputs, project_root, &filter)?;
+
+        let cwd = working_dir(name, target, project_root)?;
+        let cwd_rel = target.cwd.as_deref().map(Path::new);
+        let from_cwd = |path: &str| match cwd_rel {
+            Some(cwd_rel) => rebase(path, cwd_rel),
+            None => path.to_string(),
+        };
+
+        let outputs: Vec<String> = target.outputs.iter().map(|o| from_cwd(o)).collect();
+        let dep_outputs: Vec<(&str, Vec<String>)> = target
+            .deps
+            .iter()
+            .filter_map(|dep| {
+                spec.get_target(dep).map(|t| {
+                    let outputs = t.outputs.iter().map(|o| from_cwd(o)).collect();
+                    (dep.as_str(), outputs)
+                })
+            })
+            .collect();
+
+        let uses_libs = target
+            .cmd
+            .steps()
+            .iter()
+            .flat_map(CommandStep::templates)
+            .filter_map(|t| template::placeholders(t).ok())
+            .any(|placeholders| placeholders.contains(&Placeholder::TransitiveLibs));
+        let linked_libs = if uses_libs {
+            spec.transitive_libs(name)
+        } else {
+            Vec::new()
+        };
+
+        let ctx = TemplateContext {
+            name,
+            inputs: input_files
+                .iter()
+                .map(|p| from_cwd(&relative_to(p, project_root)))
+                .collect(),
+            outputs: &outputs,
+            deps: dep_outputs
+                .iter()
+                .map(|(dep, outputs)| (*dep, outputs.as_slice()))
+                .collect(),
+            dep_groups: spec
+                .groups
+                .iter()
+                .filter(|(_, members)| members.iter().all(|m| target.deps.contains(m)))
+                .map(|(group, members)| (group.as_str(), members.as_slice()))
+                .collect(),
+            transitive_libs: linked_libs
+                .iter()
+                .filter_map(|lib| spec.get_target(lib))
+                .flat_map(|lib| lib.outputs.iter().map(|o| from_cwd(o)))
+                .collect(),
+        };
+
+        let steps = target
+            .cmd
+            .steps()
+            .iter()
+            .map(|step| expand_step(step, &ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+        let cmd = TargetCommand::Steps {
+            steps: steps.clone(),
+        }
+        .to_string();
+        let hermetic = config.hermetic || target.hermetic;
+
+        let out_dir = from_cwd(&spec.out_dir());
+
+        Ok(Self {
+            input_files,
+            steps,
+            cmd,
+            env: command_env(name, target, &out_dir, config, hermetic),
+            hermetic,
+            linked_libs,
+            cwd,
+            shell: target.shell.as_ref().map(Shell::argv).unwrap_or_default(),
+        })
+    }
+
+    /**
+     * The command as it enters the cache key: a lone shell step keys as its
+     * text, anything else as the list of expanded steps
+     */
+    pub(crate) fn command_key(&self) -> String {
+        match self.steps.as_slice() {
+            [CommandStep::Shell(cmd)] => cmd.clone(),
+            steps => serde_json::to_string(steps).unwrap_or_default(),
+        }
+    }
+
+    /**
+     * Create the parent directories of the target's outputs and depfile, so commands
+     * can write into per-configuration directories such as `bagel-out/release/`
+     */
+    pub(crate) fn create_output_dirs(
+        &self,
+        target: &TargetSpec,
+        project_root: &Path,
+    ) -> Result<(), ExecError> {
+        for output in target.outputs.iter().chain(&target.depfile) {
+            if let Some(parent) = project_root.join(output).parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| ExecError::CommandError(self.cmd.clone(), e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Expand placeholders in one step; argv elements are expanded without quoting
+ */
+fn expand_step(step: &CommandStep, ctx: &TemplateContext) -> Result<CommandStep, ExecError> {
+    Ok(match step {
+        CommandStep::Shell(cmd) => CommandStep::Shell(template::expand(cmd, ctx)?),
+        CommandStep::Argv(argv) => {
+            let mut expanded = Vec::with_capacity(argv.len());
+            for arg in argv {
+                expanded.extend(template::expand_arg(arg, ctx)?);
+            }
+            CommandStep::Argv(expanded)
+        }
+    })
+}
+
+/**
+ * The whole environment of the target's command: the host's, or in hermetic
+ * mode only the host variables it keeps, with the target's own on top
+ */
+fn command_env(
+    name: &str,
+    target: &TargetSpec,
+    out_dir: &str,
+    config: &ExecConfig,
+    hermetic: bool,
+) -> BTreeMap<OsString, OsString> {
+    let mut env = BTreeMap::new();
+
+    if hermetic {
+        let host_vars = HERMETIC_HOST_VARS
+            .iter()
+            .copied()
+            .chain(target.pass_env.iter().map(String::as_str));
+        for var in host_vars {
+            if let Some(value) = config.host_env.get(OsStr::new(var)) {
+                env.insert(var.into(), value.clone());
+            }
+        }
+        for (var, value) in HERMETIC_FIXED_VARS {
+            env.insert(var.into(), value.into());
+        }
+    } else {
+        env.extend(config.host_env.clone());
+    }
+
+    env.extend(
+        target
+            .env
+            .iter()
+            .map(|(var, value)| (var.into(), value.into())),
+    );
+
+    let jobs = if config.parallel {
+        rayon::current_num_threads()
+    } else {
+        1
+    };
+    env.insert("BAGEL_TARGET".into(), name.into());
+    env.insert("BAGEL_OUT_DIR".into(), out_dir.into());
+    env.insert("BAGEL_JOBS".into(), jobs.to_string().into());
+
+    env
+}
+
+/**
+ * The directory the target's command runs in. Specs loaded with their root
+ * were checked already, but the directory may have changed since.
+ */
+pub(crate) fn working_dir(
+    name: &str,
+    target: &TargetSpec,
+    project_root: &Path,
+) -> Result<PathBuf, ExecError> {
+    match &target.cwd {
+        Some(cwd) => Ok(resolve_cwd(name, cwd, project_root)?),
+        None => Ok(project_root.to_path_buf()),
+    }
+}
+
+/**
+ * Output hashes recorded for each of the target's deps; these feed its cache key
+ */
+pub(crate) fn dep_output_hashes(
+    target: &TargetSpec,
+    cache: &mut BuildCache,
+) -> BTreeMap<String, String> {
+    target
+        .deps
+        .iter()
+        .map(|dep| (dep.clone(), cache.output_hash(dep).unwrap_or_default()))
+        .collect()
+}
+
+/**
+ * Flag a skipped target as saved by early cutoff when something upstream of it
+ * changed in this build. `changed` tracks targets that were rebuilt or cut off.
+ */
+pub(crate) fn note_cutoff(
+    result: &mut TargetResult,
+    target: &TargetSpec,
+    changed: &mut HashSet<String>,
+) {
+    match result.status {
+        TargetStatus::Built => {
+            changed.insert(result.target_name.clone());
+        }
+        // A group passes its deps' changes on; it was never going to run
+        TargetStatus::Skipped if target.deps.iter().any(|dep| changed.contains(dep)) => {
+            result.cutoff = !target.is_group();
+            changed.insert(result.target_name.clone());
+        }
+        _ => {}
+    }
+}
+
+/**
+ * Read the target's depfile after a successful build and record every file it
+ * lists in the cache. Under `mtime` only their stats are kept; under `stat`
+ * both stats and hashes, and under `hash` only hashes. A missing depfile
+ * means nothing was discovered.
+ */
+pub(crate) fn discover_inputs(
+    name: &str,
+    target: &TargetSpec,
+    cache: &mut BuildCache,
+    project_root: &Path,
+    memo: &DigestMemo,
+) -> Result<(), ExecError> {
+    let mut discovered = BTreeMap::new();
+    let mut stats = BTreeMap::new();
+    let content = target
+        .depfile
+        .as_ref()
+        .and_then(|depfile| std::fs::read_to_string(project_root.join(depfile)).ok())
+        .unwrap_or_default();
+
+    // Relative paths in the depfile are as the command saw them, from its cwd
+    let cwd = target
+        .cwd
+        .as_deref()
+        .map(Path::new)
+        .unwrap_or(Path::new(""));
+    for path in parse_depfile(&content) {
+        let full_path = project_root.join(cwd).join(&path);
+        if full_path.is_file() {
+            // Absolute paths inside the project are recorded relative, like declared inputs
+            let key = match Path::new(&path).is_absolute() {
+                true => relative_to(Path::new(&path), project_root),
+                false => normalize(&cwd.join(&path)).to_string_lossy().into_owned(),
+            };
+            let hash = match target.rebuild {
+                RebuildStrategy::Mtime => String::new(),
+                _ => memo.hash(&full_path, target.hash_algorithm)?,
+            };
+            if target.rebuild != RebuildStrategy::Hash {
+                let stat = FileStat::of(&full_path)?;
+                // A racy stat can't vouch for the hash; mtime has nothing else to go on
+                if target.rebuild == RebuildStrategy::Mtime || !stat.is_racy() {
+                    stats.insert(key.clone(), stat);
+                }
+            }
+            discovered.insert(key, hash);
+        }
+    }
+
+    cache.record_discovered_inputs(name, discovered);
+    cache.record_discovered_stats(name, stats);
+    Ok(())
+}
+
+/**
+ * Once `name` has finished (built or up to date), fold its dyndep file into the
+ * spec. Returns whether the graph changed, in which case callers reschedule.
+ */
+pub(crate) fn apply_dyndep(
+    spec: &mut BuildSpec,
+    name: &str,
+    project_root: &Path,
+) -> Result<bool, ExecError> {
+    let Some(path) = spec.get_target(name).and_then(|t| t.dyndep.clone()) else {
+        return Ok(false);
+    };
+
+    if !project_root.join(&path).is_file() {
+        return Err(BuildSpecError::InvalidTarget(format!(
+            "Target '{name}' did not write its dyndep file '{path}'"
+        ))
+        .into());
+    }
+
+    let dyndep = DyndepFile::from_file(project_root.join(&path))?;
+    spec.apply_dyndep(name, &dyndep)?;
+    Ok(!dyndep.targets.is_empty())
+}
+
+/**
+ * Rewrite a project-relative `path` to be relative to `cwd` (itself
+ * project-relative), e.g. `src/main.c` from `build` is `../src/main.c`
+ */
+fn rebase(path: &str, cwd: &Path) -> String {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return path.to_string_lossy().into_owned();
+    }
+
+    let (cwd, path) = (normalize(cwd), normalize(path));
+    let from: Vec<_> = cwd.components().collect();
+    let to: Vec<_> = path.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut rebased = PathBuf::new();
+    for _ in common..from.len() {
+        rebased.push("..");
+    }
+    rebased.extend(&to[common..]);
+
+    if rebased.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        rebased.to_string_lossy().into_owned()
+    }
+}
+
+/**
+ * Resolve `.` and `..` in a relative path without touching the filesystem
+ */
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/**
+ * Render `path` relative to `root` when it lives under it
+ */
+pub(crate) fn relative_to(path: &Path, root: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
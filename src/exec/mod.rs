@@ -2,18 +2,26 @@
 //!
 //! Provides serial and parallel executors for building targets.
 
+mod action;
 mod parallel;
+mod process;
+mod rebuild;
+mod report;
 mod serial;
+mod test;
 mod types;
 
 pub use parallel::ParallelExecutor;
 pub use serial::SerialExecutor;
+pub use test::{TestReport, TestRunner};
 pub use types::{BuildReport, ExecConfig, ExecError, TargetResult, TargetStatus};
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::core::BuildSpec;
+    use std::ffi::OsString;
+    use std::io::Write;
     use std::path::PathBuf;
     use std::time::Duration;
 
@@ -33,18 +41,24 @@ mod tests {
                     status: TargetStatus::Built,
                     duration: Duration::from_secs(1),
                     output: None,
+                    stderr: None,
+                    cutoff: false,
                 },
                 TargetResult {
                     target_name: "b".to_string(),
                     status: TargetStatus::Skipped,
                     duration: Duration::from_millis(10),
                     output: None,
+                    stderr: None,
+                    cutoff: false,
                 },
                 TargetResult {
                     target_name: "c".to_string(),
                     status: TargetStatus::Failed(1),
                     duration: Duration::from_secs(2),
                     output: None,
+                    stderr: None,
+                    cutoff: false,
                 },
             ],
             total_duration: Duration::from_secs(3),
@@ -52,6 +66,7 @@ mod tests {
 
         assert_eq!(report.built_count(), 1);
         assert_eq!(report.skipped_count(), 1);
+        assert_eq!(report.cutoff_count(), 0);
         assert_eq!(report.failed_count(), 1);
         assert!(!report.success());
     }
@@ -116,6 +131,1714 @@ mod tests {
         std::fs::remove_dir_all(&dir).ok();
     }
 
+    #[test]
+    fn test_command_placeholders_expanded() {
+        let dir = temp_dir("placeholders");
+
+        let toml = r#"
+            [gen]
+            cmd = "cat {inputs} > {outputs}"
+            inputs = ["part *.txt"]
+            outputs = ["gen.txt"]
+
+            [final]
+            cmd = "cp {deps.gen.outputs} {out} && echo {name} >> {out}"
+            inputs = ["part 1.txt"]
+            outputs = ["final.txt"]
+            deps = ["gen"]
+        "#;
+
+        std::fs::write(dir.join("part 1.txt"), "one\n").unwrap();
+        std::fs::write(dir.join("part 2.txt"), "two\n").unwrap();
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let mut executor = SerialExecutor::new(ExecConfig::new(&dir)).unwrap();
+        let report = executor.execute_all(&spec).unwrap();
+        assert!(report.success());
+
+        let content = std::fs::read_to_string(dir.join("final.txt")).unwrap();
+        assert_eq!(content, "one\ntwo\nfinal\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_configs_use_separate_outputs_and_cache() {
+        let dir = temp_dir("configs");
+
+        let toml = r#"
+            [app]
+            cmd = "echo $FLAGS > {out}"
+            inputs = ["input.txt"]
+            outputs = ["{out_dir}/app.txt"]
+
+            [app.env]
+            FLAGS = "-g"
+
+            [configs.release.env]
+            FLAGS = "-O2"
+        "#;
+
+        std::fs::write(dir.join("input.txt"), "test").unwrap();
+
+        let build = |config: Option<&str>| {
+            let spec = BuildSpec::load(toml, &dir, config).unwrap();
+            let mut exec_config = ExecConfig::new(&dir);
+            exec_config.build_config = config.map(str::to_string);
+            SerialExecutor::new(exec_config)
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build(None).built_count(), 1);
+        assert_eq!(build(Some("release")).built_count(), 1);
+
+        // Switching back and forth doesn't invalidate either configuration
+        assert_eq!(build(None).skipped_count(), 1);
+        assert_eq!(build(Some("release")).skipped_count(), 1);
+
+        let read = |path: &str| std::fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!(read("bagel-out/default/app.txt"), "-g\n");
+        assert_eq!(read("bagel-out/release/app.txt"), "-O2\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_configs_rebuild_outputs_they_share() {
+        let dir = temp_dir("configs_shared");
+
+        // Both configurations write the same path
+        let toml = r#"
+            [app]
+            cmd = "echo $FLAGS > {out}"
+            inputs = ["input.txt"]
+            outputs = ["app.txt"]
+
+            [app.env]
+            FLAGS = "-g"
+
+            [configs.release.env]
+            FLAGS = "-O2"
+        "#;
+
+        std::fs::write(dir.join("input.txt"), "test").unwrap();
+
+        let build = |config: Option<&str>| {
+            let spec = BuildSpec::load(toml, &dir, config).unwrap();
+            let mut exec_config = ExecConfig::new(&dir);
+            exec_config.build_config = config.map(str::to_string);
+            SerialExecutor::new(exec_config)
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+        let read = || std::fs::read_to_string(dir.join("app.txt")).unwrap();
+
+        assert_eq!(build(None).built_count(), 1);
+        assert_eq!(build(Some("release")).built_count(), 1);
+        assert_eq!(read(), "-O2\n");
+
+        // The default config's cache no longer vouches for what's on disk
+        assert_eq!(build(None).built_count(), 1);
+        assert_eq!(read(), "-g\n");
+        assert_eq!(build(None).skipped_count(), 1);
+
+        // Selecting the default config by name uses the same cache
+        assert_eq!(build(Some("default")).skipped_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_depfile_inputs_trigger_rebuild() {
+        let dir = temp_dir("depfile");
+
+        // Stands in for `gcc -MD`: the header is only known through the depfile
+        let toml = r#"
+            [main]
+            cmd = "cat main.c util.h > main.o && printf 'main.o: main.c \\\n util.h\n' > main.d"
+            inputs = ["main.c"]
+            outputs = ["main.o"]
+            depfile = "main.d"
+        "#;
+
+        std::fs::write(dir.join("main.c"), "int main;").unwrap();
+        std::fs::write(dir.join("util.h"), "#define A 1").unwrap();
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build().built_count(), 1);
+        assert_eq!(build().skipped_count(), 1);
+
+        std::fs::write(dir.join("util.h"), "#define A 2").unwrap();
+        assert_eq!(build().built_count(), 1);
+        assert_eq!(build().skipped_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_dyndep_adds_deps_during_build() {
+        let dir = temp_dir("dyndep");
+
+        // `scan` only learns while running that `main` needs `gen`'s output; without
+        // the update, `main` would be scheduled alongside `gen`
+        let toml = r#"
+            [scan]
+            cmd = "printf '[main]\\ninputs = [\"gen.txt\"]\\ndeps = [\"gen\"]\\n' > {out}"
+            inputs = ["main.txt"]
+            outputs = ["main.dd"]
+            dyndep = "main.dd"
+
+            [prep]
+            cmd = "echo prep"
+            inputs = ["main.txt"]
+            outputs = ["prep.out"]
+
+            [gen]
+            cmd = "echo generated > {out}"
+            inputs = ["main.txt"]
+            outputs = ["gen.txt"]
+            deps = ["prep"]
+
+            [main]
+            cmd = "cat main.txt gen.txt > {out}"
+            inputs = ["main.txt"]
+            outputs = ["main.out"]
+            deps = ["scan"]
+        "#;
+
+        std::fs::write(dir.join("main.txt"), "main\n").unwrap();
+        let spec = BuildSpec::from_toml(toml).unwrap();
+
+        for parallel in [false, true] {
+            std::fs::remove_dir_all(dir.join(".bagel")).ok();
+            std::fs::remove_file(dir.join("gen.txt")).ok();
+
+            let mut config = ExecConfig::new(&dir);
+            config.parallel = parallel;
+            let report = if parallel {
+                ParallelExecutor::new(config).unwrap().execute_all(&spec)
+            } else {
+                SerialExecutor::new(config).unwrap().execute_all(&spec)
+            }
+            .unwrap();
+
+            assert!(report.success());
+            let names: Vec<&str> = report
+                .results
+                .iter()
+                .map(|r| r.target_name.as_str())
+                .collect();
+            let pos_gen = names.iter().position(|&n| n == "gen").unwrap();
+            let pos_main = names.iter().position(|&n| n == "main").unwrap();
+            assert!(pos_gen < pos_main, "gen should be built before main");
+
+            let content = std::fs::read_to_string(dir.join("main.out")).unwrap();
+            assert_eq!(content, "main\ngenerated\n");
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_dyndep_pulls_in_deps_outside_the_selection() {
+        let dir = temp_dir("dyndep_closure");
+
+        let toml = r#"
+            [scan]
+            cmd = "printf '[main]\\ndeps = [\"foo_mod\"]\\n' > {out}"
+            inputs = ["main.txt"]
+            outputs = ["main.dd"]
+            dyndep = "main.dd"
+
+            [foo_prep]
+            cmd = "echo prep > {out}"
+            inputs = ["main.txt"]
+            outputs = ["foo.prep"]
+
+            [foo_mod]
+            cmd = "cp foo.prep {out}"
+            inputs = ["main.txt"]
+            outputs = ["foo.mod"]
+            deps = ["foo_prep"]
+
+            [main]
+            cmd = "cat main.txt foo.mod > {out}"
+            inputs = ["main.txt"]
+            outputs = ["main.out"]
+            deps = ["scan"]
+        "#;
+
+        std::fs::write(dir.join("main.txt"), "main\n").unwrap();
+        // As `bagel build main` selects it
+        let spec = BuildSpec::from_toml(toml)
+            .unwrap()
+            .closure(&["main".to_string()])
+            .unwrap();
+        assert!(!spec.has_target("foo_mod"));
+
+        for parallel in [false, true] {
+            std::fs::remove_dir_all(dir.join(".bagel")).ok();
+
+            let mut config = ExecConfig::new(&dir);
+            config.parallel = parallel;
+            let report = if parallel {
+                ParallelExecutor::new(config).unwrap().execute_all(&spec)
+            } else {
+                SerialExecutor::new(config).unwrap().execute_all(&spec)
+            }
+            .unwrap();
+
+            assert!(report.success());
+            assert_eq!(report.built_count(), 4);
+            let content = std::fs::read_to_string(dir.join("main.out")).unwrap();
+            assert_eq!(content, "main\nprep\n");
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_parallel_reports_why_a_target_could_not_run() {
+        let dir = temp_dir("parallel_error");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+
+        let toml = r#"
+            [app]
+            cmd = "no-such-tool-xyz {in}"
+            inputs = ["in.txt"]
+            outputs = ["app.out"]
+            tools = ["no-such-tool-xyz"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let mut config = ExecConfig::new(&dir);
+        config.parallel = true;
+        let report = ParallelExecutor::new(config)
+            .unwrap()
+            .execute_all(&spec)
+            .unwrap();
+
+        assert_eq!(report.failed_count(), 1);
+        let stderr = report.results[0].stderr.as_deref().unwrap();
+        assert!(stderr.contains("Tool 'no-such-tool-xyz' was not found on PATH"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_early_cutoff_on_identical_outputs() {
+        let dir = temp_dir("cutoff");
+
+        // utils.o ignores comments in utils.c, like a compiler would
+        let toml = r#"
+            [utils]
+            cmd = "grep -v '^//' utils.c > {out}"
+            inputs = ["utils.c"]
+            outputs = ["utils.o"]
+
+            [lib1]
+            cmd = "cat {deps.utils.outputs} > {out}"
+            inputs = ["lib1.c"]
+            outputs = ["lib1.o"]
+            deps = ["utils"]
+
+            [lib2]
+            cmd = "cat {deps.utils.outputs} > {out}"
+            inputs = ["lib2.c"]
+            outputs = ["lib2.o"]
+            deps = ["utils"]
+
+            [app]
+            cmd = "cat {deps.outputs} > {out}"
+            inputs = ["main.c"]
+            outputs = ["app"]
+            deps = ["lib1", "lib2"]
+        "#;
+
+        for file in ["lib1.c", "lib2.c", "main.c"] {
+            std::fs::write(dir.join(file), file).unwrap();
+        }
+        std::fs::write(dir.join("utils.c"), "int x;\n").unwrap();
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+
+        for parallel in [false, true] {
+            std::fs::remove_dir_all(dir.join(".bagel")).ok();
+            let build = || {
+                let mut config = ExecConfig::new(&dir);
+                config.parallel = parallel;
+                if parallel {
+                    ParallelExecutor::new(config).unwrap().execute_all(&spec)
+                } else {
+                    SerialExecutor::new(config).unwrap().execute_all(&spec)
+                }
+                .unwrap()
+            };
+
+            assert_eq!(build().built_count(), 4);
+
+            // Only a comment changes: utils rebuilds, everything downstream is cut off
+            std::fs::write(dir.join("utils.c"), "// comment\nint x;\n").unwrap();
+            let report = build();
+            assert_eq!(report.built_count(), 1);
+            assert_eq!(report.cutoff_count(), 3);
+
+            // A real change propagates
+            std::fs::write(dir.join("utils.c"), "int y;\n").unwrap();
+            let report = build();
+            assert_eq!(report.built_count(), 4);
+            assert_eq!(report.cutoff_count(), 0);
+
+            std::fs::write(dir.join("utils.c"), "int x;\n").unwrap();
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn set_mtime(path: &std::path::Path, secs_ago: u64) {
+        // Whole seconds, so repeated calls with the same age set the same mtime
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let time = std::time::UNIX_EPOCH + Duration::from_secs(now - secs_ago);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_mtime_strategy_compares_timestamps() {
+        let dir = temp_dir("rebuild_mtime");
+        std::fs::write(dir.join("in.txt"), "data").unwrap();
+
+        let toml = r#"
+            [copy]
+            cmd = "cp {in} {out}"
+            inputs = ["in.txt"]
+            outputs = ["out.txt"]
+            rebuild = "mtime"
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        set_mtime(&dir.join("in.txt"), 60);
+        assert_eq!(build().built_count(), 1);
+        assert_eq!(build().skipped_count(), 1);
+
+        // Touching the input rebuilds even though its contents are unchanged
+        set_mtime(&dir.join("out.txt"), 30);
+        set_mtime(&dir.join("in.txt"), 10);
+        assert_eq!(build().built_count(), 1);
+
+        // A missing output is always out of date
+        std::fs::remove_file(dir.join("out.txt")).unwrap();
+        assert_eq!(build().built_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_mtime_strategy_without_outputs_compares_recorded_stats() {
+        let dir = temp_dir("rebuild_mtime_no_outputs");
+        std::fs::write(dir.join("case.txt"), "ok").unwrap();
+        set_mtime(&dir.join("case.txt"), 60);
+
+        let toml = r#"
+            [case_test]
+            cmd = "grep -q ok {in}"
+            inputs = ["case.txt"]
+            kind = "test"
+            rebuild = "mtime"
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let tests = spec.tests_matching(&[]).unwrap();
+        let run = || {
+            TestRunner::new(ExecConfig::new(&dir), 1)
+                .run(&spec, &tests)
+                .unwrap()
+        };
+
+        assert_eq!(run().passed_count(), 1);
+        assert_eq!(run().cached_count(), 1);
+
+        // Touching the input reruns the test even though its contents are unchanged
+        set_mtime(&dir.join("case.txt"), 30);
+        let rerun = run();
+        assert_eq!(rerun.cached_count(), 0);
+        assert_eq!(rerun.passed_count(), 1);
+        assert_eq!(run().cached_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_mtime_strategy_judges_discovered_inputs_and_outputs_by_stat() {
+        let dir = temp_dir("rebuild_mtime_stat");
+        std::fs::write(dir.join("in.txt"), "data").unwrap();
+        std::fs::write(dir.join("dep.h"), "aaaa").unwrap();
+        set_mtime(&dir.join("in.txt"), 60);
+        set_mtime(&dir.join("dep.h"), 60);
+
+        let toml = r#"
+            [copy]
+            cmd = "cp {in} {out} && echo 'out.txt: in.txt dep.h' > out.d"
+            inputs = ["in.txt"]
+            outputs = ["out.txt"]
+            depfile = "out.d"
+            rebuild = "mtime"
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build().built_count(), 1);
+
+        // New contents behind an unchanged stat go unnoticed: nothing is read
+        let rewrite_keeping_stat = |path: &std::path::Path, contents: &[u8]| {
+            let mtime = std::fs::metadata(path).unwrap().modified().unwrap();
+            let mut file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+            file.write_all(contents).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+        rewrite_keeping_stat(&dir.join("dep.h"), b"bbbb");
+        rewrite_keeping_stat(&dir.join("out.txt"), b"DATA");
+        assert_eq!(build().skipped_count(), 1);
+
+        // Touching a discovered input rebuilds, as with declared inputs
+        set_mtime(&dir.join("dep.h"), 10);
+        assert_eq!(build().built_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_stat_strategy_reuses_hashes_of_unchanged_files() {
+        let dir = temp_dir("rebuild_stat");
+        let input = dir.join("asset.bin");
+        std::fs::write(&input, "aaaa").unwrap();
+        set_mtime(&input, 60);
+
+        let toml = r#"
+            [defaults]
+            rebuild = "stat"
+
+            [pack]
+            cmd = "cp {in} {out}"
+            inputs = ["asset.bin"]
+            outputs = ["packed.bin"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build().built_count(), 1);
+
+        // Same size, mtime and inode: the recorded hash is trusted without reading the file
+        let mtime = std::fs::metadata(&input).unwrap().modified().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&input)
+            .unwrap();
+        file.write_all(b"bbbb").unwrap();
+        file.set_modified(mtime).unwrap();
+        assert_eq!(build().skipped_count(), 1);
+
+        // A new mtime forces a re-hash, which sees the new contents
+        set_mtime(&input, 30);
+        assert_eq!(build().built_count(), 1);
+
+        // Touched again with identical contents: re-hashed but not rebuilt
+        set_mtime(&input, 20);
+        assert_eq!(build().skipped_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_hash_algorithm_switch_rebuilds_once() {
+        let dir = temp_dir("hash_algorithm");
+        std::fs::write(dir.join("in.txt"), "data").unwrap();
+
+        let spec_with = |algorithm: &str| {
+            BuildSpec::from_toml(&format!(
+                r#"
+                [copy]
+                cmd = "cp {{in}} {{out}}"
+                inputs = ["in.txt"]
+                outputs = ["out.txt"]
+                hash_algorithm = "{algorithm}"
+                "#
+            ))
+            .unwrap()
+        };
+        let build = |spec: &BuildSpec| {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(spec)
+                .unwrap()
+        };
+
+        let xxh64 = spec_with("xxh64");
+        assert_eq!(build(&xxh64).built_count(), 1);
+        assert_eq!(build(&xxh64).skipped_count(), 1);
+
+        std::fs::write(dir.join("in.txt"), "changed").unwrap();
+        assert_eq!(build(&xxh64).built_count(), 1);
+
+        let sha256 = spec_with("sha256");
+        assert_eq!(build(&sha256).built_count(), 1);
+        assert_eq!(build(&sha256).skipped_count(), 1);
+
+        let xxh3 = spec_with("xxh3");
+        assert_eq!(build(&xxh3).built_count(), 1);
+        assert_eq!(build(&xxh3).skipped_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_cache_hits_survive_moving_the_project() {
+        let base = temp_dir("relocate");
+        let original = base.join("original");
+        std::fs::create_dir_all(original.join("src")).unwrap();
+        std::fs::write(original.join("src/util.h"), "#define N 1\n").unwrap();
+        std::fs::write(original.join("src/util.c"), "int util;\n").unwrap();
+        std::fs::write(original.join("src/main.c"), "int main;\n").unwrap();
+
+        let toml = r#"
+            [utils]
+            cmd = "cat {in} > {out} && printf 'util.o: src/util.c src/util.h\n' > util.d"
+            inputs = ["src/util.c"]
+            outputs = ["util.o"]
+            depfile = "util.d"
+
+            [app]
+            cmd = "cat {inputs} {deps.outputs} > {out}"
+            inputs = ["src/*.c"]
+            outputs = ["app"]
+            deps = ["utils"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+
+        for parallel in [false, true] {
+            let build = |root: &std::path::Path| {
+                let mut config = ExecConfig::new(root);
+                config.parallel = parallel;
+                if parallel {
+                    ParallelExecutor::new(config).unwrap().execute_all(&spec)
+                } else {
+                    SerialExecutor::new(config).unwrap().execute_all(&spec)
+                }
+                .unwrap()
+            };
+
+            std::fs::remove_dir_all(original.join(".bagel")).ok();
+            assert_eq!(build(&original).built_count(), 2);
+
+            // A plain recursive copy: new paths, inodes and mtimes
+            let moved = base.join(format!("moved-{parallel}"));
+            let status = std::process::Command::new("cp")
+                .arg("-R")
+                .arg(&original)
+                .arg(&moved)
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            let report = build(&moved);
+            assert_eq!(report.skipped_count(), 2);
+            assert_eq!(report.built_count(), 0);
+
+            // The moved copy still tracks its own inputs, including discovered ones
+            std::fs::write(moved.join("src/util.h"), "#define N 2\n").unwrap();
+            assert_eq!(build(&moved).built_count(), 1);
+        }
+
+        std::fs::remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn test_directory_inputs_and_outputs() {
+        let dir = temp_dir("tree_artifacts");
+        std::fs::create_dir_all(dir.join("assets/img")).unwrap();
+        std::fs::write(dir.join("assets/img/logo.svg"), "<svg/>").unwrap();
+        std::fs::write(dir.join("assets/style.css"), "body {}").unwrap();
+
+        let toml = r#"
+            [bundle]
+            cmd = "rm -rf dist && cp -R assets dist"
+            inputs = ["assets"]
+            outputs = ["dist"]
+
+            [manifest]
+            cmd = "find {deps.outputs} -type f | sort > {out}"
+            inputs = ["manifest.txt.in"]
+            outputs = ["manifest.txt"]
+            deps = ["bundle"]
+        "#;
+        std::fs::write(dir.join("manifest.txt.in"), "").unwrap();
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build().built_count(), 2);
+        assert_eq!(build().skipped_count(), 2);
+
+        // A nested change rebuilds the tree and everything using it
+        std::fs::write(dir.join("assets/img/logo.svg"), "<svg></svg>").unwrap();
+        assert_eq!(build().built_count(), 2);
+
+        // Adding a file to the tree is a change too
+        std::fs::write(dir.join("assets/img/icon.svg"), "<svg/>").unwrap();
+        assert_eq!(build().built_count(), 2);
+        let manifest = std::fs::read_to_string(dir.join("manifest.txt")).unwrap();
+        assert!(manifest.contains("dist/img/icon.svg"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_ignored_and_generated_files_do_not_trigger_rebuilds() {
+        let dir = temp_dir("ignored_inputs");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/main.c"), "int main;").unwrap();
+        std::fs::write(dir.join("src/api.idl"), "api").unwrap();
+        std::fs::write(dir.join(".gitignore"), "*.swp\n").unwrap();
+
+        let toml = r#"
+            [defaults]
+            gitignore = true
+
+            [gen]
+            cmd = "cp {in} {out}"
+            inputs = ["src/api.idl"]
+            outputs = ["src/api.c"]
+
+            [app]
+            cmd = "cat {inputs} > {out}"
+            inputs = ["src/**/*", "!src/*.idl"]
+            outputs = ["app"]
+            deps = ["gen"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build().built_count(), 2);
+        assert_eq!(build().skipped_count(), 2);
+
+        // The generated api.c appears after the first build but isn't an input of app
+        assert_eq!(
+            std::fs::read_to_string(dir.join("app")).unwrap(),
+            "int main;"
+        );
+
+        std::fs::write(dir.join("src/.main.c.swp"), "swap").unwrap();
+        assert_eq!(build().skipped_count(), 2);
+
+        std::fs::write(dir.join("src/main.c"), "int main2;").unwrap();
+        assert_eq!(build().built_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_tools_and_pass_env_are_part_of_the_key() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("host_inputs");
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        std::fs::write(dir.join("main.c"), "int main;").unwrap();
+
+        let write_tool = |version: &str| {
+            let tool = dir.join("bin/mycc");
+            std::fs::write(&tool, format!("#!/bin/sh\necho {version} > \"$1\"\n")).unwrap();
+            std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        write_tool("v1");
+
+        let toml = format!(
+            r#"
+            [app]
+            cmd = "mycc {{out}}"
+            inputs = ["main.c"]
+            outputs = ["app"]
+            tools = ["mycc"]
+            pass_env = ["BAGEL_TEST_PASS_ENV"]
+
+            [app.env]
+            PATH = "{}:/usr/bin:/bin"
+            "#,
+            dir.join("bin").display()
+        );
+        let spec = BuildSpec::from_toml(&toml).unwrap();
+        let build = |pass_env: Option<&str>| {
+            let mut config = ExecConfig::new(&dir);
+            if let Some(value) = pass_env {
+                config
+                    .host_env
+                    .insert("BAGEL_TEST_PASS_ENV".into(), value.into());
+            }
+            SerialExecutor::new(config)
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build(None).built_count(), 1);
+        assert_eq!(build(None).skipped_count(), 1);
+
+        // Upgrading the compiler rebuilds
+        write_tool("v2");
+        assert_eq!(build(None).built_count(), 1);
+        assert_eq!(std::fs::read_to_string(dir.join("app")).unwrap(), "v2\n");
+
+        // So does a change to a passed-through host variable
+        assert_eq!(build(Some("1")).built_count(), 1);
+        assert_eq!(build(Some("1")).skipped_count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_missing_tool_fails_the_build() {
+        let dir = temp_dir("missing_tool");
+        std::fs::write(dir.join("main.c"), "int main;").unwrap();
+
+        let toml = r#"
+            [app]
+            cmd = "true"
+            inputs = ["main.c"]
+            outputs = ["app"]
+            tools = ["bagel-no-such-compiler"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let err = SerialExecutor::new(ExecConfig::new(&dir))
+            .unwrap()
+            .execute_all(&spec)
+            .unwrap_err();
+        assert!(err.to_string().contains("bagel-no-such-compiler"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_tools_follow_the_variable_that_selects_them() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("tool_variable");
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        std::fs::write(dir.join("main.c"), "int main;").unwrap();
+        let tool = dir.join("bin/mycc");
+        std::fs::write(&tool, "#!/bin/sh\nfor out; do :; done\ntouch \"$out\"\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // The default doesn't exist, so resolving it would fail the build
+        let spec_with = |cc: &str| {
+            let toml = format!(
+                r#"
+                [app]
+                cmd = "${{BAGEL_CC:-bagel-no-such-compiler}} -o {{out}}"
+                inputs = ["main.c"]
+                outputs = ["app"]
+                tools = ["${{BAGEL_CC:-bagel-no-such-compiler}}"]
+
+                [app.env]
+                BAGEL_CC = "{cc}"
+                PATH = "{}:/usr/bin:/bin"
+                "#,
+                dir.join("bin").display()
+            );
+            BuildSpec::from_toml(&toml).unwrap()
+        };
+        let build = |spec: &BuildSpec| {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(spec)
+        };
+
+        assert_eq!(build(&spec_with("mycc -O2")).unwrap().built_count(), 1);
+        let err = build(&spec_with("")).unwrap_err();
+        assert!(err.to_string().contains("bagel-no-such-compiler"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hermetic_env() {
+        let dir = temp_dir("hermetic_env");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+
+        let toml = r#"
+            [open]
+            cmd = "env | sort > {out}"
+            inputs = ["in.txt"]
+            outputs = ["open.env"]
+
+            [sealed]
+            cmd = "env | sort > {out}"
+            inputs = ["in.txt"]
+            outputs = ["sealed.env"]
+            hermetic = true
+            pass_env = ["BAGEL_TEST_PASSED"]
+            env = { LANG = "en_US.UTF-8" }
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let mut config = ExecConfig::new(&dir);
+        config.parallel = true;
+        for (var, value) in [
+            ("BAGEL_TEST_HOST_ONLY", "leaked"),
+            ("BAGEL_TEST_PASSED", "passed"),
+        ] {
+            config.host_env.insert(var.into(), value.into());
+        }
+        ParallelExecutor::new(config)
+            .unwrap()
+            .execute_all(&spec)
+            .unwrap();
+
+        let open = std::fs::read_to_string(dir.join("open.env")).unwrap();
+        assert!(open.contains("BAGEL_TEST_HOST_ONLY=leaked"));
+        assert!(open.contains("BAGEL_TARGET=open\n"));
+
+        let sealed = std::fs::read_to_string(dir.join("sealed.env")).unwrap();
+        assert!(!sealed.contains("BAGEL_TEST_HOST_ONLY"));
+        assert!(sealed.contains("BAGEL_TEST_PASSED=passed"));
+        assert!(sealed.contains("TZ=UTC"));
+        assert!(sealed.contains("LANG=en_US.UTF-8"));
+        assert!(sealed.contains("BAGEL_TARGET=sealed\n"));
+        assert!(sealed.contains("BAGEL_OUT_DIR=bagel-out/default\n"));
+        assert!(sealed.contains(&format!("BAGEL_JOBS={}\n", rayon::current_num_threads())));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_unicode_host_vars_are_inherited() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let dir = temp_dir("non_unicode_env");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+
+        let toml = r#"
+            [app]
+            cmd = "printf '%s' \"$BAGEL_TEST_LATIN1\" > {out}"
+            inputs = ["in.txt"]
+            outputs = ["app.out"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let mut config = ExecConfig::new(&dir);
+        let value = b"caf\xe9".to_vec();
+        config.host_env.insert(
+            "BAGEL_TEST_LATIN1".into(),
+            OsString::from_vec(value.clone()),
+        );
+        let report = SerialExecutor::new(config)
+            .unwrap()
+            .execute_all(&spec)
+            .unwrap();
+        assert!(report.success());
+
+        assert_eq!(std::fs::read(dir.join("app.out")).unwrap(), value);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hermetic_path_is_part_of_the_key() {
+        let dir = temp_dir("hermetic_key");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+
+        let toml = r#"
+            [open]
+            cmd = "touch {out}"
+            inputs = ["in.txt"]
+            outputs = ["open.out"]
+
+            [sealed]
+            cmd = "echo $HOME > {out}"
+            inputs = ["in.txt"]
+            outputs = ["sealed.out"]
+            hermetic = true
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = |path: &str, home: &str| {
+            let mut config = ExecConfig::new(&dir);
+            config.host_env.insert("PATH".into(), path.into());
+            config.host_env.insert("HOME".into(), home.into());
+            let report = SerialExecutor::new(config)
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap();
+            report
+                .results
+                .into_iter()
+                .filter(|r| r.status == TargetStatus::Built)
+                .map(|r| r.target_name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(build("/usr/bin:/bin", "/home/a").len(), 2);
+        assert!(build("/usr/bin:/bin", "/home/a").is_empty());
+
+        // HOME is passed through but not keyed, so another user shares the cache
+        assert!(build("/usr/bin:/bin", "/home/b").is_empty());
+        let sealed = std::fs::read_to_string(dir.join("sealed.out")).unwrap();
+        assert_eq!(sealed, "/home/a\n");
+
+        // Only the hermetic target sees PATH as an input
+        assert_eq!(build("/bin:/usr/bin", "/home/b"), ["sealed"]);
+        let sealed = std::fs::read_to_string(dir.join("sealed.out")).unwrap();
+        assert_eq!(sealed, "/home/b\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_argv_commands_pass_paths_verbatim() {
+        let dir = temp_dir("argv");
+        std::fs::write(dir.join("my notes.txt"), "notes\n").unwrap();
+        std::fs::write(dir.join("it's.txt"), "quoted\n").unwrap();
+
+        let toml = r#"
+            [copy]
+            cmd = ["cp", "{in}", "{out}"]
+            inputs = ["my notes.txt"]
+            outputs = ["copy of notes.txt"]
+
+            [concat]
+            cmd = ["sh", "-c", "cat \"$@\" > all.txt", "sh", "{inputs}"]
+            inputs = ["it's.txt", "my notes.txt"]
+            outputs = ["all.txt"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let report = SerialExecutor::new(ExecConfig::new(&dir))
+            .unwrap()
+            .execute_all(&spec)
+            .unwrap();
+        assert!(report.success());
+
+        let copy = std::fs::read_to_string(dir.join("copy of notes.txt")).unwrap();
+        assert_eq!(copy, "notes\n");
+        let all = std::fs::read_to_string(dir.join("all.txt")).unwrap();
+        assert_eq!(all, "quoted\nnotes\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_steps_stop_at_first_failure() {
+        let dir = temp_dir("steps");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+
+        let ok = BuildSpec::from_toml(
+            r#"
+            [ok]
+            cmd = { steps = ["echo one > ok.txt", "echo two >> ok.txt"] }
+            inputs = ["in.txt"]
+            outputs = ["ok.txt"]
+        "#,
+        )
+        .unwrap();
+        let broken = BuildSpec::from_toml(
+            r#"
+            [broken]
+            cmd = { steps = ["exit 3", "echo ran > broken.txt"] }
+            inputs = ["in.txt"]
+            outputs = ["broken.txt"]
+        "#,
+        )
+        .unwrap();
+
+        for parallel in [false, true] {
+            let run = |spec: &BuildSpec| {
+                let mut config = ExecConfig::new(&dir);
+                config.parallel = parallel;
+                config.force_rebuild = true;
+                let report = if parallel {
+                    ParallelExecutor::new(config).unwrap().execute_all(spec)
+                } else {
+                    SerialExecutor::new(config).unwrap().execute_all(spec)
+                };
+                report.unwrap().results[0].status.clone()
+            };
+
+            assert_eq!(run(&ok), TargetStatus::Built);
+            let content = std::fs::read_to_string(dir.join("ok.txt")).unwrap();
+            assert_eq!(content, "one\ntwo\n");
+
+            assert_eq!(run(&broken), TargetStatus::Failed(3));
+            assert!(!dir.join("broken.txt").exists());
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_changing_a_step_rebuilds() {
+        let dir = temp_dir("steps_key");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+
+        let build = |steps: &str| {
+            let toml = format!(
+                "[app]\ncmd = {{ steps = {steps} }}\ninputs = [\"in.txt\"]\noutputs = [\"out.txt\"]\n"
+            );
+            let spec = BuildSpec::from_toml(&toml).unwrap();
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(
+            build(r#"["echo a > out.txt", "echo b >> out.txt"]"#).built_count(),
+            1
+        );
+        assert_eq!(
+            build(r#"["echo a > out.txt", "echo b >> out.txt"]"#).built_count(),
+            0
+        );
+        assert_eq!(
+            build(r#"["echo a > out.txt", "echo c >> out.txt"]"#).built_count(),
+            1
+        );
+        // Same text, but as argv rather than a shell string
+        assert_eq!(
+            build(r#"["echo a > out.txt", ["echo", "c"]]"#).built_count(),
+            1
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cwd_and_shell() {
+        let dir = temp_dir("cwd_shell");
+        std::fs::create_dir_all(dir.join("scripts")).unwrap();
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(dir.join("data/in.txt"), "data\n").unwrap();
+
+        let build = |shell: &str| {
+            let toml = format!(
+                r#"
+                [gen]
+                cmd = "pwd > {{out}}.pwd && cat {{in}} > {{out}} && echo ${{BASH_VERSION:+bash}} >> {{out}}"
+                inputs = ["data/in.txt"]
+                outputs = ["gen/out.txt"]
+                cwd = "scripts"
+                {shell}
+            "#
+            );
+            let spec = BuildSpec::from_toml(&toml).unwrap();
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build("").built_count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("gen/out.txt")).unwrap(),
+            "data\n\n"
+        );
+        let pwd = std::fs::read_to_string(dir.join("gen/out.txt.pwd")).unwrap();
+        assert!(pwd.trim_end().ends_with("scripts"));
+
+        // The shell is part of the key
+        assert_eq!(build(r#"shell = "bash""#).built_count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("gen/out.txt")).unwrap(),
+            "data\nbash\n"
+        );
+        assert_eq!(build(r#"shell = "bash""#).built_count(), 0);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cwd_must_be_a_directory_inside_the_project() {
+        let dir = temp_dir("cwd_invalid");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+        std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("escape")).unwrap();
+
+        for (cwd, message) in [
+            ("missing", "not a directory"),
+            ("escape", "resolves outside the project"),
+        ] {
+            let toml = format!(
+                "[t]\ncmd = \"true\"\ninputs = [\"in.txt\"]\noutputs = [\"out\"]\ncwd = \"{cwd}\"\n"
+            );
+            let spec = BuildSpec::from_toml(&toml).unwrap();
+            let err = SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap_err();
+            assert!(err.to_string().contains(message), "{err}");
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_phony_and_always_targets_run_every_build() {
+        let dir = temp_dir("phony");
+        std::fs::write(dir.join("in.txt"), "in").unwrap();
+
+        let toml = r#"
+            [stamp]
+            cmd = "echo fixed > {out} && echo run >> runs.log"
+            outputs = ["stamp.txt"]
+            always = true
+
+            [use_stamp]
+            cmd = "cp {deps.stamp.outputs} {out}"
+            inputs = ["in.txt"]
+            outputs = ["copy.txt"]
+            deps = ["stamp"]
+
+            [check]
+            cmd = "echo check >> runs.log"
+            phony = true
+            deps = ["use_stamp"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        let first = build();
+        assert_eq!(first.built_count(), 3);
+
+        // Both run again; the unchanged stamp cuts `use_stamp` off
+        let second = build();
+        assert_eq!(second.built_count(), 2);
+        assert_eq!(second.cutoff_count(), 1);
+
+        let log = std::fs::read_to_string(dir.join("runs.log")).unwrap();
+        assert_eq!(log, "run\ncheck\nrun\ncheck\n");
+        assert!(dir.join(".bagel/cache/default/stamp.json").exists());
+        assert!(!dir.join(".bagel/cache/default/check.json").exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_group_targets_follow_their_deps() {
+        let dir = temp_dir("group");
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+
+        let toml = r#"
+            [a]
+            cmd = "cp {in} {out}"
+            inputs = ["a.txt"]
+            outputs = ["a.out"]
+
+            [b]
+            cmd = "cp {in} {out}"
+            inputs = ["b.txt"]
+            outputs = ["b.out"]
+
+            [all]
+            deps = ["a", "b"]
+
+            [bundle]
+            cmd = "cat a.out b.out > {out}"
+            inputs = ["a.txt"]
+            outputs = ["bundle.txt"]
+            deps = ["all"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+
+        let build = |parallel: bool| {
+            let mut config = ExecConfig::new(&dir);
+            config.parallel = parallel;
+            if parallel {
+                ParallelExecutor::new(config).unwrap().execute_all(&spec)
+            } else {
+                SerialExecutor::new(config).unwrap().execute_all(&spec)
+            }
+            .unwrap()
+        };
+
+        let first = build(false);
+        assert_eq!(first.built_count(), 3);
+        assert_eq!(first.skipped_count(), 1);
+
+        let second = build(true);
+        assert_eq!(second.built_count(), 0);
+
+        // `bundle` doesn't list b.txt, but sees the change through the group
+        std::fs::write(dir.join("b.txt"), "B").unwrap();
+        let third = build(true);
+        assert_eq!(third.built_count(), 2);
+        assert_eq!(third.cutoff_count(), 0);
+        let bundle = std::fs::read_to_string(dir.join("bundle.txt")).unwrap();
+        assert_eq!(bundle, "aB");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_tests_run_after_their_deps_and_cache_passes() {
+        let dir = temp_dir("test_runner");
+        std::fs::write(dir.join("app.txt"), "ok").unwrap();
+
+        let toml = r#"
+            [app]
+            cmd = "cp {in} {out}"
+            inputs = ["app.txt"]
+            outputs = ["app.out"]
+
+            [app_test]
+            cmd = "grep -q ok app.out && echo ran >> tests.log"
+            kind = "test"
+            deps = ["app"]
+
+            [broken_test]
+            cmd = "echo broken && exit 2"
+            kind = "test"
+            deps = ["app"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let tests = spec.tests_matching(&[]).unwrap();
+        assert_eq!(tests, ["app_test", "broken_test"]);
+
+        let run = || {
+            TestRunner::new(ExecConfig::new(&dir), 1)
+                .run(&spec, &tests)
+                .unwrap()
+        };
+
+        let first = run();
+        assert_eq!(first.build.built_count(), 1);
+        assert_eq!(first.passed_count(), 1);
+        assert_eq!(first.failed_count(), 1);
+        let broken = first
+            .results
+            .iter()
+            .find(|r| r.target_name == "broken_test");
+        let broken = broken.unwrap();
+        assert_eq!(broken.status, TargetStatus::Failed(2));
+        assert_eq!(broken.output.as_deref(), Some("broken\n"));
+        assert!(!first.success());
+
+        // Passes are cached, failures rerun
+        let second = run();
+        assert_eq!(second.cached_count(), 1);
+        assert_eq!(second.failed_count(), 1);
+
+        // A changed dep output invalidates the cached pass
+        std::fs::write(dir.join("app.txt"), "still ok").unwrap();
+        let third = run();
+        assert_eq!(third.passed_count(), 1);
+
+        let log = std::fs::read_to_string(dir.join("tests.log")).unwrap();
+        assert_eq!(log, "ran\nran\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_runs_per_test_detects_flaky_tests() {
+        let dir = temp_dir("flaky");
+
+        // Fails every other run
+        let toml = r#"
+            [flaky]
+            cmd = "echo x >> runs.log && test $(wc -l < runs.log) -ne 2"
+            kind = "test"
+            always = true
+
+            [steady]
+            cmd = "true"
+            kind = "test"
+            always = true
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let tests = spec.tests_matching(&[]).unwrap();
+
+        let report = TestRunner::new(ExecConfig::new(&dir), 3)
+            .run(&spec, &tests)
+            .unwrap();
+        let flaky = &report.results[0];
+        assert_eq!(flaky.status, TargetStatus::Flaky { passed: 2, runs: 3 });
+        assert_eq!(report.results[1].status, TargetStatus::Built);
+        assert_eq!(report.flaky_count(), 1);
+        assert!(!report.success());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_junit_and_json_reports() {
+        let dir = temp_dir("test_reports");
+
+        let toml = r#"
+            [good]
+            cmd = "echo all good"
+            kind = "test"
+
+            [bad]
+            cmd = "echo '<expected> & got' && echo oops >&2 && exit 1"
+            kind = "test"
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let tests = spec.tests_matching(&[]).unwrap();
+        let report = TestRunner::new(ExecConfig::new(&dir), 1)
+            .run(&spec, &tests)
+            .unwrap();
+
+        let xml = report.to_junit_xml();
+        assert!(xml.contains(r#"<testsuite name="bagel" tests="2" failures="1" errors="0""#));
+        assert!(xml.contains(r#"<testcase name="good" classname="bagel""#));
+        assert!(xml.contains("<system-out>all good\n</system-out>"));
+        assert!(xml.contains(r#"<failure message="exit code 1" type="failed"/>"#));
+        assert!(xml.contains("<system-out>&lt;expected&gt; &amp; got\n</system-out>"));
+        assert!(xml.contains("<system-err>oops\n</system-err>"));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["success"], false);
+        assert_eq!(json["passed"], 1);
+        assert_eq!(json["failed"], 1);
+        assert_eq!(json["tests"][0]["name"], "bad");
+        assert_eq!(json["tests"][0]["status"], "failed");
+        assert_eq!(json["tests"][0]["exit_code"], 1);
+        assert_eq!(json["tests"][0]["stderr"], "oops\n");
+        assert_eq!(json["tests"][1]["passed_runs"], 1);
+        assert_eq!(json["tests"][1]["output"], "all good\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reports_list_tests_blocked_by_a_failed_build() {
+        let dir = temp_dir("test_reports_blocked");
+        std::fs::write(dir.join("in.txt"), "").unwrap();
+
+        let toml = r#"
+            [app]
+            cmd = "exit 1"
+            inputs = ["in.txt"]
+            outputs = ["app"]
+
+            [app_test]
+            cmd = "./app"
+            kind = "test"
+            deps = ["app"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let report = TestRunner::new(ExecConfig::new(&dir), 1)
+            .run(&spec, &["app_test".to_string()])
+            .unwrap();
+
+        assert!(!report.build.success());
+        assert_eq!(report.results[0].status, TargetStatus::NotRun);
+        let xml = report.to_junit_xml();
+        assert!(xml.contains(r#"<testsuite name="bagel" tests="1" failures="0" errors="1""#));
+        assert!(xml.contains(r#"<error message="dependencies failed to build" type="not_run"/>"#));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_transitive_libs_relink_through_cutoff() {
+        let dir = temp_dir("transitive_libs");
+        std::fs::write(dir.join("base.txt"), "base1\n").unwrap();
+        std::fs::write(dir.join("mid.txt"), "mid\n").unwrap();
+        std::fs::write(dir.join("main.txt"), "main\n").unwrap();
+
+        // `mid` doesn't copy `base`, so a change to base leaves mid's output alone
+        let toml = r#"
+            [base]
+            cmd = "cp {in} {out}"
+            inputs = ["base.txt"]
+            outputs = ["base.lib"]
+            kind = "lib"
+
+            [mid]
+            cmd = "cp {in} {out}"
+            inputs = ["mid.txt"]
+            outputs = ["mid.lib"]
+            deps = ["base"]
+            kind = "lib"
+
+            [app]
+            cmd = "cat {in} {deps.transitive_libs} > {out}"
+            inputs = ["main.txt"]
+            outputs = ["app.txt"]
+            deps = ["mid"]
+        "#;
+        let spec = BuildSpec::from_toml(toml).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+
+        assert_eq!(build().built_count(), 3);
+        let app = std::fs::read_to_string(dir.join("app.txt")).unwrap();
+        assert_eq!(app, "main\nmid\nbase1\n");
+
+        std::fs::write(dir.join("base.txt"), "base2\n").unwrap();
+        let report = build();
+        assert_eq!(report.built_count(), 3);
+        let app = std::fs::read_to_string(dir.join("app.txt")).unwrap();
+        assert_eq!(app, "main\nmid\nbase2\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_cc_rules_build_and_track_headers() {
+        let has_cc = std::process::Command::new("cc")
+            .arg("--version")
+            .output()
+            .is_ok_and(|out| out.status.success());
+        if !has_cc {
+            return;
+        }
+
+        let dir = temp_dir("cc_rules");
+        std::fs::write(dir.join("greet.h"), "#define GREETING \"hello\"\n").unwrap();
+        std::fs::write(
+            dir.join("greet.c"),
+            "#include \"greet.h\"\nconst char *greeting(void) { return GREETING; }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("main.c"),
+            "#include <stdio.h>\nconst char *greeting(void);\nint main(void) { puts(greeting()); return 0; }\n",
+        )
+        .unwrap();
+
+        let toml = r#"
+            [greet]
+            rule = "cc_library"
+            srcs = ["greet.c"]
+
+            [app]
+            rule = "cc_binary"
+            srcs = ["main.c"]
+            deps = ["greet"]
+        "#;
+        let spec = BuildSpec::from_toml_in(toml, &dir).unwrap();
+        let build = || {
+            SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap()
+        };
+        let run = || {
+            let out = std::process::Command::new(dir.join("bagel-out/default/bin/app"))
+                .output()
+                .unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+
+        let report = build();
+        assert!(report.success());
+        assert_eq!(report.built_count(), 4);
+        assert_eq!(run(), "hello\n");
+
+        // The header isn't declared anywhere; the depfile picks it up
+        std::fs::write(dir.join("greet.h"), "#define GREETING \"bye\"\n").unwrap();
+        let report = build();
+        let built: Vec<&str> = report
+            .results
+            .iter()
+            .filter(|r| r.status == TargetStatus::Built)
+            .map(|r| r.target_name.as_str())
+            .collect();
+        assert_eq!(built, ["greet:greet.c", "greet", "app"]);
+        assert_eq!(run(), "bye\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rust_rules_track_modules_through_dep_info() {
+        let has_tool = |tool: &str| {
+            std::process::Command::new(tool)
+                .arg("--version")
+                .output()
+                .is_ok_and(|out| out.status.success())
+        };
+        if !has_tool("rustc") || !has_tool("cargo") {
+            return;
+        }
+
+        let dir = temp_dir("rust_rules");
+        std::fs::create_dir_all(dir.join("words")).unwrap();
+        std::fs::create_dir_all(dir.join("srv/src/bin")).unwrap();
+        std::fs::write(
+            dir.join("words/lib.rs"),
+            "mod inner;\npub use inner::WORD;\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("words/inner.rs"),
+            "pub const WORD: &str = \"hi\";\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("main.rs"),
+            "fn main() { println!(\"{}\", words::WORD); }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("srv/Cargo.toml"),
+            "[package]\nname = \"srv\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("srv/src/main.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(dir.join("srv/src/bin/admin.rs"), "fn main() {}\n").unwrap();
+
+        let toml = r#"
+            [words]
+            rule = "rust_library"
+            crate_root = "words/lib.rs"
+
+            [app]
+            rule = "rust_binary"
+            crate_root = "main.rs"
+            deps = ["words"]
+
+            [srv]
+            rule = "cargo_crate"
+            manifest = "srv/Cargo.toml"
+            bins = ["srv", "admin"]
+            cargo_flags = ["--offline", "--quiet"]
+        "#;
+        let spec = BuildSpec::from_toml_in(toml, &dir).unwrap();
+        let build = || {
+            let report = SerialExecutor::new(ExecConfig::new(&dir))
+                .unwrap()
+                .execute_all(&spec)
+                .unwrap();
+            let mut built: Vec<String> = report
+                .results
+                .into_iter()
+                .filter(|r| r.status == TargetStatus::Built)
+                .map(|r| r.target_name)
+                .collect();
+            built.sort();
+            built
+        };
+        let run = || {
+            let out = std::process::Command::new(dir.join("bagel-out/default/bin/app"))
+                .output()
+                .unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+
+        assert_eq!(build(), ["app", "srv", "words"]);
+        assert_eq!(run(), "hi\n");
+        assert!(dir.join("bagel-out/default/cargo/srv/debug/srv").is_file());
+        assert!(
+            dir.join("bagel-out/default/cargo/srv/debug/admin")
+                .is_file()
+        );
+
+        // Neither module file is declared; rustc's and cargo's dep-info list them
+        std::fs::write(
+            dir.join("words/inner.rs"),
+            "pub const WORD: &str = \"bye\";\n",
+        )
+        .unwrap();
+        assert_eq!(build(), ["app", "words"]);
+        assert_eq!(run(), "bye\n");
+
+        std::fs::write(dir.join("srv/src/main.rs"), "fn main() { println!(); }\n").unwrap();
+        assert_eq!(build(), ["srv"]);
+        std::fs::write(
+            dir.join("srv/src/bin/admin.rs"),
+            "fn main() { println!(); }\n",
+        )
+        .unwrap();
+        assert_eq!(build(), ["srv"]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_timeout_kills_target() {
+        let dir = temp_dir("timeout");
+
+        let toml = r#"
+            [slow]
+            cmd = "sleep 5"
+            inputs = ["input.txt"]
+            outputs = ["output.txt"]
+            timeout = 1
+        "#;
+
+        std::fs::write(dir.join("input.txt"), "test").unwrap();
+
+        let spec = BuildSpec::from_toml(toml).unwrap();
+
+        let mut executor = SerialExecutor::new(ExecConfig::new(&dir)).unwrap();
+        let report = executor.execute_all(&spec).unwrap();
+        assert_eq!(report.results[0].status, TargetStatus::TimedOut);
+        assert!(report.results[0].duration < Duration::from_secs(5));
+        assert!(!report.success());
+
+        let mut config = ExecConfig::new(&dir);
+        config.parallel = true;
+        let mut executor = ParallelExecutor::new(config).unwrap();
+        let report = executor.execute_all(&spec).unwrap();
+        assert_eq!(report.results[0].status, TargetStatus::TimedOut);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn test_parallel_simple_command() {
         let dir = temp_dir("parallel_simple");
@@ -1,128 +1,104 @@
+use crate::core::{BuildSpec, TargetSpec};
+use crate::exec::action::{Action, apply_dyndep, discover_inputs, note_cutoff};
+use crate::exec::process;
+use crate::exec::rebuild::{check_freshness, record_outputs, settle_group};
 use crate::exec::types::{BuildReport, ExecConfig, ExecError, TargetResult, TargetStatus};
-use crate::core::BuildSpec;
-use crate::utils::{BuildCache, compute_target_hash, expand_globs};
+use crate::utils::{BuildCache, DigestMemo};
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::process::{Command, Output, Stdio};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 /**
  * Parallel executor; builds independent targets concurrently using rayon
  */
 pub struct ParallelExecutor {
     config: ExecConfig,
+    memo: DigestMemo, // shared by all workers, so each file is read once per build
 }
 
 impl ParallelExecutor {
     pub fn new(config: ExecConfig) -> Result<Self, ExecError> {
-        Ok(Self { config })
+        Ok(Self {
+            config,
+            memo: DigestMemo::new(),
+        })
     }
 
     /**
      * Execute all targets in the build spec, running independent targets in parallel.
+     * Each wave holds every target whose deps have all finished; dyndep files are
+     * applied between waves, so dependents are only scheduled against the updated graph.
      */
     pub fn execute_all(&mut self, spec: &BuildSpec) -> Result<BuildReport, ExecError> {
         let start = Instant::now();
+        let mut spec = spec.clone();
 
-        // Reverse dependency map: target -> list of targets that depend on it
-        let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
-        let mut remaining_deps: HashMap<&str, AtomicUsize> = HashMap::new();
-
-        for (name, target) in &spec.targets {
-            remaining_deps.insert(name.as_str(), AtomicUsize::new(target.deps.len()));
-            for dep in &target.deps {
-                dependents
-                    .entry(dep.as_str())
-                    .or_default()
-                    .push(name.as_str());
-            }
-        }
+        let mut results: Vec<TargetResult> = Vec::new();
+        let mut finished: HashSet<String> = HashSet::new();
+        let mut changed: HashSet<String> = HashSet::new();
+        let has_error = AtomicBool::new(false);
 
         // Populate with no-dependency targets, which can be executed immediately
-        let ready: Vec<&str> = spec
-            .targets
-            .iter()
-            .filter(|(_, t)| t.deps.is_empty())
-            .map(|(name, _)| name.as_str())
-            .collect();
-
-        // Shared state
-        let results: Arc<Mutex<Vec<TargetResult>>> = Arc::new(Mutex::new(Vec::new()));
-        let has_error = Arc::new(AtomicBool::new(false));
-        let completed: Arc<Mutex<Vec<&str>>> = Arc::new(Mutex::new(Vec::new()));
-
-        let mut current_wave = ready;
+        let mut current_wave = ready_targets(&spec, &finished);
 
         while !current_wave.is_empty() {
             if has_error.load(Ordering::Relaxed) && !self.config.continue_on_error {
                 break;
             }
 
-            let wave_results: Vec<TargetResult> = current_wave
+            let mut wave_results: Vec<TargetResult> = current_wave
                 .par_iter()
-                .filter_map(|&target_name| {
+                .filter_map(|target_name| {
                     if has_error.load(Ordering::Relaxed) && !self.config.continue_on_error {
                         return None;
                     }
 
                     let target = spec.get_target(target_name)?;
-                    let result = self.execute_target(target_name, target);
+                    let result = self.execute_target(target_name, target, &spec);
 
                     match result {
                         Ok(r) => {
-                            if matches!(r.status, TargetStatus::Failed(_) | TargetStatus::Signaled)
-                            {
+                            if r.status.is_failure() {
                                 has_error.store(true, Ordering::Relaxed);
                             }
                             Some(r)
                         }
-                        Err(_) => {
+                        Err(e) => {
+                            // Reported with the target's output, as the wave carries on
                             has_error.store(true, Ordering::Relaxed);
                             Some(TargetResult {
                                 target_name: target_name.to_string(),
                                 status: TargetStatus::Failed(-1),
                                 duration: std::time::Duration::ZERO,
                                 output: None,
+                                stderr: Some(e.to_string()),
+                                cutoff: false,
                             })
                         }
                     }
                 })
                 .collect();
 
-            {
-                let mut comp = completed.lock().unwrap();
-                let mut res = results.lock().unwrap();
-                for result in &wave_results {
-                    comp.push(Box::leak(result.target_name.clone().into_boxed_str()));
-                    res.push(result.clone());
+            for result in &mut wave_results {
+                finished.insert(result.target_name.clone());
+                if let Some(target) = spec.get_target(&result.target_name) {
+                    note_cutoff(result, target, &mut changed);
                 }
             }
 
-            let mut next_wave = Vec::new();
-            for completed_target in &wave_results {
-                if let Some(deps) = dependents.get(completed_target.target_name.as_str()) {
-                    for &dependent in deps {
-                        if let Some(counter) = remaining_deps.get(dependent) {
-                            let prev = counter.fetch_sub(1, Ordering::SeqCst);
-                            if prev == 1 {
-                                next_wave.push(dependent);
-                            }
-                        }
-                    }
+            for result in &wave_results {
+                if !result.status.is_failure() {
+                    apply_dyndep(&mut spec, &result.target_name, &self.config.project_root)?;
                 }
             }
 
-            current_wave = next_wave;
+            results.extend(wave_results);
+            current_wave = ready_targets(&spec, &finished);
         }
 
-        let final_results = Arc::try_unwrap(results)
-            .map(|mutex| mutex.into_inner().unwrap_or_default())
-            .unwrap_or_else(|arc| arc.lock().unwrap().clone());
-
         Ok(BuildReport {
-            results: final_results,
+            results,
             total_duration: start.elapsed(),
         })
     }
@@ -130,88 +106,109 @@ impl ParallelExecutor {
     fn execute_target(
         &self,
         name: &str,
-        target: &crate::core::TargetSpec,
+        target: &TargetSpec,
+        spec: &BuildSpec,
     ) -> Result<TargetResult, ExecError> {
         let start = Instant::now();
 
         // Designate each parallel worker its own cache handle
-        let mut cache = BuildCache::new(&self.config.project_root);
-
-        let input_files = expand_globs(&target.inputs, &self.config.project_root)?;
-        let curr_hash = compute_target_hash(&input_files, &target.cmd, &target.env)?;
-
-        let needs_rebuild =
-            self.config.force_rebuild || cache.needs_rebuild(name, &curr_hash).unwrap_or(true);
+        let mut cache = BuildCache::for_config(
+            &self.config.project_root,
+            self.config.build_config.as_deref(),
+        );
 
-        if !needs_rebuild {
+        if target.is_group() {
+            settle_group(name, target, &mut cache)?;
             return Ok(TargetResult {
                 target_name: name.to_string(),
                 status: TargetStatus::Skipped,
                 duration: start.elapsed(),
                 output: None,
+                stderr: None,
+                cutoff: false,
             });
         }
 
-        let output = self.run_command_captured(&target.cmd, &target.env)?;
-
-        let result_status = if output.status.success() {
-            cache.record_build(name, curr_hash);
+        let action = Action::resolve(name, target, spec, &self.config)?;
+        let freshness = check_freshness(
+            name,
+            target,
+            &action,
+            &mut cache,
+            &self.memo,
+            &self.config.project_root,
+            self.config.force_rebuild,
+        )?;
+
+        if !freshness.stale {
+            cache.record_input_stats(name, freshness.input_stats);
             cache.flush_target(name)?;
+            return Ok(TargetResult {
+                target_name: name.to_string(),
+                status: TargetStatus::Skipped,
+                duration: start.elapsed(),
+                output: None,
+                stderr: None,
+                cutoff: false,
+            });
+        }
+
+        action.create_output_dirs(target, &self.config.project_root)?;
+        let timeout = target.timeout.map(Duration::from_secs);
+        let (completion, captured) = process::run_action_captured(&action, timeout)?;
+
+        let result_status = if completion.success() {
+            // Phony targets leave nothing in the cache
+            if !target.phony {
+                cache.record_build_with(name, freshness.hash, target.hash_algorithm);
+                cache.record_input_stats(name, freshness.input_stats);
+                record_outputs(
+                    name,
+                    target,
+                    &mut cache,
+                    &self.memo,
+                    &self.config.project_root,
+                )?;
+                discover_inputs(
+                    name,
+                    target,
+                    &mut cache,
+                    &self.config.project_root,
+                    &self.memo,
+                )?;
+                cache.flush_target(name)?;
+            }
             TargetStatus::Built
-        } else if let Some(code) = output.status.code() {
-            TargetStatus::Failed(code)
         } else {
-            TargetStatus::Signaled
+            completion.failure_status()
         };
 
         let duration = start.elapsed();
 
-        // Combine stdout and stderr
-        let combined_output = format!(
-            "{}{}",
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
-
         Ok(TargetResult {
             target_name: name.to_string(),
             status: result_status,
             duration,
-            output: if combined_output.is_empty() {
-                None
-            } else {
-                Some(combined_output)
-            },
+            output: Some(captured.stdout).filter(|out| !out.is_empty()),
+            stderr: Some(captured.stderr).filter(|err| !err.is_empty()),
+            cutoff: false,
         })
     }
+}
 
-    fn run_command_captured(
-        &self,
-        cmd: &str,
-        env: &HashMap<String, String>,
-    ) -> Result<Output, ExecError> {
-        let mut command = if cfg!(target_os = "windows") {
-            let mut c = Command::new("cmd");
-            c.args(["/C", cmd]);
-            c
-        } else {
-            let mut c = Command::new("sh");
-            c.args(["-c", cmd]);
-            c
-        };
-
-        command.current_dir(&self.config.project_root);
-
-        for (key, value) in env {
-            command.env(key, value);
-        }
-
-        // We choose to capture output instead of inheriting to prevent interleaving
-        command.stdout(Stdio::piped());
-        command.stderr(Stdio::piped());
+/**
+ * Targets that haven't run yet and whose deps have all finished
+ */
+fn ready_targets(spec: &BuildSpec, finished: &HashSet<String>) -> Vec<String> {
+    let mut ready: Vec<String> = spec
+        .targets
+        .iter()
+        .filter(|(name, target)| {
+            !finished.contains(*name) && target.deps.iter().all(|dep| finished.contains(dep))
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
 
-        command
-            .output()
-            .map_err(|e| ExecError::CommandError(cmd.to_string(), e))
-    }
+    ready.sort();
+    ready
 }
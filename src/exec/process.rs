@@ -0,0 +1,297 @@
+//! Process spawning shared by the serial and parallel executors
+
+use crate::core::CommandStep;
+use crate::exec::action::Action;
+use crate::exec::types::{ExecError, TargetStatus};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/** How often a running child is polled while a timeout is armed */
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/**
+ * Outcome of a finished (or killed) command
+ */
+#[derive(Debug)]
+pub(crate) enum Completion {
+    Exited(ExitStatus),
+    TimedOut,
+}
+
+impl Completion {
+    pub(crate) fn success(&self) -> bool {
+        matches!(self, Completion::Exited(status) if status.success())
+    }
+
+    /**
+     * Map a failed completion onto the status reported for the target
+     */
+    pub(crate) fn failure_status(&self) -> TargetStatus {
+        match self {
+            Completion::Exited(status) => match status.code() {
+                Some(code) => TargetStatus::Failed(code),
+                None => TargetStatus::Signaled,
+            },
+            Completion::TimedOut => TargetStatus::TimedOut,
+        }
+    }
+}
+
+/**
+ * What a command wrote to each of its streams
+ */
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Captured {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Captured {
+    fn push(&mut self, other: Captured) {
+        self.stdout.push_str(&other.stdout);
+        self.stderr.push_str(&other.stderr);
+    }
+}
+
+/** Host variables kept in hermetic mode, when set */
+pub(crate) const HERMETIC_HOST_VARS: &[&str] = &["PATH", "HOME", "TMPDIR", "SOURCE_DATE_EPOCH"];
+
+/**
+ * The kept host variables that enter the cache key. The others differ from
+ * user to user and machine to machine, and keying them would stop the cache
+ * being shared; targets that depend on one can name it in `pass_env`.
+ */
+pub(crate) const HERMETIC_KEYED_VARS: &[&str] = &["PATH"];
+
+/** Variables pinned in hermetic mode so output doesn't depend on the user's locale or zone */
+pub(crate) const HERMETIC_FIXED_VARS: &[(&str, &str)] = &[("LANG", "C"), ("TZ", "UTC")];
+
+/**
+ * Build a shell invocation of `cmd` with `env` as its whole environment.
+ * `shell` is the program and flags to use, or empty for the platform shell.
+ */
+pub(crate) fn shell_command(
+    cmd: &str,
+    shell: &[String],
+    env: &BTreeMap<OsString, OsString>,
+    cwd: &Path,
+) -> Command {
+    let mut command = if let [program, flags @ ..] = shell {
+        let mut c = Command::new(program);
+        c.args(flags).arg(cmd);
+        c
+    } else if cfg!(target_os = "windows") {
+        let mut c = Command::new("cmd");
+        c.args(["/C", cmd]);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.args(["-c", cmd]);
+        c
+    };
+
+    configure(&mut command, env, cwd);
+    command
+}
+
+/**
+ * Build the invocation of one step: shell steps go through [`shell_command`],
+ * argv steps run the program directly with no shell in between
+ */
+pub(crate) fn step_command(
+    step: &CommandStep,
+    shell: &[String],
+    env: &BTreeMap<OsString, OsString>,
+    cwd: &Path,
+) -> Command {
+    let argv = match step {
+        CommandStep::Shell(cmd) => return shell_command(cmd, shell, env, cwd),
+        CommandStep::Argv(argv) => argv,
+    };
+
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    configure(&mut command, env, cwd);
+    command
+}
+
+fn configure(command: &mut Command, env: &BTreeMap<OsString, OsString>, cwd: &Path) {
+    command.current_dir(cwd).env_clear().envs(env);
+}
+
+/**
+ * Run `steps` in order with `run`, stopping at the first one that fails. The
+ * timeout covers all steps together; each is given whatever is left of it.
+ */
+pub(crate) fn run_steps<F>(
+    steps: &[CommandStep],
+    timeout: Option<Duration>,
+    mut run: F,
+) -> io::Result<Completion>
+where
+    F: FnMut(usize, &CommandStep, Option<Duration>) -> io::Result<Completion>,
+{
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut last = None;
+
+    for (i, step) in steps.iter().enumerate() {
+        let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        let completion = run(i, step, remaining)?;
+        if !completion.success() {
+            return Ok(completion);
+        }
+        last = Some(completion);
+    }
+
+    last.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command to run"))
+}
+
+/**
+ * Run all of an action's steps with their output captured. With several
+ * steps, each one's stdout is preceded by a `$ step` line.
+ */
+pub(crate) fn run_action_captured(
+    action: &Action,
+    timeout: Option<Duration>,
+) -> Result<(Completion, Captured), ExecError> {
+    let multi_step = action.steps.len() > 1;
+    let mut captured = Captured::default();
+
+    let completion = run_steps(&action.steps, timeout, |_, step, remaining| {
+        let command = step_command(step, &action.shell, &action.env, &action.cwd);
+        let (completion, output) = run_captured(command, remaining)?;
+        if multi_step {
+            captured.stdout.push_str(&format!("$ {step}\n"));
+        }
+        captured.push(output);
+        Ok(completion)
+    })
+    .map_err(|e| ExecError::CommandError(action.cmd.clone(), e))?;
+
+    Ok((completion, captured))
+}
+
+/**
+ * Run a command with stdout/stderr attached to ours; with `stdout_to_stderr`
+ * both of its streams go to our stderr
+ */
+pub(crate) fn run_inherited(
+    mut command: Command,
+    timeout: Option<Duration>,
+    stdout_to_stderr: bool,
+) -> io::Result<Completion> {
+    if stdout_to_stderr {
+        command.stdout(io::stderr());
+    } else {
+        command.stdout(Stdio::inherit());
+    }
+    command.stderr(Stdio::inherit());
+
+    let mut child = spawn(&mut command, timeout)?;
+    wait(&mut child, timeout)
+}
+
+/**
+ * Run a command and return what it wrote to stdout and stderr
+ */
+pub(crate) fn run_captured(
+    mut command: Command,
+    timeout: Option<Duration>,
+) -> io::Result<(Completion, Captured)> {
+    // We choose to capture output instead of inheriting to prevent interleaving
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+
+    let mut child = spawn(&mut command, timeout)?;
+
+    // Drain both pipes on their own threads so a chatty child can't block on a full pipe
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+
+    let completion = wait(&mut child, timeout)?;
+
+    let read = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+        let bytes = reader.and_then(|r| r.join().ok()).unwrap_or_default();
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+
+    Ok((
+        completion,
+        Captured {
+            stdout: read(stdout),
+            stderr: read(stderr),
+        },
+    ))
+}
+
+/**
+ * Spawn the child; with a timeout armed it gets its own process group so
+ * that everything the shell started can be killed together
+ */
+fn spawn(command: &mut Command, timeout: Option<Duration>) -> io::Result<Child> {
+    #[cfg(unix)]
+    if timeout.is_some() {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    #[cfg(not(unix))]
+    let _ = timeout;
+
+    command.spawn()
+}
+
+fn drain<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/**
+ * Wait for the child, killing it once the timeout (if any) elapses
+ */
+fn wait(child: &mut Child, timeout: Option<Duration>) -> io::Result<Completion> {
+    let Some(timeout) = timeout else {
+        return child.wait().map(Completion::Exited);
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Completion::Exited(status));
+        }
+
+        if Instant::now() >= deadline {
+            kill_tree(child)?;
+            child.wait()?;
+            return Ok(Completion::TimedOut);
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/**
+ * Kill the child's whole process group, which `spawn` made it the leader of
+ */
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) -> io::Result<()> {
+    let pgid = libc::pid_t::try_from(child.id()).map_err(io::Error::other)?;
+    // SAFETY: killpg only sends a signal; the group was created for this child
+    if unsafe { libc::killpg(pgid, libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        child.kill()
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_tree(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
@@ -0,0 +1,362 @@
+//! Up-to-date checks for each [`RebuildStrategy`]
+
+use crate::core::{RebuildStrategy, TargetSpec};
+use crate::exec::action::{Action, dep_output_hashes, relative_to};
+use crate::exec::process::HERMETIC_KEYED_VARS;
+use crate::exec::types::ExecError;
+use crate::utils::{
+    BuildCache, DigestMemo, FileStat, InputStat, combine_target_hash, hash_outputs, hash_string,
+    newest_mtime, resolve_tool,
+};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/**
+ * Outcome of checking a target against the cache
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct Freshness {
+    /** Cache key recorded if the target gets built */
+    pub hash: String,
+    pub stale: bool,
+    /**
+     * Stats that vouch for the input hashes under the `stat` strategy, or under
+     * `mtime` stand in for the outputs of a target that has none
+     */
+    pub input_stats: BTreeMap<String, InputStat>,
+}
+
+pub(crate) fn check_freshness(
+    name: &str,
+    target: &TargetSpec,
+    action: &Action,
+    cache: &mut BuildCache,
+    memo: &DigestMemo,
+    project_root: &Path,
+    force: bool,
+) -> Result<Freshness, ExecError> {
+    let mut dep_outputs = dep_output_hashes(target, cache);
+    for lib in &action.linked_libs {
+        if !dep_outputs.contains_key(lib) {
+            dep_outputs.insert(lib.clone(), cache.output_hash(lib).unwrap_or_default());
+        }
+    }
+    let algorithm = target.hash_algorithm;
+    let mut input_stats = BTreeMap::new();
+
+    let input_digests = match target.rebuild {
+        RebuildStrategy::Hash => memo.hash_all(&action.input_files, algorithm)?,
+        // Only the set of inputs is part of the key; their contents are judged by mtime below
+        RebuildStrategy::Mtime => {
+            if target.outputs.is_empty() {
+                for path in &action.input_files {
+                    if let Ok(stat) = FileStat::of_tree(path) {
+                        let key = relative_to(path, project_root);
+                        let hash = String::new();
+                        input_stats.insert(key, InputStat { stat, hash });
+                    }
+                }
+            }
+            action
+                .input_files
+                .iter()
+                .map(|path| (path.clone(), String::new()))
+                .collect()
+        }
+        RebuildStrategy::Stat => {
+            let mut recorded = Vec::with_capacity(action.input_files.len());
+            for path in &action.input_files {
+                let key = relative_to(path, project_root);
+                let stat = FileStat::of(path)?;
+                // A directory's own stat doesn't change when nested files do
+                let hash = cache
+                    .input_stat(name, &key, algorithm)
+                    .filter(|recorded| recorded.stat == stat && !path.is_dir())
+                    .map(|recorded| recorded.hash.clone());
+                recorded.push((key, stat, hash));
+            }
+
+            // Only files whose stat changed are read, in parallel
+            let changed: Vec<PathBuf> = action
+                .input_files
+                .iter()
+                .zip(&recorded)
+                .filter(|(_, (_, _, hash))| hash.is_none())
+                .map(|(path, _)| path.clone())
+                .collect();
+            let mut rehashed = memo.hash_all(&changed, algorithm)?.into_iter();
+
+            let mut digests = Vec::with_capacity(action.input_files.len());
+            for (path, (key, stat, hash)) in action.input_files.iter().zip(recorded) {
+                let hash = match hash {
+                    Some(hash) => hash,
+                    None => rehashed.next().map(|(_, hash)| hash).unwrap_or_default(),
+                };
+                if !stat.is_racy() && !path.is_dir() {
+                    input_stats.insert(
+                        key,
+                        InputStat {
+                            stat,
+                            hash: hash.clone(),
+                        },
+                    );
+                }
+                digests.push((path.clone(), hash));
+            }
+            digests
+        }
+    };
+
+    let host_inputs = host_inputs(target, action, memo, project_root)?;
+    let hash = combine_target_hash(
+        project_root,
+        &input_digests,
+        &action.command_key(),
+        &target.env,
+        &dep_outputs,
+        &host_inputs,
+    );
+
+    let stale = force
+        || target.always_runs()
+        || cache
+            .needs_rebuild_with(name, &hash, algorithm)
+            .unwrap_or(true)
+        || cache.discovered_inputs_changed(name, memo, target.rebuild)
+        || outputs_rewritten(name, target, cache, memo, project_root)?
+        || (target.rebuild == RebuildStrategy::Mtime
+            && mtime_outdated(name, target, action, &input_stats, cache, project_root));
+
+    Ok(Freshness {
+        hash,
+        stale,
+        input_stats,
+    })
+}
+
+/**
+ * Record what a successful build left behind: the hash of its outputs, for
+ * dependents' keys, and their stats, to notice when something else rewrites them
+ */
+pub(crate) fn record_outputs(
+    name: &str,
+    target: &TargetSpec,
+    cache: &mut BuildCache,
+    memo: &DigestMemo,
+    project_root: &Path,
+) -> Result<(), ExecError> {
+    let stats = output_stats(target, project_root);
+    cache.record_output_hash(name, outputs_digest(target, &stats, memo, project_root)?);
+    cache.record_output_stats(name, stats);
+    Ok(())
+}
+
+/**
+ * Stats of the target's outputs that exist; a directory counts as its newest file
+ */
+fn output_stats(target: &TargetSpec, project_root: &Path) -> BTreeMap<String, FileStat> {
+    target
+        .outputs
+        .iter()
+        .filter_map(|output| {
+            let stat = FileStat::of_tree(project_root.join(output)).ok()?;
+            Some((output.clone(), stat))
+        })
+        .collect()
+}
+
+/**
+ * Digest of the target's outputs that dependents key off. Under `mtime` the
+ * outputs aren't read: their stats stand in for their contents.
+ */
+fn outputs_digest(
+    target: &TargetSpec,
+    stats: &BTreeMap<String, FileStat>,
+    memo: &DigestMemo,
+    project_root: &Path,
+) -> Result<String, ExecError> {
+    Ok(match target.rebuild {
+        RebuildStrategy::Mtime => hash_string(&serde_json::to_string(stats).unwrap_or_default()),
+        RebuildStrategy::Hash | RebuildStrategy::Stat => {
+            hash_outputs(&target.outputs, project_root, target.hash_algorithm, memo)?
+        }
+    })
+}
+
+/**
+ * Whether an output changed since this cache recorded it, e.g. because a
+ * build under another configuration wrote the same path. Outputs are only
+ * re-read when their stats moved (and never under `mtime`), and if their
+ * contents still match (as after copying the project) the new stats are recorded. Entries recorded
+ * before output stats were kept are trusted.
+ */
+fn outputs_rewritten(
+    name: &str,
+    target: &TargetSpec,
+    cache: &mut BuildCache,
+    memo: &DigestMemo,
+    project_root: &Path,
+) -> Result<bool, ExecError> {
+    let Some(recorded) = cache.output_stats(name) else {
+        return Ok(false);
+    };
+
+    let current = output_stats(target, project_root);
+    if *recorded == current {
+        return Ok(false);
+    }
+
+    let output_hash = outputs_digest(target, &current, memo, project_root)?;
+    if cache.output_hash(name).as_ref() != Some(&output_hash) {
+        return Ok(true);
+    }
+    cache.record_output_stats(name, current);
+    Ok(false)
+}
+
+/**
+ * Bring a group's cache entry in line with its deps. A group's key and output
+ * hash are both the digest of its deps' output hashes, so its dependents see
+ * a change whenever one of its members produced something new.
+ */
+pub(crate) fn settle_group(
+    name: &str,
+    target: &TargetSpec,
+    cache: &mut BuildCache,
+) -> Result<(), ExecError> {
+    let dep_outputs = dep_output_hashes(target, cache);
+    let hash = hash_string(&serde_json::to_string(&dep_outputs).unwrap_or_default());
+
+    if cache.output_hash(name).as_ref() != Some(&hash) {
+        cache.record_build(name, hash.clone());
+        cache.record_output_hash(name, hash);
+        cache.flush_target(name)?;
+    }
+    Ok(())
+}
+
+/**
+ * Values of `pass_env` variables and digests of `tools`, as seen by the command.
+ * Variables the target sets itself are already keyed through `env`. Running
+ * hermetically is keyed too, as it changes what the command sees, and so is
+ * the `PATH` hermetic mode keeps.
+ */
+fn host_inputs(
+    target: &TargetSpec,
+    action: &Action,
+    memo: &DigestMemo,
+    project_root: &Path,
+) -> Result<BTreeMap<String, String>, ExecError> {
+    let mut host = BTreeMap::new();
+
+    if action.hermetic {
+        host.insert("mode".to_string(), "hermetic".to_string());
+    }
+    if let Some(cwd) = &target.cwd {
+        host.insert("cwd".to_string(), cwd.clone());
+    }
+    if let Some(shell) = &target.shell {
+        host.insert("shell".to_string(), shell.to_string());
+    }
+
+    let kept = match action.hermetic {
+        true => HERMETIC_KEYED_VARS,
+        false => &[],
+    };
+    let host_vars = kept
+        .iter()
+        .copied()
+        .chain(target.pass_env.iter().map(String::as_str));
+    for name in host_vars {
+        if target.env.contains_key(name) {
+            continue;
+        }
+        let value = match action.env.get(OsStr::new(name)) {
+            Some(value) => format!("={}", value.to_string_lossy()),
+            None => "unset".to_string(),
+        };
+        host.insert(format!("env:{name}"), value);
+    }
+
+    let path_var = action.env.get(OsStr::new("PATH")).map(OsString::as_os_str);
+    for tool in &target.tools {
+        let program = tool_program(tool, &action.env);
+        let exe = resolve_tool(&program, path_var, project_root)?;
+        host.insert(
+            format!("tool:{tool}"),
+            memo.hash(&exe, target.hash_algorithm)?,
+        );
+    }
+
+    Ok(host)
+}
+
+/**
+ * The program a `tools` entry names. `${VAR:-default}` stands for the first
+ * word of `VAR` in the command's `env`, or `default` when that is unset or empty.
+ */
+fn tool_program(tool: &str, env: &BTreeMap<OsString, OsString>) -> String {
+    let Some((var, default)) = tool
+        .strip_prefix("${")
+        .and_then(|rest| rest.strip_suffix('}'))
+        .and_then(|inner| inner.split_once(":-"))
+    else {
+        return tool.to_string();
+    };
+
+    env.get(OsStr::new(var))
+        .and_then(|value| value.to_str())
+        .and_then(|value| value.split_whitespace().next())
+        .unwrap_or(default)
+        .to_string()
+}
+
+/**
+ * Whether an `mtime` target is out of date: by make's rule, or for a target
+ * without outputs (such as a test) when an input's stat moved since its last build
+ */
+fn mtime_outdated(
+    name: &str,
+    target: &TargetSpec,
+    action: &Action,
+    current: &BTreeMap<String, InputStat>,
+    cache: &mut BuildCache,
+    project_root: &Path,
+) -> bool {
+    if !target.outputs.is_empty() {
+        return outputs_older_than_inputs(&action.input_files, &target.outputs, project_root);
+    }
+
+    action.input_files.iter().any(|path| {
+        let key = relative_to(path, project_root);
+        let Some(current) = current.get(&key) else {
+            return true;
+        };
+        cache
+            .input_stat(name, &key, target.hash_algorithm)
+            .is_none_or(|recorded| recorded.stat != current.stat)
+    })
+}
+
+/**
+ * Make's rule: out of date when an output is missing or any input was
+ * modified after the oldest output. Directories count as their newest entry.
+ */
+fn outputs_older_than_inputs(inputs: &[PathBuf], outputs: &[String], project_root: &Path) -> bool {
+    let mut oldest_output = None;
+    for output in outputs {
+        let Some(mtime) = newest_mtime(&project_root.join(output)) else {
+            return true;
+        };
+        oldest_output = Some(oldest_output.map_or(mtime, |t: u64| t.min(mtime)));
+    }
+
+    let Some(oldest_output) = oldest_output else {
+        return true;
+    };
+
+    inputs
+        .iter()
+        .any(|input| newest_mtime(input).is_none_or(|mtime| mtime > oldest_output))
+}
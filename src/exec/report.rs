@@ -0,0 +1,173 @@
+//! Machine-readable test reports for CI: JUnit XML and JSON
+//!
+//! Both list one entry per selected test target with its status, duration
+//! and captured stdout and stderr.
+
+use crate::exec::test::TestReport;
+use crate::exec::types::TargetStatus;
+
+/** Suite name used in the JUnit report */
+const SUITE_NAME: &str = "bagel";
+
+impl TestReport {
+    /**
+     * The report as JUnit XML. Failed and flaky tests are `<failure>`s and
+     * tests that couldn't run are `<error>`s; cached passes are ordinary
+     * passing testcases with no time spent.
+     */
+    pub fn to_junit_xml(&self) -> String {
+        let tests = self.results.len();
+        let errors = self.not_run_count();
+        let failures = self.failed_count() + self.flaky_count();
+        let time = self.total_duration.as_secs_f64();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!(
+            "<testsuites name=\"{SUITE_NAME}\" tests=\"{tests}\" failures=\"{failures}\" errors=\"{errors}\" time=\"{time:.3}\">\n"
+        ));
+        xml.push_str(&format!(
+            "  <testsuite name=\"{SUITE_NAME}\" tests=\"{tests}\" failures=\"{failures}\" errors=\"{errors}\" time=\"{time:.3}\">\n"
+        ));
+
+        for result in &self.results {
+            xml.push_str(&format!(
+                "    <testcase name=\"{}\" classname=\"{SUITE_NAME}\" time=\"{:.3}\"",
+                xml_escape(&result.target_name),
+                result.duration.as_secs_f64()
+            ));
+
+            let failure = result.status.failure_message();
+            let stdout = result.output.as_deref().filter(|o| !o.is_empty());
+            let stderr = result.stderr.as_deref().filter(|e| !e.is_empty());
+            if failure.is_none() && stdout.is_none() && stderr.is_none() {
+                xml.push_str("/>\n");
+                continue;
+            }
+
+            xml.push_str(">\n");
+            if let Some(message) = failure {
+                let element = match result.status {
+                    TargetStatus::NotRun => "error",
+                    _ => "failure",
+                };
+                xml.push_str(&format!(
+                    "      <{element} message=\"{}\" type=\"{}\"/>\n",
+                    xml_escape(&message),
+                    status_name(&result.status)
+                ));
+            }
+            if let Some(stdout) = stdout {
+                xml.push_str(&format!(
+                    "      <system-out>{}</system-out>\n",
+                    xml_escape(stdout)
+                ));
+            }
+            if let Some(stderr) = stderr {
+                xml.push_str(&format!(
+                    "      <system-err>{}</system-err>\n",
+                    xml_escape(stderr)
+                ));
+            }
+            xml.push_str("    </testcase>\n");
+        }
+
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+
+    /**
+     * The report as pretty-printed JSON: totals plus one object per test
+     */
+    pub fn to_json(&self) -> String {
+        let tests: Vec<serde_json::Value> = self
+            .results
+            .iter()
+            .map(|result| {
+                let (runs, passed_runs) = match result.status {
+                    TargetStatus::Skipped | TargetStatus::NotRun => (0, 0),
+                    TargetStatus::Built => (self.runs_per_test, self.runs_per_test),
+                    TargetStatus::Flaky { passed, runs } => (runs, passed),
+                    _ => (self.runs_per_test, 0),
+                };
+                serde_json::json!({
+                    "name": result.target_name,
+                    "status": status_name(&result.status),
+                    "duration": result.duration.as_secs_f64(),
+                    "runs": runs,
+                    "passed_runs": passed_runs,
+                    "exit_code": match result.status {
+                        TargetStatus::Failed(code) => Some(code),
+                        _ => None,
+                    },
+                    "message": result.status.failure_message(),
+                    "output": result.output,
+                    "stderr": result.stderr,
+                })
+            })
+            .collect();
+
+        let report = serde_json::json!({
+            "success": self.success(),
+            "duration": self.total_duration.as_secs_f64(),
+            "passed": self.passed_count(),
+            "cached": self.cached_count(),
+            "failed": self.failed_count(),
+            "flaky": self.flaky_count(),
+            "not_run": self.not_run_count(),
+            "tests": tests,
+        });
+        serde_json::to_string_pretty(&report).unwrap_or_default()
+    }
+}
+
+impl TargetStatus {
+    /**
+     * Why a test with this status counts as failed, or `None` if it passed
+     */
+    pub fn failure_message(&self) -> Option<String> {
+        match self {
+            TargetStatus::Built | TargetStatus::Skipped => None,
+            TargetStatus::Failed(code) => Some(format!("exit code {code}")),
+            TargetStatus::Signaled => Some("terminated by signal".to_string()),
+            TargetStatus::TimedOut => Some("timed out".to_string()),
+            TargetStatus::Flaky { passed, runs } => {
+                Some(format!("flaky: {passed}/{runs} runs passed"))
+            }
+            TargetStatus::NotRun => Some("dependencies failed to build".to_string()),
+        }
+    }
+}
+
+/**
+ * How a test with this status is named in reports
+ */
+fn status_name(status: &TargetStatus) -> &'static str {
+    match status {
+        TargetStatus::Built => "passed",
+        TargetStatus::Skipped => "cached",
+        TargetStatus::Failed(_) | TargetStatus::Signaled | TargetStatus::TimedOut => "failed",
+        TargetStatus::Flaky { .. } => "flaky",
+        TargetStatus::NotRun => "not_run",
+    }
+}
+
+/**
+ * Escape text for XML attributes and content, dropping control characters
+ * XML 1.0 can't represent
+ */
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,9 +1,11 @@
-use crate::exec::types::{BuildReport, ExecConfig, ExecError, TargetResult, TargetStatus};
 use crate::core::{BuildSpec, TargetSpec};
-use crate::utils::{BuildCache, compute_target_hash, expand_globs};
-use std::collections::HashMap;
-use std::process::{Command, ExitStatus, Stdio};
-use std::time::Instant;
+use crate::exec::action::{Action, apply_dyndep, discover_inputs, note_cutoff};
+use crate::exec::process::{self, Completion};
+use crate::exec::rebuild::{check_freshness, record_outputs, settle_group};
+use crate::exec::types::{BuildReport, ExecConfig, ExecError, TargetResult, TargetStatus};
+use crate::utils::{BuildCache, DigestMemo};
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 /**
  * Serial executor; builds targets sequentially in topological order.
@@ -11,38 +13,54 @@ use std::time::Instant;
 pub struct SerialExecutor {
     config: ExecConfig,
     cache: BuildCache,
+    memo: DigestMemo,
 }
 
 impl SerialExecutor {
     pub fn new(config: ExecConfig) -> Result<Self, ExecError> {
-        let cache = BuildCache::new(&config.project_root);
-        Ok(Self { config, cache })
+        let cache = BuildCache::for_config(&config.project_root, config.build_config.as_deref());
+        Ok(Self {
+            config,
+            cache,
+            memo: DigestMemo::new(),
+        })
     }
 
     /**
      * Execute all targets in the build spec.
+     * Dyndep files update the graph as they appear, and the remaining targets are re-sorted.
      */
     pub fn execute_all(&mut self, spec: &BuildSpec) -> Result<BuildReport, ExecError> {
         let start = Instant::now();
-        let order = spec.topological_sort()?;
+        let mut spec = spec.clone();
+        let mut order: VecDeque<String> = spec.topological_sort()?.into();
+        let mut finished: HashSet<String> = HashSet::new();
+        let mut changed: HashSet<String> = HashSet::new();
         let mut results = Vec::new();
 
-        for target_name in &order {
+        while let Some(target_name) = order.pop_front() {
             let target = spec
-                .get_target(target_name)
+                .get_target(&target_name)
                 .ok_or_else(|| ExecError::TargetNotFound(target_name.clone()))?;
 
-            let result = self.execute_target(target_name, target)?;
+            let mut result = self.execute_target(&target_name, target, &spec)?;
+            note_cutoff(&mut result, target, &mut changed);
 
-            let failed = matches!(
-                result.status,
-                TargetStatus::Failed(_) | TargetStatus::Signaled
-            );
+            let failed = result.status.is_failure();
             results.push(result);
+            finished.insert(target_name.clone());
 
             if failed && !self.config.continue_on_error {
                 break;
             }
+
+            if !failed && apply_dyndep(&mut spec, &target_name, &self.config.project_root)? {
+                order = spec
+                    .topological_sort()?
+                    .into_iter()
+                    .filter(|name| !finished.contains(name))
+                    .collect();
+            }
         }
 
         Ok(BuildReport {
@@ -58,48 +76,95 @@ impl SerialExecutor {
         &mut self,
         name: &str,
         target: &TargetSpec,
+        spec: &BuildSpec,
     ) -> Result<TargetResult, ExecError> {
         let start = Instant::now();
 
-        let input_files = expand_globs(&target.inputs, &self.config.project_root)?;
-        let curr_hash = compute_target_hash(&input_files, &target.cmd, &target.env)?;
-
-        let needs_rebuild =
-            self.config.force_rebuild || self.cache.needs_rebuild(name, &curr_hash).unwrap_or(true);
+        if target.is_group() {
+            settle_group(name, target, &mut self.cache)?;
+            return Ok(TargetResult {
+                target_name: name.to_string(),
+                status: TargetStatus::Skipped,
+                duration: start.elapsed(),
+                output: None,
+                stderr: None,
+                cutoff: false,
+            });
+        }
 
-        if !needs_rebuild {
+        let action = Action::resolve(name, target, spec, &self.config)?;
+        let freshness = check_freshness(
+            name,
+            target,
+            &action,
+            &mut self.cache,
+            &self.memo,
+            &self.config.project_root,
+            self.config.force_rebuild,
+        )?;
+
+        if !freshness.stale {
+            self.cache.record_input_stats(name, freshness.input_stats);
+            self.cache.flush_target(name)?;
             if self.config.verbose {
-                println!("Skipping {} (up to date)", name);
+                self.progress(format_args!("Skipping {} (up to date)", name));
             }
             return Ok(TargetResult {
                 target_name: name.to_string(),
                 status: TargetStatus::Skipped,
                 duration: start.elapsed(),
                 output: None,
+                stderr: None,
+                cutoff: false,
             });
         }
 
-        println!("Building {}...", name);
+        self.progress(format_args!("Building {}...", name));
         if self.config.verbose {
-            println!("   cmd: {}", target.cmd);
+            self.progress(format_args!("   cmd: {}", action.cmd));
+            if let Some(cwd) = &target.cwd {
+                self.progress(format_args!("   cwd: {cwd}"));
+            }
         }
 
-        let status = self.run_command(&target.cmd, &target.env)?;
-        let result_status = if status.success() {
-            self.cache.record_build(name, curr_hash);
-            self.cache.flush_target(name)?;
+        action.create_output_dirs(target, &self.config.project_root)?;
+        let completion = self.run_command(&action, target)?;
+        let result_status = if completion.success() {
+            // Phony targets leave nothing in the cache
+            if !target.phony {
+                self.cache
+                    .record_build_with(name, freshness.hash, target.hash_algorithm);
+                self.cache.record_input_stats(name, freshness.input_stats);
+                record_outputs(
+                    name,
+                    target,
+                    &mut self.cache,
+                    &self.memo,
+                    &self.config.project_root,
+                )?;
+                discover_inputs(
+                    name,
+                    target,
+                    &mut self.cache,
+                    &self.config.project_root,
+                    &self.memo,
+                )?;
+                self.cache.flush_target(name)?;
+            }
             TargetStatus::Built
-        } else if let Some(code) = status.code() {
-            TargetStatus::Failed(code)
         } else {
-            TargetStatus::Signaled
+            completion.failure_status()
         };
 
         let duration = start.elapsed();
 
         match &result_status {
             TargetStatus::Built => {
-                println!("    {} completed in {:.2}s", name, duration.as_secs_f64());
+                self.progress(format_args!(
+                    "    {} completed in {:.2}s",
+                    name,
+                    duration.as_secs_f64()
+                ));
             }
             TargetStatus::Failed(code) => {
                 eprintln!("    {} failed with exit code {}", name, code);
@@ -107,7 +172,12 @@ impl SerialExecutor {
             TargetStatus::Signaled => {
                 eprintln!("    {} was terminated by signal", name);
             }
-            TargetStatus::Skipped => unreachable!(),
+            TargetStatus::TimedOut => {
+                eprintln!("    {} timed out", name);
+            }
+            TargetStatus::Skipped | TargetStatus::Flaky { .. } | TargetStatus::NotRun => {
+                unreachable!()
+            }
         }
 
         Ok(TargetResult {
@@ -115,35 +185,33 @@ impl SerialExecutor {
             status: result_status,
             duration,
             output: None,
+            stderr: None,
+            cutoff: false,
         })
     }
 
-    fn run_command(
-        &self,
-        cmd: &str,
-        env: &HashMap<String, String>,
-    ) -> Result<ExitStatus, ExecError> {
-        let mut command = if cfg!(target_os = "windows") {
-            let mut c = Command::new("cmd");
-            c.args(["/C", cmd]);
-            c
+    /**
+     * Report progress on stdout, or on stderr when stdout is kept free
+     */
+    fn progress(&self, message: std::fmt::Arguments) {
+        if self.config.progress_to_stderr {
+            eprintln!("{message}");
         } else {
-            let mut c = Command::new("sh");
-            c.args(["-c", cmd]);
-            c
-        };
-
-        command.current_dir(&self.config.project_root);
-
-        for (key, value) in env {
-            command.env(key, value);
+            println!("{message}");
         }
+    }
 
-        command.stdout(Stdio::inherit());
-        command.stderr(Stdio::inherit());
+    fn run_command(&self, action: &Action, target: &TargetSpec) -> Result<Completion, ExecError> {
+        let count = action.steps.len();
+        let timeout = target.timeout.map(Duration::from_secs);
 
-        command
-            .status()
-            .map_err(|e| ExecError::CommandError(cmd.to_string(), e))
+        process::run_steps(&action.steps, timeout, |i, step, remaining| {
+            if self.config.verbose && count > 1 {
+                self.progress(format_args!("   step {}/{}: {}", i + 1, count, step));
+            }
+            let command = process::step_command(step, &action.shell, &action.env, &action.cwd);
+            process::run_inherited(command, remaining, self.config.progress_to_stderr)
+        })
+        .map_err(|e| ExecError::CommandError(action.cmd.clone(), e))
     }
 }
@@ -0,0 +1,215 @@
+//! Running test targets for `bagel test`
+//!
+//! The tests' dependencies are built first, then every selected test runs in
+//! parallel; a test can't depend on another test, so there is no order to keep. A passing test is cached under its action key like any other
+//! target, so it only reruns once something it depends on changes; failures
+//! are never cached.
+
+use crate::core::{BuildSpec, TargetSpec};
+use crate::exec::action::Action;
+use crate::exec::process::{self, Captured, Completion};
+use crate::exec::rebuild::check_freshness;
+use crate::exec::types::{BuildReport, ExecConfig, ExecError, TargetResult, TargetStatus};
+use crate::exec::{ParallelExecutor, SerialExecutor};
+use crate::utils::{BuildCache, DigestMemo};
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+/**
+ * Results of a `bagel test` invocation. Each test's status is `Built` when it
+ * passed, `Skipped` when its pass was cached, `Flaky` when only some of its
+ * runs passed, `NotRun` when its deps failed to build and otherwise that of
+ * its first failing run, whose output it keeps.
+ */
+#[derive(Debug, Clone)]
+pub struct TestReport {
+    /** Building the tests' dependencies */
+    pub build: BuildReport,
+    pub results: Vec<TargetResult>,
+    /** How many times each test that wasn't cached was run */
+    pub runs_per_test: u32,
+    pub total_duration: Duration,
+}
+
+impl TestReport {
+    fn count(&self, status: impl Fn(&TargetStatus) -> bool) -> usize {
+        self.results.iter().filter(|r| status(&r.status)).count()
+    }
+
+    pub fn passed_count(&self) -> usize {
+        self.count(|s| *s == TargetStatus::Built)
+    }
+
+    pub fn cached_count(&self) -> usize {
+        self.count(|s| *s == TargetStatus::Skipped)
+    }
+
+    /** Tests whose every run failed */
+    pub fn failed_count(&self) -> usize {
+        self.count(|s| {
+            matches!(
+                s,
+                TargetStatus::Failed(_) | TargetStatus::Signaled | TargetStatus::TimedOut
+            )
+        })
+    }
+
+    pub fn flaky_count(&self) -> usize {
+        self.count(|s| matches!(s, TargetStatus::Flaky { .. }))
+    }
+
+    pub fn not_run_count(&self) -> usize {
+        self.count(|s| *s == TargetStatus::NotRun)
+    }
+
+    pub fn success(&self) -> bool {
+        self.build.success() && self.results.iter().all(|r| !r.status.is_failure())
+    }
+}
+
+/**
+ * Builds the dependencies of test targets, then runs the tests in parallel
+ */
+pub struct TestRunner {
+    config: ExecConfig,
+    runs_per_test: u32,
+    memo: DigestMemo,
+}
+
+impl TestRunner {
+    /**
+     * Each test is run `runs_per_test` times (at least once) to expose flaky
+     * tests; with more than one run, cached passes are not trusted
+     */
+    pub fn new(config: ExecConfig, runs_per_test: u32) -> Self {
+        Self {
+            config,
+            runs_per_test: runs_per_test.max(1),
+            memo: DigestMemo::new(),
+        }
+    }
+
+    /**
+     * Run the named test targets. If building their dependencies fails, no
+     * test is run and each is reported as [`TargetStatus::NotRun`].
+     */
+    pub fn run(&mut self, spec: &BuildSpec, tests: &[String]) -> Result<TestReport, ExecError> {
+        let start = Instant::now();
+        let closure = spec.closure(tests)?;
+
+        let deps = closure.without_tests();
+        let build = if self.config.parallel {
+            ParallelExecutor::new(self.config.clone())?.execute_all(&deps)?
+        } else {
+            SerialExecutor::new(self.config.clone())?.execute_all(&deps)?
+        };
+
+        let results = if build.success() {
+            tests
+                .par_iter()
+                .map(|name| {
+                    let target = closure
+                        .get_target(name)
+                        .ok_or_else(|| ExecError::TargetNotFound(name.clone()))?;
+                    self.run_test(name, target, &closure)
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            tests
+                .iter()
+                .map(|name| TargetResult {
+                    target_name: name.clone(),
+                    status: TargetStatus::NotRun,
+                    duration: Duration::ZERO,
+                    output: None,
+                    stderr: None,
+                    cutoff: false,
+                })
+                .collect()
+        };
+
+        Ok(TestReport {
+            build,
+            results,
+            runs_per_test: self.runs_per_test,
+            total_duration: start.elapsed(),
+        })
+    }
+
+    fn run_test(
+        &self,
+        name: &str,
+        target: &TargetSpec,
+        spec: &BuildSpec,
+    ) -> Result<TargetResult, ExecError> {
+        let start = Instant::now();
+
+        // Each test gets its own cache handle, as in the parallel executor
+        let mut cache = BuildCache::for_config(
+            &self.config.project_root,
+            self.config.build_config.as_deref(),
+        );
+
+        let action = Action::resolve(name, target, spec, &self.config)?;
+        let freshness = check_freshness(
+            name,
+            target,
+            &action,
+            &mut cache,
+            &self.memo,
+            &self.config.project_root,
+            self.config.force_rebuild || self.runs_per_test > 1,
+        )?;
+
+        if !freshness.stale {
+            return Ok(TargetResult {
+                target_name: name.to_string(),
+                status: TargetStatus::Skipped,
+                duration: start.elapsed(),
+                output: None,
+                stderr: None,
+                cutoff: false,
+            });
+        }
+
+        action.create_output_dirs(target, &self.config.project_root)?;
+        let timeout = target.timeout.map(Duration::from_secs);
+
+        let mut passed_runs = 0;
+        let mut last_output = Captured::default();
+        let mut first_failure: Option<(Completion, Captured)> = None;
+        for _ in 0..self.runs_per_test {
+            let (completion, output) = process::run_action_captured(&action, timeout)?;
+            if completion.success() {
+                passed_runs += 1;
+                last_output = output;
+            } else if first_failure.is_none() {
+                first_failure = Some((completion, output));
+            }
+        }
+
+        let status = match &first_failure {
+            None => {
+                cache.record_build_with(name, freshness.hash, target.hash_algorithm);
+                cache.record_input_stats(name, freshness.input_stats);
+                cache.flush_target(name)?;
+                TargetStatus::Built
+            }
+            Some(_) if passed_runs > 0 => TargetStatus::Flaky {
+                passed: passed_runs,
+                runs: self.runs_per_test,
+            },
+            Some((completion, _)) => completion.failure_status(),
+        };
+        let output = first_failure.map_or(last_output, |(_, output)| output);
+
+        Ok(TargetResult {
+            target_name: name.to_string(),
+            status,
+            duration: start.elapsed(),
+            output: Some(output.stdout),
+            stderr: Some(output.stderr),
+            cutoff: false,
+        })
+    }
+}
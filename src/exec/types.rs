@@ -1,7 +1,9 @@
 //! Shared types for build execution
 
 use crate::core::BuildSpecError;
-use crate::utils::{CacheError, HashError};
+use crate::utils::{CacheError, HashError, TemplateError};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::path::PathBuf;
 use std::time::Duration;
 use thiserror::Error;
@@ -20,6 +22,9 @@ pub enum ExecError {
     #[error("Hash error: {0}")]
     HashError(#[from] HashError),
 
+    #[error("Command template error: {0}")]
+    TemplateError(#[from] TemplateError),
+
     #[error("Target '{0}' failed with exit code {1}")]
     TargetFailed(String, i32),
 
@@ -39,16 +44,34 @@ pub struct TargetResult {
     pub target_name: String,
     pub status: TargetStatus,
     pub duration: Duration,
-    pub output: Option<String>,
+    pub output: Option<String>, // captured stdout, when the command's output was captured
+    pub stderr: Option<String>, // captured stderr, likewise
+    pub cutoff: bool, // skipped even though a dep was rebuilt, as the dep's outputs didn't change
 }
 
 /// Status of a target build
 #[derive(Debug, Clone, PartialEq)]
 pub enum TargetStatus {
-    Built,       // Target was built successfully
-    Skipped,     // Target was skipped (already up to date)
-    Failed(i32), // Target failed with given exit code
-    Signaled,    // Target was terminated by signal
+    Built,                            // Target was built successfully
+    Skipped,                          // Target was skipped (already up to date)
+    Failed(i32),                      // Target failed with given exit code
+    Signaled,                         // Target was terminated by signal
+    TimedOut,                         // Target was killed after exceeding its timeout
+    Flaky { passed: u32, runs: u32 }, // Test passed some of its runs and failed the others
+    NotRun,                           // Test wasn't run, as its dependencies failed to build
+}
+
+impl TargetStatus {
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            TargetStatus::Failed(_)
+                | TargetStatus::Signaled
+                | TargetStatus::TimedOut
+                | TargetStatus::Flaky { .. }
+                | TargetStatus::NotRun
+        )
+    }
 }
 
 /// Represents successful/unsuccessful targets and their status
@@ -73,10 +96,15 @@ impl BuildReport {
             .count()
     }
 
+    /// Skipped targets that would have been rebuilt without early cutoff
+    pub fn cutoff_count(&self) -> usize {
+        self.results.iter().filter(|r| r.cutoff).count()
+    }
+
     pub fn failed_count(&self) -> usize {
         self.results
             .iter()
-            .filter(|r| matches!(r.status, TargetStatus::Failed(_) | TargetStatus::Signaled))
+            .filter(|r| r.status.is_failure())
             .count()
     }
 
@@ -93,6 +121,10 @@ pub struct ExecConfig {
     pub continue_on_error: bool, // continue execution after a target fails to build
     pub verbose: bool,         // verbose output
     pub parallel: bool,        // execute in parallel
+    pub build_config: Option<String>, // selected `[configs.<name>]`; keeps its own cache
+    pub hermetic: bool,        // run every target in a clean environment
+    pub progress_to_stderr: bool, // progress and commands' stdout go to stderr, leaving stdout free
+    pub host_env: BTreeMap<OsString, OsString>, // host environment commands start from; ours by default
 }
 
 impl ExecConfig {
@@ -103,6 +135,10 @@ impl ExecConfig {
             continue_on_error: false,
             verbose: false,
             parallel: false,
+            build_config: None,
+            hermetic: false,
+            progress_to_stderr: false,
+            host_env: std::env::vars_os().collect(),
         }
     }
 }
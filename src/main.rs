@@ -1,19 +1,126 @@
-use bagel::core::BuildSpec;
-use bagel::exec::{ExecConfig, ParallelExecutor, SerialExecutor, TargetStatus};
+use bagel::core::{BuildSpec, RebuildStrategy, TargetSpec};
+use bagel::exec::{
+    BuildReport, ExecConfig, ParallelExecutor, SerialExecutor, TargetStatus, TestRunner,
+};
+use bagel::utils::HashAlgorithm;
 use std::env;
 use std::path::Path;
 
+/**
+ * `println!`, or `eprintln!` when stdout belongs to the program `bagel run` starts
+ */
+macro_rules! progress {
+    ($to_stderr:expr) => {
+        if $to_stderr {
+            eprintln!();
+        } else {
+            println!();
+        }
+    };
+    ($to_stderr:expr, $($arg:tt)*) => {
+        if $to_stderr {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/** Options shared by the commands */
+#[derive(Debug, Default)]
+struct CliOptions {
+    targets: Vec<String>,
+    force: bool,
+    verbose: bool,
+    parallel: bool,
+    hermetic: bool,
+    config: Option<String>,
+    runs_per_test: u32,
+    /** Arguments after `--`, passed to the program by `bagel run` */
+    program_args: Vec<String>,
+    junit: Option<String>,
+    report_json: Option<String>,
+}
+
+impl CliOptions {
+    fn parse(args: &[String]) -> Self {
+        let mut options = CliOptions {
+            runs_per_test: 1,
+            ..CliOptions::default()
+        };
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--" => {
+                    options.program_args = iter.by_ref().cloned().collect();
+                    break;
+                }
+                "--force" | "-f" => options.force = true,
+                "--verbose" | "-v" => options.verbose = true,
+                "--parallel" | "-j" => options.parallel = true,
+                "--hermetic" => options.hermetic = true,
+                "--config" | "-c" => match iter.next() {
+                    Some(name) => options.config = Some(name.clone()),
+                    None => {
+                        eprintln!("--config requires a configuration name");
+                        std::process::exit(1);
+                    }
+                },
+                "--runs-per-test" => options.runs_per_test = parse_runs(iter.next()),
+                "--junit" => options.junit = Some(parse_path(arg, iter.next())),
+                "--report-json" => options.report_json = Some(parse_path(arg, iter.next())),
+                _ => {
+                    if let Some(path) = arg.strip_prefix("--junit=") {
+                        options.junit = Some(path.to_string());
+                    } else if let Some(path) = arg.strip_prefix("--report-json=") {
+                        options.report_json = Some(path.to_string());
+                    } else if let Some(runs) = arg.strip_prefix("--runs-per-test=") {
+                        options.runs_per_test = parse_runs(Some(&runs.to_string()));
+                    } else if let Some(name) = arg.strip_prefix("--config=") {
+                        options.config = Some(name.to_string());
+                    } else if !arg.starts_with('-') {
+                        options.targets.push(arg.clone());
+                    }
+                }
+            }
+        }
+
+        options
+    }
+}
+
+fn parse_runs(value: Option<&String>) -> u32 {
+    match value.and_then(|v| v.parse().ok()) {
+        Some(runs) if runs > 0 => runs,
+        _ => {
+            eprintln!("--runs-per-test requires a positive number");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_path(flag: &str, value: Option<&String>) -> String {
+    match value {
+        Some(path) => path.clone(),
+        None => {
+            eprintln!("{flag} requires a file path");
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     let command = args.get(1).map(|s| s.as_str()).unwrap_or("build");
-    let force = args.iter().any(|a| a == "--force" || a == "-f");
-    let verbose = args.iter().any(|a| a == "--verbose" || a == "-v");
-    let parallel = args.iter().any(|a| a == "--parallel" || a == "-j");
+    let options = CliOptions::parse(args.get(2..).unwrap_or_default());
 
     match command {
-        "build" => run_build(force, verbose, parallel),
-        "info" => show_info(),
+        "build" => run_build(&options),
+        "test" => run_tests(&options),
+        "run" => run_target(&options),
+        "info" => show_info(&options),
         "--help" | "-h" | "help" => show_help(),
         _ => {
             eprintln!("Unknown command: {}", command);
@@ -27,21 +134,31 @@ fn show_help() {
     println!("Bagel - a simple, lightweight build system");
     println!();
     println!("USAGE:");
-    println!("    bagel [COMMAND] [OPTIONS]");
+    println!("    bagel [COMMAND] [OPTIONS] [TARGETS...]");
     println!();
     println!("COMMANDS:");
-    println!("    build    Build all targets (default)");
+    println!("    build    Build the given targets and their deps, or all targets (default)");
+    println!(
+        "    test     Build and run the test targets matching the given patterns, or all tests"
+    );
+    println!("    run      Build a target, then run its output with the arguments after '--':");
+    println!("             bagel run <target> -- ARGS...");
     println!("    info     Show build spec info without building");
     println!("    help     Show this help message");
     println!();
     println!("OPTIONS:");
+    println!("    -c, --config     Use the named [configs.<name>] section");
     println!("    -f, --force      Force rebuild all targets (ignore cache)");
     println!("    -j, --parallel   Build targets in parallel");
+    println!("        --hermetic   Run every command in a clean environment");
+    println!("        --runs-per-test N  Run each test N times to detect flaky tests");
+    println!("        --junit FILE       Write a JUnit XML report of the test run");
+    println!("        --report-json FILE Write a JSON report of the test run");
     println!("    -v, --verbose    Show verbose output");
     println!("    -h, --help       Show help");
 }
 
-fn show_info() {
+fn show_info(options: &CliOptions) {
     let build_file = "Bagel.toml";
 
     if !Path::new(build_file).exists() {
@@ -50,15 +167,25 @@ fn show_info() {
         return;
     }
 
-    match BuildSpec::from_file(build_file) {
+    match BuildSpec::from_file_with_config(build_file, options.config.as_deref()) {
         Ok(spec) => {
             if spec.targets.is_empty() {
                 println!("{build_file} exists but contains no targets");
                 return;
             }
 
+            print_warnings(&spec);
             println!("Build spec: {}", build_file);
             println!("Targets: {}", spec.targets.len());
+            if !spec.configs.is_empty() {
+                let mut configs: Vec<&String> = spec.configs.keys().collect();
+                configs.sort();
+                let configs: Vec<&str> = configs.iter().map(|c| c.as_str()).collect();
+                println!("Configs: {}", configs.join(", "));
+            }
+            if let Some(config) = &spec.config {
+                println!("Using config: {config}");
+            }
             println!();
 
             match spec.topological_sort() {
@@ -72,6 +199,7 @@ fn show_info() {
                             format!("deps: {}", target.deps.join(", "))
                         };
                         println!("  {}. {} ({})", i + 1, target_name, deps_str);
+                        print_target_details(target);
                     }
                 }
                 Err(e) => {
@@ -87,7 +215,69 @@ fn show_info() {
     }
 }
 
-fn run_build(force: bool, verbose: bool, parallel: bool) {
+/**
+ * Print a target's fields after `[defaults]` have been merged in
+ */
+fn print_target_details(target: &TargetSpec) {
+    if target.is_group() {
+        println!("       group of: {}", target.deps.join(", "));
+        return;
+    }
+
+    println!("       kind: {}", target.kind);
+    println!("       inputs: {}", target.inputs.join(", "));
+    println!("       outputs: {}", target.outputs.join(", "));
+
+    if !target.env.is_empty() {
+        let mut env: Vec<_> = target.env.iter().collect();
+        env.sort();
+        let env_str: Vec<String> = env.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        println!("       env: {}", env_str.join(" "));
+    }
+
+    if let Some(cwd) = &target.cwd {
+        println!("       cwd: {cwd}");
+    }
+
+    if let Some(shell) = &target.shell {
+        println!("       shell: {shell}");
+    }
+
+    if !target.pass_env.is_empty() {
+        println!("       pass_env: {}", target.pass_env.join(", "));
+    }
+
+    if !target.tools.is_empty() {
+        println!("       tools: {}", target.tools.join(", "));
+    }
+
+    if target.phony {
+        println!("       phony: yes");
+    } else if target.always {
+        println!("       always: yes");
+    }
+
+    if target.hermetic {
+        println!("       hermetic: yes");
+    }
+
+    if let Some(timeout) = target.timeout {
+        println!("       timeout: {timeout}s");
+    }
+
+    if target.rebuild != RebuildStrategy::default() {
+        println!("       rebuild: {}", target.rebuild);
+    }
+
+    if target.hash_algorithm != HashAlgorithm::default() {
+        println!("       hash: {}", target.hash_algorithm);
+    }
+}
+
+/**
+ * Load `Bagel.toml` from the current directory, exiting if it is missing or invalid
+ */
+fn load_spec(options: &CliOptions) -> BuildSpec {
     let build_file = "Bagel.toml";
 
     if !Path::new(build_file).exists() {
@@ -96,35 +286,93 @@ fn run_build(force: bool, verbose: bool, parallel: bool) {
         std::process::exit(1);
     }
 
-    let spec = match BuildSpec::from_file(build_file) {
-        Ok(spec) => spec,
+    match BuildSpec::from_file_with_config(build_file, options.config.as_deref()) {
+        Ok(spec) => {
+            print_warnings(&spec);
+            spec
+        }
         Err(e) => {
             eprintln!("Failed to parse {build_file}: {e}");
             std::process::exit(1);
         }
-    };
+    }
+}
 
-    if spec.targets.is_empty() {
-        println!("No targets defined in {build_file}");
-        return;
+fn print_warnings(spec: &BuildSpec) {
+    for warning in spec.warnings() {
+        eprintln!("warning: {warning}");
     }
+}
 
+fn exec_config(options: &CliOptions) -> ExecConfig {
     let project_root = env::current_dir().expect("Failed to get current directory");
 
     let mut config = ExecConfig::new(project_root);
-    config.force_rebuild = force;
-    config.verbose = verbose;
-    config.parallel = parallel;
+    config.force_rebuild = options.force;
+    config.verbose = options.verbose;
+    config.parallel = options.parallel;
+    config.build_config = options.config.clone();
+    config.hermetic = options.hermetic;
+    config
+}
+
+fn run_build(options: &CliOptions) {
+    let mut spec = load_spec(options);
+
+    if !options.targets.is_empty() {
+        spec = match spec.closure(&options.targets) {
+            Ok(closure) => closure,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        };
+    }
+    // Tests run under `bagel test`
+    spec = spec.without_tests();
+
+    if spec.targets.is_empty() {
+        println!("No targets defined in Bagel.toml");
+        return;
+    }
+
+    let report = build(&spec, exec_config(options));
+    print_build_summary(&report, false);
+    if !report.success() {
+        std::process::exit(1);
+    }
 
-    let mode = if parallel { "parallel" } else { "serial" };
-    println!(
-        "Building {} target(s) ({} mode)...",
-        spec.targets.len(),
-        mode
-    );
     println!();
+    println!("All targets built successfully!");
+}
+
+/**
+ * Build every target in `spec`, printing progress as it goes
+ */
+fn build(spec: &BuildSpec, config: ExecConfig) -> BuildReport {
+    let verbose = config.verbose;
+    let parallel = config.parallel;
+    let to_stderr = config.progress_to_stderr;
+
+    let mode = if parallel { "parallel" } else { "serial" };
+    match &config.build_config {
+        Some(name) => progress!(
+            to_stderr,
+            "Building {} target(s) ({} mode, config '{}')...",
+            spec.targets.len(),
+            mode,
+            name
+        ),
+        None => progress!(
+            to_stderr,
+            "Building {} target(s) ({} mode)...",
+            spec.targets.len(),
+            mode
+        ),
+    }
+    progress!(to_stderr);
 
-    let report = if parallel {
+    if parallel {
         let mut executor = match ParallelExecutor::new(config) {
             Ok(e) => e,
             Err(e) => {
@@ -133,17 +381,17 @@ fn run_build(force: bool, verbose: bool, parallel: bool) {
             }
         };
 
-        match executor.execute_all(&spec) {
+        match executor.execute_all(spec) {
             Ok(r) => {
                 for result in &r.results {
-                    if let Some(output) = &result.output {
-                        if !output.is_empty() {
-                            println!("[{}] {}", result.target_name, output.trim());
-                        }
+                    let captured = [&result.output, &result.stderr].into_iter().flatten();
+                    for output in captured.filter(|output| !output.is_empty()) {
+                        progress!(to_stderr, "[{}] {}", result.target_name, output.trim());
                     }
                     match &result.status {
                         TargetStatus::Built => {
-                            println!(
+                            progress!(
+                                to_stderr,
                                 "    {} completed in {:.2}s",
                                 result.target_name,
                                 result.duration.as_secs_f64()
@@ -151,7 +399,11 @@ fn run_build(force: bool, verbose: bool, parallel: bool) {
                         }
                         TargetStatus::Skipped => {
                             if verbose {
-                                println!("Skipping {} (up to date)", result.target_name);
+                                progress!(
+                                    to_stderr,
+                                    "Skipping {} (up to date)",
+                                    result.target_name
+                                );
                             }
                         }
                         TargetStatus::Failed(code) => {
@@ -160,6 +412,11 @@ fn run_build(force: bool, verbose: bool, parallel: bool) {
                         TargetStatus::Signaled => {
                             eprintln!("    {} was terminated by signal", result.target_name);
                         }
+                        TargetStatus::TimedOut => {
+                            eprintln!("    {} timed out", result.target_name);
+                        }
+                        // Only tests end up flaky or not run
+                        TargetStatus::Flaky { .. } | TargetStatus::NotRun => {}
                     }
                 }
                 r
@@ -178,27 +435,37 @@ fn run_build(force: bool, verbose: bool, parallel: bool) {
             }
         };
 
-        match executor.execute_all(&spec) {
+        match executor.execute_all(spec) {
             Ok(r) => r,
             Err(e) => {
                 eprintln!("Build failed: {e}");
                 std::process::exit(1);
             }
         }
-    };
+    }
+}
 
-    println!();
-    println!("─────────────────────────────────────");
-    println!(
+fn print_build_summary(report: &BuildReport, to_stderr: bool) {
+    progress!(to_stderr);
+    progress!(to_stderr, "─────────────────────────────────────");
+    progress!(
+        to_stderr,
         "Build completed in {:.2}s",
         report.total_duration.as_secs_f64()
     );
-    println!("  Built:   {}", report.built_count());
-    println!("  Skipped: {}", report.skipped_count());
+    progress!(to_stderr, "  Built:   {}", report.built_count());
+    progress!(to_stderr, "  Skipped: {}", report.skipped_count());
+    if report.cutoff_count() > 0 {
+        progress!(
+            to_stderr,
+            "  Cut off: {} (deps rebuilt with unchanged outputs)",
+            report.cutoff_count()
+        );
+    }
 
     if report.failed_count() > 0 {
-        println!("  Failed:  {}", report.failed_count());
-        println!();
+        progress!(to_stderr, "  Failed:  {}", report.failed_count());
+        progress!(to_stderr);
 
         for result in &report.results {
             match &result.status {
@@ -208,15 +475,186 @@ fn run_build(force: bool, verbose: bool, parallel: bool) {
                 TargetStatus::Signaled => {
                     eprintln!("  - {} (signaled)", result.target_name);
                 }
+                TargetStatus::TimedOut => {
+                    eprintln!("  - {} (timed out)", result.target_name);
+                }
                 _ => {}
             }
         }
+    }
+}
+
+/**
+ * Build a binary target's closure, then run its output attached to the terminal;
+ * the program's exit code becomes ours
+ */
+fn run_target(options: &CliOptions) {
+    let [name] = options.targets.as_slice() else {
+        eprintln!("Usage: bagel run <target> [-- ARGS...]");
+        std::process::exit(1);
+    };
+
+    let spec = load_spec(options);
+    let Some(target) = spec.get_target(name) else {
+        eprintln!("No target named '{name}'");
+        std::process::exit(1);
+    };
+    let program = match target.runnable_output(name) {
+        Ok(output) => output.to_string(),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
 
+    let closure = match spec.closure(std::slice::from_ref(name)) {
+        Ok(closure) => closure,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    // The program's output is all that goes to stdout
+    let mut config = exec_config(options);
+    config.progress_to_stderr = true;
+    let report = build(&closure, config);
+    if !report.success() {
+        print_build_summary(&report, true);
         std::process::exit(1);
     }
+    eprintln!();
+
+    let project_root = env::current_dir().expect("Failed to get current directory");
+    let status = std::process::Command::new(project_root.join(&program))
+        .args(&options.program_args)
+        .status();
+
+    match status {
+        Ok(status) => std::process::exit(exit_code(status)),
+        Err(e) => {
+            eprintln!("Failed to run {program}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/**
+ * Exit code to report for a finished program; like a shell, a program killed
+ * by a signal gives 128 + the signal number
+ */
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+fn run_tests(options: &CliOptions) {
+    let spec = load_spec(options);
 
+    let tests = match spec.tests_matching(&options.targets) {
+        Ok(tests) => tests,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if tests.is_empty() {
+        println!("No test targets defined in Bagel.toml");
+        return;
+    }
+
+    let config = exec_config(options);
+
+    match options.runs_per_test {
+        1 => println!("Running {} test(s)...", tests.len()),
+        runs => println!("Running {} test(s), {runs} runs each...", tests.len()),
+    }
     println!();
-    println!("All targets built successfully!");
+
+    let report = match TestRunner::new(config, options.runs_per_test).run(&spec, &tests) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Test run failed: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    // Reports are written whatever the outcome, so CI can pick up failures
+    let reports = [
+        (&options.junit, report.to_junit_xml()),
+        (&options.report_json, report.to_json()),
+    ];
+    for (path, content) in reports {
+        if let Some(path) = path
+            && let Err(e) = std::fs::write(path, content)
+        {
+            eprintln!("Failed to write report {path}: {e}");
+            std::process::exit(1);
+        }
+    }
+
+    if !report.build.success() {
+        eprintln!("Building test dependencies failed:");
+        for result in &report.build.results {
+            if result.status.is_failure() {
+                for output in [&result.output, &result.stderr].into_iter().flatten() {
+                    eprintln!("[{}] {}", result.target_name, output.trim());
+                }
+                eprintln!("  - {}", result.target_name);
+            }
+        }
+        std::process::exit(1);
+    }
+
+    for result in &report.results {
+        let secs = result.duration.as_secs_f64();
+        match &result.status {
+            TargetStatus::Built => println!("PASSED  {} ({:.2}s)", result.target_name, secs),
+            TargetStatus::Skipped => println!("CACHED  {}", result.target_name),
+            TargetStatus::Flaky { passed, runs } => println!(
+                "FLAKY   {} ({passed}/{runs} runs passed)",
+                result.target_name
+            ),
+            status => println!(
+                "FAILED  {} ({}, {secs:.2}s)",
+                result.target_name,
+                status.failure_message().unwrap_or_default()
+            ),
+        }
+        if result.status.is_failure() {
+            let captured = [&result.output, &result.stderr].into_iter().flatten();
+            for output in captured.filter(|output| !output.trim().is_empty()) {
+                for line in output.trim_end().lines() {
+                    println!("    {line}");
+                }
+            }
+        }
+    }
+
+    println!();
+    println!("─────────────────────────────────────");
+    println!(
+        "Tests finished in {:.2}s",
+        report.total_duration.as_secs_f64()
+    );
+    println!("  Passed:  {}", report.passed_count());
+    println!("  Cached:  {}", report.cached_count());
+    if report.flaky_count() > 0 {
+        println!("  Flaky:   {}", report.flaky_count());
+    }
+    println!("  Failed:  {}", report.failed_count());
+
+    if !report.success() {
+        std::process::exit(1);
+    }
+
+    println!();
+    println!("All tests passed!");
 }
 
 fn show_getting_started() {
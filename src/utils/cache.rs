@@ -1,5 +1,7 @@
+use crate::core::{DEFAULT_CONFIG, RebuildStrategy};
+use crate::utils::{DigestMemo, FileStat, HashAlgorithm};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
@@ -19,7 +21,36 @@ pub enum CacheError {
 pub struct CacheEntry {
     // Hashed inputs + command + env of the last successful build
     pub hash: String,
+    // Algorithm behind every digest in this entry; entries from before it was recorded used SHA-256
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
     pub built_at: u64,
+    // Inputs found in the target's depfile after the build, mapped to their hashes
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub discovered_inputs: BTreeMap<String, String>,
+    // Stats of the discovered inputs, for the `mtime` and `stat` rebuild strategies
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub discovered_stats: BTreeMap<String, FileStat>,
+    // Hash of the outputs produced by that build; dependents key off this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_hash: Option<String>,
+    // Stats of each input when its hash was taken, for the `stat` rebuild strategy
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub input_stats: BTreeMap<String, InputStat>,
+    // Stats of each output right after the build; a mismatch means something else rewrote it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_stats: Option<BTreeMap<String, FileStat>>,
+}
+
+/**
+ * An input's content hash along with its stat at the time it was hashed.
+ * While the stat is unchanged the hash is reused instead of re-reading the file.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InputStat {
+    #[serde(flatten)]
+    pub stat: FileStat,
+    pub hash: String,
 }
 
 /**
@@ -30,6 +61,7 @@ pub struct CacheEntry {
 #[derive(Debug, Clone, Default)]
 pub struct BuildCache {
     root: PathBuf,
+    config: Option<String>,
     entries: HashMap<String, CacheEntry>,
     dirty: HashMap<String, bool>,
 }
@@ -39,8 +71,16 @@ impl BuildCache {
      * Create a new cache handle for a project
      */
     pub fn new(project_root: &Path) -> Self {
+        Self::for_config(project_root, None)
+    }
+
+    /**
+     * Create a cache handle whose entries are kept apart from other build configurations
+     */
+    pub fn for_config(project_root: &Path, config: Option<&str>) -> Self {
         Self {
             root: project_root.to_path_buf(),
+            config: config.map(str::to_string),
             entries: HashMap::new(),
             dirty: HashMap::new(),
         }
@@ -60,10 +100,10 @@ impl BuildCache {
             let path = entry.path();
 
             if path.extension().and_then(|s| s.to_str()) == Some("json")
-                && let Some(target_name) = path.file_stem().and_then(|s| s.to_str())
+                && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
                 && let Ok(cache_entry) = self.load_entry(&path)
             {
-                self.entries.insert(target_name.to_string(), cache_entry);
+                self.entries.insert(decode_file_stem(stem), cache_entry);
             }
         }
 
@@ -75,14 +115,30 @@ impl BuildCache {
         target_name: &str,
         current_hash: &str,
     ) -> Result<bool, CacheError> {
+        self.needs_rebuild_with(target_name, current_hash, HashAlgorithm::default())
+    }
+
+    /**
+     * Like `needs_rebuild`, for a hash computed with `algorithm`. An entry
+     * recorded with a different algorithm is always out of date.
+     */
+    pub fn needs_rebuild_with(
+        &mut self,
+        target_name: &str,
+        current_hash: &str,
+        algorithm: HashAlgorithm,
+    ) -> Result<bool, CacheError> {
+        let is_stale =
+            |entry: &CacheEntry| entry.hash_algorithm != algorithm || entry.hash != current_hash;
+
         if let Some(entry) = self.entries.get(target_name) {
-            return Ok(entry.hash != current_hash);
+            return Ok(is_stale(entry));
         }
 
         let path = self.entry_path(target_name);
         if path.exists() {
             let entry = self.load_entry(&path)?;
-            let needs_rebuild = entry.hash != current_hash;
+            let needs_rebuild = is_stale(&entry);
             self.entries.insert(target_name.to_string(), entry);
 
             Ok(needs_rebuild)
@@ -95,6 +151,13 @@ impl BuildCache {
      * Record a sucessfully, and mark the entry as dirty
      */
     pub fn record_build(&mut self, target_name: &str, hash: String) {
+        self.record_build_with(target_name, hash, HashAlgorithm::default());
+    }
+
+    /**
+     * Record a successful build whose digests were computed with `algorithm`
+     */
+    pub fn record_build_with(&mut self, target_name: &str, hash: String, algorithm: HashAlgorithm) {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .map(|d| d.as_secs())
@@ -102,12 +165,144 @@ impl BuildCache {
 
         let entry = CacheEntry {
             hash,
+            hash_algorithm: algorithm,
             built_at: now,
+            discovered_inputs: BTreeMap::new(),
+            discovered_stats: BTreeMap::new(),
+            output_hash: None,
+            input_stats: BTreeMap::new(),
+            output_stats: None,
         };
         self.entries.insert(target_name.to_string(), entry);
         self.dirty.insert(target_name.to_string(), true);
     }
 
+    /**
+     * Attach inputs discovered during the build (e.g. from a depfile) to the
+     * target's recorded entry
+     */
+    pub fn record_discovered_inputs(
+        &mut self,
+        target_name: &str,
+        discovered: BTreeMap<String, String>,
+    ) {
+        if let Some(entry) = self.entries.get_mut(target_name) {
+            entry.discovered_inputs = discovered;
+            self.dirty.insert(target_name.to_string(), true);
+        }
+    }
+
+    /**
+     * Attach the stats of the discovered inputs, so they are only re-read once
+     * their stat changes
+     */
+    pub fn record_discovered_stats(
+        &mut self,
+        target_name: &str,
+        stats: BTreeMap<String, FileStat>,
+    ) {
+        if let Some(entry) = self.entries.get_mut(target_name) {
+            entry.discovered_stats = stats;
+            self.dirty.insert(target_name.to_string(), true);
+        }
+    }
+
+    /**
+     * Attach the hash of the outputs produced by the target's recorded build
+     */
+    pub fn record_output_hash(&mut self, target_name: &str, output_hash: String) {
+        if let Some(entry) = self.entries.get_mut(target_name) {
+            entry.output_hash = Some(output_hash);
+            self.dirty.insert(target_name.to_string(), true);
+        }
+    }
+
+    /**
+     * Output hash of the target's last successful build, loading its entry from disk if needed
+     */
+    pub fn output_hash(&mut self, target_name: &str) -> Option<String> {
+        self.load(target_name)?.output_hash.clone()
+    }
+
+    /**
+     * Attach the stats of the outputs produced by the target's recorded build
+     */
+    pub fn record_output_stats(&mut self, target_name: &str, stats: BTreeMap<String, FileStat>) {
+        if let Some(entry) = self.entries.get_mut(target_name) {
+            entry.output_stats = Some(stats);
+            self.dirty.insert(target_name.to_string(), true);
+        }
+    }
+
+    /**
+     * Output stats of the target's last successful build, loading its entry from disk if needed
+     */
+    pub fn output_stats(&mut self, target_name: &str) -> Option<&BTreeMap<String, FileStat>> {
+        self.load(target_name)?.output_stats.as_ref()
+    }
+
+    /**
+     * Replace the recorded input stats of a target, marking it dirty only if they changed
+     */
+    pub fn record_input_stats(&mut self, target_name: &str, stats: BTreeMap<String, InputStat>) {
+        if let Some(entry) = self.entries.get_mut(target_name)
+            && entry.input_stats != stats
+        {
+            entry.input_stats = stats;
+            self.dirty.insert(target_name.to_string(), true);
+        }
+    }
+
+    /**
+     * Recorded stat of one of the target's inputs, loading its entry from disk if needed.
+     * Stats recorded under a different hash algorithm are not returned.
+     */
+    pub fn input_stat(
+        &mut self,
+        target_name: &str,
+        input: &str,
+        algorithm: HashAlgorithm,
+    ) -> Option<&InputStat> {
+        let entry = self.load(target_name)?;
+        if entry.hash_algorithm != algorithm {
+            return None;
+        }
+        entry.input_stats.get(input)
+    }
+
+    /**
+     * Whether any input discovered by the target's last build has changed or
+     * disappeared. Relative paths are resolved against the project root.
+     * An input whose recorded stat still matches is not read; under `mtime`
+     * a changed stat is a change, under `stat` the contents are compared.
+     */
+    pub fn discovered_inputs_changed(
+        &self,
+        target_name: &str,
+        memo: &DigestMemo,
+        strategy: RebuildStrategy,
+    ) -> bool {
+        let Some(entry) = self.entries.get(target_name) else {
+            return false;
+        };
+
+        entry.discovered_inputs.iter().any(|(path, recorded)| {
+            let full_path = self.root.join(path);
+            if let Some(stat) = entry.discovered_stats.get(path) {
+                match FileStat::of(&full_path) {
+                    Ok(current) if current == *stat => return false,
+                    Err(_) => return true,
+                    Ok(_) if strategy == RebuildStrategy::Mtime => return true,
+                    Ok(_) => {}
+                }
+            }
+            match memo.hash(&full_path, entry.hash_algorithm) {
+                Ok(current) => current != *recorded,
+                Err(_) => true,
+            }
+        })
+    }
+
     /**
      * Flush a single target's cache to disk.
      * Each worker can call this independently without coordination
@@ -123,7 +318,7 @@ impl BuildCache {
             fs::create_dir_all(&cache_dir)?;
 
             let path = self.entry_path(target_name);
-            let tmp_path = cache_dir.join(format!("{}.tmp", target_name));
+            let tmp_path = cache_dir.join(format!("{}.tmp", encode_file_stem(target_name)));
 
             let content = serde_json::to_string_pretty(entry)
                 .map_err(|e| CacheError::ParseError(path.display().to_string(), e))?;
@@ -189,9 +384,9 @@ impl BuildCache {
             let entry = entry?;
             let path = entry.path();
             if path.extension().and_then(|s| s.to_str()) == Some("json")
-                && let Some(name) = path.file_stem().and_then(|s| s.to_str())
+                && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
             {
-                targets.push(name.to_string());
+                targets.push(decode_file_stem(stem));
             }
         }
 
@@ -214,17 +409,33 @@ impl BuildCache {
     }
 
     /**
-     * Returns default cache directory
+     * Returns the cache directory; each build configuration gets a subdirectory,
+     * and building without one is the same as selecting the default configuration
      */
     fn cache_dir(&self) -> PathBuf {
-        self.root.join(CACHE_DIR)
+        let config = self.config.as_deref().unwrap_or(DEFAULT_CONFIG);
+        self.root.join(CACHE_DIR).join(encode_file_stem(config))
     }
 
     /**
      * Formats a target dependency to the path of its cache file
      */
     fn entry_path(&self, target_name: &str) -> PathBuf {
-        self.cache_dir().join(format!("{}.json", target_name))
+        self.cache_dir()
+            .join(format!("{}.json", encode_file_stem(target_name)))
+    }
+
+    /**
+     * Returns the target's entry, loading it from disk if it isn't loaded yet
+     */
+    fn load(&mut self, target_name: &str) -> Option<&CacheEntry> {
+        if !self.entries.contains_key(target_name) {
+            let path = self.entry_path(target_name);
+            let entry = self.load_entry(&path).ok()?;
+            self.entries.insert(target_name.to_string(), entry);
+        }
+
+        self.entries.get(target_name)
     }
 
     /**
@@ -237,6 +448,48 @@ impl BuildCache {
     }
 }
 
+/**
+ * Escape a target name for use as a file name; generated names such as
+ * `compile:net/http` contain separators that aren't valid in one path component
+ */
+fn encode_file_stem(target_name: &str) -> String {
+    let mut encoded = String::with_capacity(target_name.len());
+    for byte in target_name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"_-.".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/**
+ * Inverse of `encode_file_stem`
+ */
+fn decode_file_stem(stem: &str) -> String {
+    let bytes = stem.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| stem.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 /// Reason why a target needs to be rebuilt
 #[derive(Debug, Clone, PartialEq)]
 pub enum RebuildReason {
@@ -375,6 +628,115 @@ mod tests {
         fs::remove_dir_all(&dir).ok();
     }
 
+    #[test]
+    fn test_discovered_inputs_detect_changes() {
+        let dir = temp_dir("discovered");
+        fs::write(dir.join("util.h"), "#define A 1").unwrap();
+
+        let mut cache = BuildCache::new(&dir);
+        cache.record_build("app", "hash".to_string());
+        cache.record_discovered_inputs(
+            "app",
+            BTreeMap::from([(
+                "util.h".to_string(),
+                crate::utils::hash_file(dir.join("util.h")).unwrap(),
+            )]),
+        );
+        cache.flush().unwrap();
+
+        let mut reloaded = BuildCache::new(&dir);
+        assert!(!reloaded.needs_rebuild("app", "hash").unwrap());
+        let memo = DigestMemo::new();
+        let changed = |cache: &BuildCache| {
+            cache.discovered_inputs_changed("app", &memo, RebuildStrategy::Hash)
+        };
+        assert!(!changed(&reloaded));
+
+        fs::write(dir.join("util.h"), "#define A 2").unwrap();
+        assert!(changed(&reloaded));
+
+        fs::remove_file(dir.join("util.h")).unwrap();
+        assert!(changed(&reloaded));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_switching_hash_algorithm_invalidates() {
+        let dir = temp_dir("hash_algorithm");
+
+        let mut cache = BuildCache::new(&dir);
+        cache.record_build_with("app", "abc".to_string(), HashAlgorithm::Xxh64);
+        cache.flush().unwrap();
+
+        let mut reloaded = BuildCache::new(&dir);
+        assert!(
+            !reloaded
+                .needs_rebuild_with("app", "abc", HashAlgorithm::Xxh64)
+                .unwrap()
+        );
+        assert!(
+            reloaded
+                .needs_rebuild_with("app", "abc", HashAlgorithm::Sha256)
+                .unwrap()
+        );
+
+        // Entries written before the algorithm was recorded are SHA-256
+        fs::write(
+            reloaded.entry_path("old"),
+            r#"{ "hash": "abc", "built_at": 0 }"#,
+        )
+        .unwrap();
+        assert!(!reloaded.needs_rebuild("old", "abc").unwrap());
+        assert!(
+            reloaded
+                .needs_rebuild_with("old", "abc", HashAlgorithm::Xxh64)
+                .unwrap()
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_configs_cached_separately() {
+        let dir = temp_dir("configs");
+
+        let mut debug = BuildCache::new(&dir);
+        debug.record_build("app", "debug_hash".to_string());
+        debug.flush().unwrap();
+
+        let mut release = BuildCache::for_config(&dir, Some("release"));
+        assert!(release.needs_rebuild("app", "debug_hash").unwrap());
+        release.record_build("app", "release_hash".to_string());
+        release.flush().unwrap();
+
+        // Building release did not evict the default configuration's entry
+        let mut debug = BuildCache::new(&dir);
+        assert!(!debug.needs_rebuild("app", "debug_hash").unwrap());
+        assert_eq!(debug.cached_targets().unwrap(), vec!["app"]);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_generated_target_names_round_trip() {
+        let dir = temp_dir("generated_names");
+        let mut cache = BuildCache::new(&dir);
+
+        cache.record_build("compile:net/http", "1".to_string());
+        cache.record_build("100%", "2".to_string());
+        cache.flush().unwrap();
+
+        let mut reloaded = BuildCache::new(&dir);
+        assert!(!reloaded.needs_rebuild("compile:net/http", "1").unwrap());
+        assert_eq!(
+            reloaded.cached_targets().unwrap(),
+            vec!["100%", "compile:net/http"]
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn test_parallel_safe_writes() {
         // Simulate parallel builds: two "workers" updating different targets
@@ -0,0 +1,105 @@
+//! Parsing of Makefile-format dependency files, as written by `gcc -MD` or `rustc --emit=dep-info`
+
+/**
+ * Return every prerequisite listed in a depfile, in order of first appearance.
+ *
+ * Handles line continuations (`\` + newline), escaped spaces (`\ `), `$$`,
+ * and the empty phony rules emitted by `-MP`.
+ */
+pub fn parse_depfile(content: &str) -> Vec<String> {
+    let mut deps: Vec<String> = Vec::new();
+
+    let joined = content.replace("\\\r\n", " ").replace("\\\n", " ");
+    for line in joined.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some(prereqs) = split_rule(line) else {
+            continue;
+        };
+
+        for word in split_words(prereqs) {
+            if !deps.contains(&word) {
+                deps.push(word);
+            }
+        }
+    }
+
+    deps
+}
+
+/**
+ * Return the text after the rule's `:` separator; a `:` followed by a path
+ * separator (e.g. `C:\foo`) or preceded by a backslash is part of a path
+ */
+fn split_rule(line: &str) -> Option<&str> {
+    let bytes = line.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        if b != b':' {
+            continue;
+        }
+        let escaped = i > 0 && bytes[i - 1] == b'\\';
+        let drive = matches!(bytes.get(i + 1), Some(b'\\') | Some(b'/')) && i == 1;
+        if !escaped && !drive {
+            return Some(&line[i + 1..]);
+        }
+    }
+    None
+}
+
+fn split_words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some(' ') | Some('#') | Some(':')) => {
+                current.push(chars.next().unwrap());
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                current.push('$');
+            }
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    words.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gcc_depfile() {
+        let content =
+            "main.o: main.c include/util.h \\\n  include/my\\ header.h\n\ninclude/util.h:\n";
+
+        assert_eq!(
+            parse_depfile(content),
+            vec!["main.c", "include/util.h", "include/my header.h"]
+        );
+    }
+
+    #[test]
+    fn test_parse_multiple_rules_dedupes() {
+        let content = "a.o: a.c common.h\nb.o: b.c common.h cost$$.h\n";
+
+        assert_eq!(
+            parse_depfile(content),
+            vec!["a.c", "common.h", "b.c", "cost$.h"]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! File digests memoized for the duration of one build
+//!
+//! Several targets often hash the same file (a shared header, a dep's output),
+//! and parallel workers would otherwise each read it. The memo is keyed by path
+//! and algorithm, and an entry is only reused while the file's stat is unchanged.
+//! Racy stats (see [`FileStat::is_racy`]) are never memoized, as a same-size
+//! rewrite within one mtime tick would leave them unchanged.
+//!
+//! A directory is hashed as a tree: every entry's relative path, kind and mode,
+//! plus the contents of each file, in sorted order. Its own stat says nothing
+//! about nested contents, so only the files inside are memoized.
+
+use crate::utils::{FileStat, HashAlgorithm, HashError, hash_file_with};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Slot = Arc<Mutex<Option<(FileStat, String)>>>;
+
+/**
+ * Concurrent digest memo shared by every target in a build; clones share entries
+ */
+#[derive(Debug, Clone, Default)]
+pub struct DigestMemo {
+    slots: Arc<Mutex<HashMap<(PathBuf, HashAlgorithm), Slot>>>,
+    files_hashed: Arc<AtomicUsize>,
+}
+
+impl DigestMemo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Digest of `path`, read from disk only if it hasn't been hashed yet, its
+     * stat changed since, or it was modified too recently to trust its stat.
+     * Concurrent callers for the same file wait for the first one instead of
+     * reading it again.
+     */
+    pub fn hash(&self, path: &Path, algorithm: HashAlgorithm) -> Result<String, HashError> {
+        if path.is_dir() {
+            return self.hash_tree(path, algorithm, false);
+        }
+
+        let slot = self.slot(path, algorithm);
+        let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+
+        let stat = FileStat::of(path)?;
+        if let Some((recorded, digest)) = slot.as_ref()
+            && *recorded == stat
+        {
+            return Ok(digest.clone());
+        }
+
+        let digest = hash_file_with(path, algorithm)?;
+        self.files_hashed.fetch_add(1, Ordering::Relaxed);
+        *slot = (!stat.is_racy()).then(|| (stat, digest.clone()));
+        Ok(digest)
+    }
+
+    /**
+     * Hash `path` unconditionally and remember the result; used for outputs a
+     * command just wrote, whose stat may not differ from an earlier read
+     */
+    pub fn rehash(&self, path: &Path, algorithm: HashAlgorithm) -> Result<String, HashError> {
+        if path.is_dir() {
+            return self.hash_tree(path, algorithm, true);
+        }
+
+        let slot = self.slot(path, algorithm);
+        let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+
+        let stat = FileStat::of(path)?;
+        let digest = hash_file_with(path, algorithm)?;
+        self.files_hashed.fetch_add(1, Ordering::Relaxed);
+        *slot = (!stat.is_racy()).then(|| (stat, digest.clone()));
+        Ok(digest)
+    }
+
+    /**
+     * Hash several files in parallel, preserving their order
+     */
+    pub fn hash_all(
+        &self,
+        paths: &[PathBuf],
+        algorithm: HashAlgorithm,
+    ) -> Result<Vec<(PathBuf, String)>, HashError> {
+        paths
+            .par_iter()
+            .map(|path| Ok((path.clone(), self.hash(path, algorithm)?)))
+            .collect()
+    }
+
+    /**
+     * Number of times a file was actually read; mainly for tests and diagnostics
+     */
+    pub fn files_hashed(&self) -> usize {
+        self.files_hashed.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Digest of a directory tree. Symlinks are hashed by their target rather
+     * than followed, so links out of the tree or back into it are harmless.
+     */
+    fn hash_tree(
+        &self,
+        dir: &Path,
+        algorithm: HashAlgorithm,
+        rehash: bool,
+    ) -> Result<String, HashError> {
+        let entries = tree_entries(dir)?;
+
+        let digests = entries
+            .par_iter()
+            .map(|entry| match entry.kind {
+                EntryKind::File if rehash => self.rehash(&dir.join(&entry.path), algorithm),
+                EntryKind::File => self.hash(&dir.join(&entry.path), algorithm),
+                EntryKind::Dir => Ok(String::new()),
+                EntryKind::Symlink => {
+                    let full_path = dir.join(&entry.path);
+                    std::fs::read_link(&full_path)
+                        .map(|target| target.to_string_lossy().into_owned())
+                        .map_err(|e| HashError::IoError(full_path.display().to_string(), e))
+                }
+            })
+            .collect::<Result<Vec<_>, HashError>>()?;
+
+        let mut hasher = Sha256::new();
+        for (entry, digest) in entries.iter().zip(digests) {
+            hasher.update(entry.path.to_string_lossy().as_bytes());
+            hasher.update(format!(":{}:{:o}:", entry.kind.tag(), entry.mode).as_bytes());
+            hasher.update(digest.as_bytes());
+            hasher.update(b"\n");
+        }
+
+        Ok(format!("tree:{}", hex::encode(hasher.finalize())))
+    }
+
+    fn slot(&self, path: &Path, algorithm: HashAlgorithm) -> Slot {
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        slots
+            .entry((path.to_path_buf(), algorithm))
+            .or_default()
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl EntryKind {
+    fn tag(self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Dir => "dir",
+            EntryKind::Symlink => "link",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TreeEntry {
+    path: PathBuf, // relative to the tree's root
+    kind: EntryKind,
+    mode: u32,
+}
+
+/**
+ * Every entry below `dir`, sorted by relative path
+ */
+fn tree_entries(dir: &Path) -> Result<Vec<TreeEntry>, HashError> {
+    let io_error = |path: &Path, e| HashError::IoError(path.display().to_string(), e);
+
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel_dir) = pending.pop() {
+        let full_dir = dir.join(&rel_dir);
+        for entry in std::fs::read_dir(&full_dir).map_err(|e| io_error(&full_dir, e))? {
+            let entry = entry.map_err(|e| io_error(&full_dir, e))?;
+            let metadata = entry
+                .path()
+                .symlink_metadata()
+                .map_err(|e| io_error(&entry.path(), e))?;
+            let path = rel_dir.join(entry.file_name());
+
+            let kind = if metadata.file_type().is_symlink() {
+                EntryKind::Symlink
+            } else if metadata.is_dir() {
+                pending.push(path.clone());
+                EntryKind::Dir
+            } else {
+                EntryKind::File
+            };
+
+            entries.push(TreeEntry {
+                path,
+                kind,
+                mode: file_mode(&metadata),
+            });
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bagel_digest_test_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /** Write `path` with an mtime old enough that its stat isn't racy */
+    fn write_settled(path: &Path, contents: &str) {
+        std::fs::write(path, contents).unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_each_file_read_once_across_threads() {
+        let dir = temp_dir("once");
+        let paths: Vec<PathBuf> = (0..4)
+            .map(|i| {
+                let path = dir.join(format!("{i}.txt"));
+                write_settled(&path, &format!("file {i}"));
+                path
+            })
+            .collect();
+
+        let memo = DigestMemo::new();
+        let hashed: Vec<_> = (0..8)
+            .into_par_iter()
+            .map(|_| memo.hash_all(&paths, HashAlgorithm::Sha256).unwrap())
+            .collect();
+
+        assert!(hashed.windows(2).all(|w| w[0] == w[1]));
+        assert_eq!(memo.files_hashed(), 4);
+
+        // Algorithms are memoized separately
+        memo.hash(&paths[0], HashAlgorithm::Xxh64).unwrap();
+        assert_eq!(memo.files_hashed(), 5);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_tree_hash_covers_paths_contents_and_modes() {
+        let dir = temp_dir("tree");
+        let tree = dir.join("assets");
+        std::fs::create_dir_all(tree.join("img")).unwrap();
+        std::fs::write(tree.join("img/logo.png"), "png").unwrap();
+        std::fs::write(tree.join("run.sh"), "echo").unwrap();
+
+        let hash = || {
+            DigestMemo::new()
+                .hash(&tree, HashAlgorithm::Sha256)
+                .unwrap()
+        };
+        let original = hash();
+        assert_eq!(original, hash());
+
+        std::fs::write(tree.join("img/logo.png"), "gif").unwrap();
+        let changed_content = hash();
+        assert_ne!(changed_content, original);
+
+        std::fs::rename(tree.join("run.sh"), tree.join("start.sh")).unwrap();
+        let renamed = hash();
+        assert_ne!(renamed, changed_content);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(
+                tree.join("start.sh"),
+                std::fs::Permissions::from_mode(0o755),
+            )
+            .unwrap();
+            assert_ne!(hash(), renamed);
+        }
+
+        // An empty directory is still part of the tree
+        let before_empty = hash();
+        std::fs::create_dir(tree.join("empty")).unwrap();
+        assert_ne!(hash(), before_empty);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_changed_stat_is_rehashed() {
+        let dir = temp_dir("changed");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "one").unwrap();
+
+        let memo = DigestMemo::new();
+        let before = memo.hash(&path, HashAlgorithm::Sha256).unwrap();
+
+        std::fs::write(&path, "three").unwrap();
+        let after = memo.hash(&path, HashAlgorithm::Sha256).unwrap();
+
+        assert_ne!(before, after);
+        assert_eq!(memo.files_hashed(), 2);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_racy_stat_is_not_memoized() {
+        let dir = temp_dir("racy");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "one").unwrap();
+
+        let memo = DigestMemo::new();
+        let before = memo.hash(&path, HashAlgorithm::Sha256).unwrap();
+
+        // Same size, and likely the same mtime tick
+        std::fs::write(&path, "two").unwrap();
+        let after = memo.hash(&path, HashAlgorithm::Sha256).unwrap();
+
+        assert_ne!(before, after);
+        assert_eq!(memo.files_hashed(), 2);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
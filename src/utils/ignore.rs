@@ -0,0 +1,159 @@
+//! Ignore files (`.bagelignore`, `.gitignore`) applied to glob-expanded inputs
+//!
+//! Supports the common gitignore syntax: comments, `!` to re-include, a
+//! trailing `/` for directories only, a leading or inner `/` to anchor a
+//! pattern to the project root, and `*`, `?`, `[...]` and `**`. Only the
+//! ignore files at the project root are read.
+
+use glob::{MatchOptions, Pattern};
+use std::path::Path;
+
+/** Read whenever it exists */
+pub const BAGELIGNORE: &str = ".bagelignore";
+
+/** Read only for targets with `gitignore = true` */
+pub const GITIGNORE: &str = ".gitignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+}
+
+/**
+ * Ordered ignore rules; as in git, the last rule matching a path decides
+ */
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /**
+     * Load `.bagelignore` from the project root, followed by `.gitignore` when
+     * `gitignore` is set. Missing files contribute no rules.
+     */
+    pub fn load(project_root: &Path, gitignore: bool) -> Self {
+        let mut rules = Self::default();
+        let files = if gitignore {
+            vec![GITIGNORE, BAGELIGNORE]
+        } else {
+            vec![BAGELIGNORE]
+        };
+
+        for file in files {
+            if let Ok(content) = std::fs::read_to_string(project_root.join(file)) {
+                rules.rules.extend(Self::parse(&content).rules);
+            }
+        }
+        rules
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let rules = content.lines().filter_map(parse_rule).collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /**
+     * Whether `rel_path` (relative to the project root) is ignored. A path
+     * inside an ignored directory is ignored too, and can't be re-included.
+     */
+    pub fn is_ignored(&self, rel_path: &Path, is_dir: bool) -> bool {
+        let components: Vec<_> = rel_path.components().collect();
+
+        for depth in 1..=components.len() {
+            let prefix: std::path::PathBuf = components[..depth].iter().collect();
+            let prefix_is_dir = depth < components.len() || is_dir;
+
+            if self.matches(&prefix, prefix_is_dir) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let path_str = path.to_string_lossy();
+        let mut ignored = false;
+
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            if rule.pattern.matches_with(&path_str, MATCH_OPTIONS) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (negated, line) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line.strip_prefix('\\').unwrap_or(line)),
+    };
+
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+
+    // Without a slash a pattern matches at any depth; with one it is anchored
+    let pattern = match line.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if line.contains('/') => line.to_string(),
+        None => format!("**/{line}"),
+    };
+
+    Pattern::new(&pattern).ok().map(|pattern| Rule {
+        pattern,
+        negated,
+        dir_only,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gitignore_syntax() {
+        let rules = IgnoreRules::parse(
+            "# editor files\n*.swp\n*~\n\nbuild/\n/generated\ndocs/*.html\n!docs/index.html\n",
+        );
+
+        assert!(rules.is_ignored(Path::new("src/.main.c.swp"), false));
+        assert!(rules.is_ignored(Path::new("src/main.c~"), false));
+        assert!(!rules.is_ignored(Path::new("src/main.c"), false));
+
+        // Directory-only rules apply to everything inside
+        assert!(rules.is_ignored(Path::new("build/app.o"), false));
+        assert!(rules.is_ignored(Path::new("src/build/app.o"), false));
+        assert!(!rules.is_ignored(Path::new("build"), false));
+
+        // Anchored to the root
+        assert!(rules.is_ignored(Path::new("generated/api.c"), false));
+        assert!(!rules.is_ignored(Path::new("src/generated/api.c"), false));
+
+        // Later negations re-include
+        assert!(rules.is_ignored(Path::new("docs/guide.html"), false));
+        assert!(!rules.is_ignored(Path::new("docs/index.html"), false));
+    }
+}
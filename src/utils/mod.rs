@@ -1,16 +1,27 @@
 //! Utility functions for the bagel build system
 
 pub mod cache;
+pub mod depfile;
+pub mod digest;
+pub mod ignore;
+pub mod template;
 pub mod xxhash_ffi;
 
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufReader, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use xxhash_ffi::xxhash_file;
+use xxhash_ffi::{xxh3_file, xxhash_file};
 
-pub use cache::{BuildCache, CacheEntry, CacheError, RebuildReason};
+pub use cache::{BuildCache, CacheEntry, CacheError, InputStat, RebuildReason};
+pub use depfile::parse_depfile;
+pub use digest::DigestMemo;
+pub use ignore::IgnoreRules;
+pub use template::{TemplateContext, TemplateError};
 
 #[derive(Error, Debug)]
 pub enum HashError {
@@ -20,6 +31,8 @@ pub enum HashError {
     GlobError(#[from] glob::PatternError),
     #[error("No files matched pattern: {0}")]
     NoFilesMatched(String),
+    #[error("Tool '{0}' was not found on PATH")]
+    ToolNotFound(String),
 }
 
 /**
@@ -52,6 +65,48 @@ pub fn hash_fast<P: AsRef<Path>>(path: P) -> Result<u64, HashError> {
         .map_err(|e| HashError::IoError(path.as_ref().display().to_string(), e))
 }
 
+/**
+ * Algorithm used for content digests. SHA-256 is the default; XXH64 and XXH3
+ * stream the file through the vendored xxHash and are much faster on large inputs.
+ */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Xxh64,
+    Xxh3,
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Xxh64 => write!(f, "xxh64"),
+            HashAlgorithm::Xxh3 => write!(f, "xxh3"),
+        }
+    }
+}
+
+/**
+ * Hash a single file with the given algorithm and return it hex-encoded
+ */
+pub fn hash_file_with<P: AsRef<Path>>(
+    path: P,
+    algorithm: HashAlgorithm,
+) -> Result<String, HashError> {
+    match algorithm {
+        HashAlgorithm::Sha256 => hash_file(path),
+        HashAlgorithm::Xxh64 => Ok(format!("{:016x}", hash_fast(path)?)),
+        HashAlgorithm::Xxh3 => {
+            let path = path.as_ref();
+            let digest =
+                xxh3_file(path).map_err(|e| HashError::IoError(path.display().to_string(), e))?;
+            Ok(format!("{digest:016x}"))
+        }
+    }
+}
+
 /**
  * Hash multiple files and combine into a single hash.
  */
@@ -70,41 +125,139 @@ pub fn hash_files<P: AsRef<Path>>(paths: &[P]) -> Result<String, HashError> {
     Ok(hex::encode(combined_hasher.finalize()))
 }
 
+/**
+ * Paths left out of glob matches. `.bagel/` is always excluded; literal
+ * (non-glob) inputs are kept even when a rule would exclude them.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct InputFilter {
+    /** Project-relative paths excluded along with everything under them, e.g. other targets' outputs */
+    pub excluded: Vec<PathBuf>,
+    pub ignore: IgnoreRules,
+}
+
+impl InputFilter {
+    pub(crate) fn excludes(&self, rel_path: &Path, is_dir: bool) -> bool {
+        rel_path.starts_with(BAGEL_DIR)
+            || self.excluded.iter().any(|ex| rel_path.starts_with(ex))
+            || self.ignore.is_ignored(rel_path, is_dir)
+    }
+}
+
+/** Bagel's own state directory, never treated as an input */
+const BAGEL_DIR: &str = ".bagel";
+
 /**
  * Expand glob patterns and return matching file paths
  */
-pub fn expand_globs(
+pub fn expand_globs(patterns: &[String], base_dir: &Path) -> Result<Vec<PathBuf>, HashError> {
+    expand_inputs(patterns, base_dir, &InputFilter::default())
+}
+
+/**
+ * Expand glob patterns, then drop matches of `!` patterns and anything the
+ * filter excludes. Directories matched by a recursive (`**`) glob are dropped
+ * too, since the files inside are matched individually.
+ */
+pub fn expand_inputs(
     patterns: &[String],
     base_dir: &Path,
-) -> Result<Vec<std::path::PathBuf>, HashError> {
+    filter: &InputFilter,
+) -> Result<Vec<PathBuf>, HashError> {
     let mut files = Vec::new();
+    let mut negations = Vec::new();
 
     for pattern in patterns {
-        let full_pattern = base_dir.join(pattern);
-        let pattern_str = full_pattern.to_string_lossy();
+        if let Some(negated) = pattern.strip_prefix('!') {
+            negations.push(glob::Pattern::new(negated)?);
+            continue;
+        }
 
-        let matches: Vec<_> = glob::glob(&pattern_str)?.filter_map(Result::ok).collect();
+        let is_glob = pattern.contains(['*', '?', '[']);
+        if !is_glob {
+            let literal_path = base_dir.join(pattern);
+            if literal_path.exists() {
+                files.push(literal_path);
+                continue;
+            }
+            return Err(HashError::NoFilesMatched(pattern.clone()));
+        }
+
+        let full_pattern = base_dir.join(pattern);
+        let matches: Vec<_> = glob::glob(&full_pattern.to_string_lossy())?
+            .filter_map(Result::ok)
+            .collect();
 
         if matches.is_empty() {
-            if !pattern.contains('*') && !pattern.contains('?') {
-                let literal_path = base_dir.join(pattern);
-                if literal_path.exists() {
-                    files.push(literal_path);
-                } else {
-                    return Err(HashError::NoFilesMatched(pattern.clone()));
-                }
-            } else {
-                return Err(HashError::NoFilesMatched(pattern.clone()));
-            }
-        } else {
-            files.extend(matches);
+            return Err(HashError::NoFilesMatched(pattern.clone()));
         }
+
+        let recursive = pattern.contains("**");
+        files.extend(matches.into_iter().filter(|path| {
+            let is_dir = path.is_dir();
+            let rel_path = path.strip_prefix(base_dir).unwrap_or(path);
+            let tree_of_matched_files = recursive && is_dir;
+            !tree_of_matched_files && !filter.excludes(rel_path, is_dir)
+        }));
     }
 
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    files.retain(|path| {
+        let rel_path = path.strip_prefix(base_dir).unwrap_or(path);
+        !negations
+            .iter()
+            .any(|negation| negation.matches_path_with(rel_path, options))
+    });
+
     files.sort();
+    files.dedup();
     Ok(files)
 }
 
+/**
+ * Locate a tool's executable the way the shell would: names containing a
+ * path separator are taken relative to `base_dir`, anything else is searched
+ * for on `path_var`. Symlinks are resolved, so e.g. `cc` hashes the real compiler.
+ */
+pub fn resolve_tool(
+    tool: &str,
+    path_var: Option<&std::ffi::OsStr>,
+    base_dir: &Path,
+) -> Result<PathBuf, HashError> {
+    let candidate = if tool.contains(std::path::MAIN_SEPARATOR) || tool.contains('/') {
+        Some(base_dir.join(tool)).filter(|path| path.is_file())
+    } else {
+        path_var.and_then(|paths| {
+            std::env::split_paths(paths)
+                .flat_map(|dir| {
+                    let exe = dir.join(tool);
+                    let with_suffix = dir.join(format!("{tool}{}", std::env::consts::EXE_SUFFIX));
+                    [exe, with_suffix]
+                })
+                .find(|path| is_executable(path))
+        })
+    };
+
+    candidate
+        .and_then(|path| path.canonicalize().ok())
+        .ok_or_else(|| HashError::ToolNotFound(tool.to_string()))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
 /**
  * Hash a string (useful for hashing commands)
  */
@@ -115,18 +268,141 @@ pub fn hash_string(s: &str) -> String {
 }
 
 /**
- * Compute a combined hash for a target's inputs and command
- * This becomes the cache key upon running change detection
+ * Hash a target's declared outputs, relative to `base_dir`, after its command ran.
+ * An output may be a directory, hashed as a whole tree.
+ * Missing outputs are part of the hash rather than an error.
+ */
+pub fn hash_outputs(
+    outputs: &[String],
+    base_dir: &Path,
+    algorithm: HashAlgorithm,
+    memo: &DigestMemo,
+) -> Result<String, HashError> {
+    let mut hasher = Sha256::new();
+
+    for output in outputs {
+        let path = base_dir.join(output);
+        hasher.update(output.as_bytes());
+        hasher.update(b":");
+        if path.exists() {
+            hasher.update(memo.rehash(&path, algorithm)?.as_bytes());
+        } else {
+            hasher.update(b"missing");
+        }
+        hasher.update(b"\n");
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/**
+ * The parts of a file's metadata that change whenever its contents are rewritten
  */
-pub fn compute_target_hash(
-    input_files: &[std::path::PathBuf],
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u64,
+    pub mtime_ns: u64,
+    pub inode: u64,
+}
+
+impl FileStat {
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<Self, HashError> {
+        let path = path.as_ref();
+        let metadata = std::fs::metadata(path)
+            .map_err(|e| HashError::IoError(path.display().to_string(), e))?;
+
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        let mtime_ns = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos() as u64);
+
+        Ok(Self {
+            size: metadata.len(),
+            mtime_ns,
+            inode,
+        })
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.mtime_ns)
+    }
+
+    /**
+     * Whether the file was modified so recently that a further write could
+     * land within the same mtime tick and go unnoticed; such stats are not
+     * trusted to vouch for a recorded hash
+     */
+    pub fn is_racy(&self) -> bool {
+        SystemTime::now()
+            .duration_since(self.modified())
+            .map_or(true, |age| age < Duration::from_secs(2))
+    }
+
+    /**
+     * Stat of a file, or of a directory with the mtime of its most recently
+     * modified entry, since a directory's own stat misses nested rewrites
+     */
+    pub fn of_tree<P: AsRef<Path>>(path: P) -> Result<Self, HashError> {
+        let path = path.as_ref();
+        let mut stat = Self::of(path)?;
+        if path.is_dir() {
+            stat.size = 0;
+            stat.mtime_ns = newest_mtime(path).unwrap_or(stat.mtime_ns);
+        }
+        Ok(stat)
+    }
+}
+
+/**
+ * Modification time of a file, or of the most recently modified entry in a directory tree
+ */
+pub fn newest_mtime(path: &Path) -> Option<u64> {
+    let own = FileStat::of(path).ok()?.mtime_ns;
+    if !path.is_dir() {
+        return Some(own);
+    }
+
+    let mut newest = own;
+    for entry in std::fs::read_dir(path).ok()?.flatten() {
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+        let mtime = if is_dir {
+            newest_mtime(&entry.path())?
+        } else {
+            // Dangling symlinks have no mtime of their own to compare
+            FileStat::of(entry.path()).map_or(0, |stat| stat.mtime_ns)
+        };
+        newest = newest.max(mtime);
+    }
+    Some(newest)
+}
+
+/**
+ * Combine already-computed input digests with the rest of a target's cache key;
+ * rebuild strategies that avoid re-reading inputs supply their own digests.
+ * Deps contribute the hash of their outputs, so a dep rebuilt into
+ * byte-identical outputs doesn't invalidate its dependents (early cutoff).
+ * Input paths are keyed relative to `base_dir`, so the key survives moving the project.
+ * `host_inputs` holds what the command picks up from the host, such as
+ * passed-through env values and tool digests.
+ */
+pub fn combine_target_hash(
+    base_dir: &Path,
+    input_digests: &[(PathBuf, String)],
     command: &str,
     env: &std::collections::HashMap<String, String>,
-) -> Result<String, HashError> {
+    dep_outputs: &BTreeMap<String, String>,
+    host_inputs: &BTreeMap<String, String>,
+) -> String {
     let mut hasher = Sha256::new();
 
-    for path in input_files {
-        let file_hash = hash_file(path)?;
+    for (path, file_hash) in input_digests {
+        let path = path.strip_prefix(base_dir).unwrap_or(path);
         hasher.update(path.to_string_lossy().as_bytes());
         hasher.update(b":");
         hasher.update(file_hash.as_bytes());
@@ -147,7 +423,23 @@ pub fn compute_target_hash(
         hasher.update(b"\n");
     }
 
-    Ok(hex::encode(hasher.finalize()))
+    for (dep, output_hash) in dep_outputs {
+        hasher.update(b"dep:");
+        hasher.update(dep.as_bytes());
+        hasher.update(b"=");
+        hasher.update(output_hash.as_bytes());
+        hasher.update(b"\n");
+    }
+
+    for (key, value) in host_inputs {
+        hasher.update(b"host:");
+        hasher.update(key.as_bytes());
+        hasher.update(b"=");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    }
+
+    hex::encode(hasher.finalize())
 }
 
 #[cfg(test)]
@@ -192,6 +484,103 @@ mod tests {
         std::fs::remove_dir_all(&dir).ok();
     }
 
+    #[test]
+    fn test_hash_file_with_xxhash() {
+        let dir = std::env::temp_dir().join("bagel_test_hash_xxh64");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Larger than the read buffer, so the digest spans several chunks
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+        let file_path = dir.join("big.bin");
+        std::fs::write(&file_path, &content).unwrap();
+
+        let hash = hash_file_with(&file_path, HashAlgorithm::Xxh64).unwrap();
+        assert_eq!(hash, format!("{:016x}", xxhash_ffi::xxhash64(&content)));
+        assert_ne!(
+            hash,
+            hash_file_with(&file_path, HashAlgorithm::Sha256).unwrap()
+        );
+
+        let hash = hash_file_with(&file_path, HashAlgorithm::Xxh3).unwrap();
+        assert_eq!(hash, format!("{:016x}", xxhash_ffi::xxh3_64(&content)));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_target_hash_independent_of_project_location() {
+        let base = std::env::temp_dir().join("bagel_test_hash_relocate");
+        let _ = std::fs::remove_dir_all(&base);
+
+        let hash_in = |root: &Path| {
+            std::fs::create_dir_all(root.join("src")).unwrap();
+            std::fs::write(root.join("src/main.c"), "int main;").unwrap();
+            let input = root.join("src/main.c");
+            let digest = hash_file(&input).unwrap();
+            combine_target_hash(
+                root,
+                &[(input, digest)],
+                "gcc src/main.c",
+                &std::collections::HashMap::new(),
+                &BTreeMap::new(),
+                &BTreeMap::new(),
+            )
+        };
+
+        assert_eq!(hash_in(&base.join("a")), hash_in(&base.join("b/nested")));
+
+        std::fs::remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn test_expand_inputs_applies_exclusions() {
+        let dir = std::env::temp_dir().join("bagel_test_expand_inputs");
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in [
+            "src/main.c",
+            "src/util.c",
+            "src/util_test.c",
+            "src/net/http_test.c",
+            "src/.main.c.swp",
+            "src/gen/api.c",
+            ".bagel/cache/x.c",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+
+        let filter = InputFilter {
+            excluded: vec![PathBuf::from("src/gen")],
+            ignore: IgnoreRules::parse("*.swp\n"),
+        };
+        let rel = |files: Vec<PathBuf>| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| f.strip_prefix(&dir).unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let patterns = vec!["**/*".to_string(), "!src/**/*_test.c".to_string()];
+        let files = expand_inputs(&patterns, &dir, &filter).unwrap();
+        assert_eq!(rel(files), vec!["src/main.c", "src/util.c"]);
+
+        // Listing an excluded path explicitly keeps it
+        let patterns = vec!["src/*.c".to_string(), "src/gen/api.c".to_string()];
+        let files = expand_inputs(&patterns, &dir, &filter).unwrap();
+        assert_eq!(
+            rel(files),
+            vec![
+                "src/gen/api.c",
+                "src/main.c",
+                "src/util.c",
+                "src/util_test.c"
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn test_hash_files_order_matters() {
         let dir = std::env::temp_dir().join("bagel_test_hash_order");
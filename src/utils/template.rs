@@ -0,0 +1,375 @@
+//! Placeholder expansion for target commands
+//!
+//! Supported placeholders:
+//! - `{name}`: the target's name
+//! - `{inputs}` / `{in}`: the target's inputs after glob expansion
+//! - `{outputs}` / `{out}`: the target's declared outputs
+//! - `{deps.outputs}`: outputs of every direct dependency, in `deps` order
+//! - `{deps.<dep>.outputs}`: outputs of a single direct dependency
+//! - `{deps.transitive_libs}`: outputs of every lib reachable through lib deps,
+//!   each lib before the libs it depends on (the order static linkers need)
+//!
+//! `{{` and `}}` produce literal braces, and `${...}` is left for the shell.
+//! Any other `{word}` is kept as written, so commands like `awk '{print}'`
+//! need no escaping; only keys under the `deps.`, `matrix.` and `config.`
+//! namespaces must be known.
+
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TemplateError {
+    #[error("Unknown placeholder '{{{0}}}'")]
+    UnknownPlaceholder(String),
+    #[error("Placeholder '{{{0}}}' refers to '{1}', which is not a dependency")]
+    NotADependency(String, String),
+}
+
+/** A placeholder reference found in a template */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placeholder {
+    Name,
+    Inputs,
+    Outputs,
+    AllDepOutputs,
+    DepOutputs(String),
+    TransitiveLibs,
+}
+
+/** Key prefixes reserved for placeholders; an unknown key under one is a mistake */
+const RESERVED_NAMESPACES: &[&str] = &["deps.", "matrix.", "config."];
+
+impl Placeholder {
+    /**
+     * The placeholder `key` names, or `None` for a key that is left as written
+     */
+    fn parse(key: &str) -> Result<Option<Self>, TemplateError> {
+        let placeholder = match key {
+            "name" => Placeholder::Name,
+            "inputs" | "in" => Placeholder::Inputs,
+            "outputs" | "out" => Placeholder::Outputs,
+            "deps.outputs" => Placeholder::AllDepOutputs,
+            "deps.transitive_libs" => Placeholder::TransitiveLibs,
+            _ => match key
+                .strip_prefix("deps.")
+                .and_then(|rest| rest.strip_suffix(".outputs"))
+                .filter(|dep| !dep.is_empty())
+            {
+                Some(dep) => Placeholder::DepOutputs(dep.to_string()),
+                None if RESERVED_NAMESPACES.iter().any(|ns| key.starts_with(ns)) => {
+                    return Err(TemplateError::UnknownPlaceholder(key.to_string()));
+                }
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(placeholder))
+    }
+}
+
+/**
+ * Values placeholders expand to. Paths are relative to the project root.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct TemplateContext<'a> {
+    pub name: &'a str,
+    pub inputs: Vec<String>,
+    pub outputs: &'a [String],
+    /** Direct dependencies (in `deps` order) and their declared outputs */
+    pub deps: Vec<(&'a str, &'a [String])>,
+    /** Rules and matrices whose every target is a dependency, with those targets */
+    pub dep_groups: Vec<(&'a str, &'a [String])>,
+    /** Outputs of the transitive lib deps, in link order */
+    pub transitive_libs: Vec<String>,
+}
+
+impl TemplateContext<'_> {
+    fn words(&self, placeholder: &Placeholder, key: &str) -> Result<Vec<&str>, TemplateError> {
+        Ok(match placeholder {
+            Placeholder::Name => vec![self.name],
+            Placeholder::Inputs => self.inputs.iter().map(String::as_str).collect(),
+            Placeholder::Outputs => self.outputs.iter().map(String::as_str).collect(),
+            Placeholder::AllDepOutputs => self
+                .deps
+                .iter()
+                .flat_map(|(_, outputs)| outputs.iter().map(String::as_str))
+                .collect(),
+            Placeholder::DepOutputs(dep) => {
+                let members = match self.dep_groups.iter().find(|(name, _)| name == dep) {
+                    Some((_, members)) => members.iter().map(String::as_str).collect(),
+                    None => vec![dep.as_str()],
+                };
+                let mut words = Vec::new();
+                for member in members {
+                    let (_, outputs) = self
+                        .deps
+                        .iter()
+                        .find(|(name, _)| *name == member)
+                        .ok_or_else(|| {
+                            TemplateError::NotADependency(key.to_string(), dep.clone())
+                        })?;
+                    words.extend(outputs.iter().map(String::as_str));
+                }
+                words
+            }
+            Placeholder::TransitiveLibs => {
+                self.transitive_libs.iter().map(String::as_str).collect()
+            }
+        })
+    }
+}
+
+/**
+ * Expand every placeholder in `template`
+ */
+pub fn expand(template: &str, ctx: &TemplateContext) -> Result<String, TemplateError> {
+    let mut out = String::with_capacity(template.len());
+    for segment in Segments::new(template) {
+        match segment {
+            Segment::Literal(text) => out.push_str(text),
+            Segment::Escaped(c) => out.push(c),
+            Segment::Placeholder(key) => match Placeholder::parse(key)? {
+                Some(placeholder) => out.push_str(&shell_join(&ctx.words(&placeholder, key)?)),
+                None => out.push_str(&format!("{{{key}}}")),
+            },
+        }
+    }
+    Ok(out)
+}
+
+/**
+ * Expand one argv element without any shell quoting. An element that is
+ * exactly one placeholder becomes one argument per value; elsewhere values
+ * are joined with spaces.
+ */
+pub fn expand_arg(arg: &str, ctx: &TemplateContext) -> Result<Vec<String>, TemplateError> {
+    let segments: Vec<Segment> = Segments::new(arg).collect();
+    if let [Segment::Placeholder(key)] = segments.as_slice()
+        && let Some(placeholder) = Placeholder::parse(key)?
+    {
+        return Ok(ctx
+            .words(&placeholder, key)?
+            .into_iter()
+            .map(str::to_string)
+            .collect());
+    }
+
+    let mut out = String::with_capacity(arg.len());
+    for segment in segments {
+        match segment {
+            Segment::Literal(text) => out.push_str(text),
+            Segment::Escaped(c) => out.push(c),
+            Segment::Placeholder(key) => match Placeholder::parse(key)? {
+                Some(placeholder) => out.push_str(&ctx.words(&placeholder, key)?.join(" ")),
+                None => out.push_str(&format!("{{{key}}}")),
+            },
+        }
+    }
+    Ok(vec![out])
+}
+
+/**
+ * List the placeholders referenced by `template`; used to validate specs before building
+ */
+pub fn placeholders(template: &str) -> Result<Vec<Placeholder>, TemplateError> {
+    Segments::new(template)
+        .filter_map(|segment| match segment {
+            Segment::Placeholder(key) => Placeholder::parse(key).transpose(),
+            _ => None,
+        })
+        .collect()
+}
+
+/**
+ * Quote a word for POSIX `sh` if it contains anything but safe characters
+ */
+pub fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./:@%+=,".contains(c);
+
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+fn shell_join(words: &[&str]) -> String {
+    words
+        .iter()
+        .map(|w| shell_quote(w))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+enum Segment<'a> {
+    Literal(&'a str),
+    Escaped(char),
+    Placeholder(&'a str),
+}
+
+/**
+ * Splits a template into literal text, escaped braces and placeholder keys.
+ * A `{` only opens a placeholder when it is followed by key characters and a
+ * closing `}`, so shell constructs like `{ a; }` or `{a,b}` pass through.
+ */
+struct Segments<'a> {
+    rest: &'a str,
+    prev: Option<char>,
+}
+
+impl<'a> Segments<'a> {
+    fn new(template: &'a str) -> Self {
+        Self {
+            rest: template,
+            prev: None,
+        }
+    }
+
+    fn placeholder_len(s: &str) -> Option<usize> {
+        let is_key_char = |c: char| c.is_ascii_alphanumeric() || "_.:@/+-".contains(c);
+        let body = s.strip_prefix('{')?;
+        let end = body.find(|c: char| !is_key_char(c))?;
+        (end > 0 && body[end..].starts_with('}')).then_some(end + 2)
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        for escape in ["{{", "}}"] {
+            if let Some(rest) = self.rest.strip_prefix(escape) {
+                self.rest = rest;
+                self.prev = None;
+                return Some(Segment::Escaped(escape.chars().next().unwrap()));
+            }
+        }
+
+        if self.prev != Some('$')
+            && let Some(len) = Self::placeholder_len(self.rest)
+        {
+            let key = &self.rest[1..len - 1];
+            self.rest = &self.rest[len..];
+            self.prev = Some('}');
+            return Some(Segment::Placeholder(key));
+        }
+
+        // Literal run up to the next brace (always consuming at least one char)
+        let first_len = self.rest.chars().next().map_or(0, char::len_utf8);
+        let end = self.rest[first_len..]
+            .find(['{', '}'])
+            .map_or(self.rest.len(), |i| i + first_len);
+        let (literal, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        self.prev = literal.chars().last();
+        Some(Segment::Literal(literal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_placeholders() {
+        let outputs = vec!["build/app".to_string()];
+        let utils_outputs = vec!["utils.o".to_string()];
+        let lib_outputs = vec!["lib one.o".to_string()];
+        let group = vec!["lib".to_string(), "utils".to_string()];
+        let ctx = TemplateContext {
+            name: "app",
+            inputs: vec!["main.c".to_string(), "util.h".to_string()],
+            outputs: &outputs,
+            deps: vec![("utils", &utils_outputs), ("lib", &lib_outputs)],
+            dep_groups: vec![("objects", &group)],
+            transitive_libs: vec!["lib one.o".to_string(), "libutils.a".to_string()],
+        };
+
+        assert_eq!(
+            expand("gcc -o {out} {in} {deps.utils.outputs}", &ctx).unwrap(),
+            "gcc -o build/app main.c util.h utils.o"
+        );
+        assert_eq!(
+            expand("ld {deps.outputs} # {name}", &ctx).unwrap(),
+            "ld utils.o 'lib one.o' # app"
+        );
+        assert_eq!(
+            expand("ar rcs {out} {deps.objects.outputs}", &ctx).unwrap(),
+            "ar rcs build/app 'lib one.o' utils.o"
+        );
+        assert_eq!(
+            expand("cc -o {out} main.o {deps.transitive_libs}", &ctx).unwrap(),
+            "cc -o build/app main.o 'lib one.o' libutils.a"
+        );
+    }
+
+    #[test]
+    fn test_expand_arg_splices_lists() {
+        let outputs = vec!["build/app".to_string()];
+        let ctx = TemplateContext {
+            name: "app",
+            inputs: vec!["src/main.c".to_string(), "src/my util.c".to_string()],
+            outputs: &outputs,
+            deps: vec![],
+            dep_groups: vec![],
+            transitive_libs: vec![],
+        };
+
+        assert_eq!(
+            expand_arg("{inputs}", &ctx).unwrap(),
+            vec!["src/main.c", "src/my util.c"]
+        );
+        assert_eq!(expand_arg("-o{out}", &ctx).unwrap(), vec!["-obuild/app"]);
+        assert_eq!(
+            expand_arg("--files={in}", &ctx).unwrap(),
+            vec!["--files=src/main.c src/my util.c"]
+        );
+        assert_eq!(expand_arg("{{literal}}", &ctx).unwrap(), vec!["{literal}"]);
+    }
+
+    #[test]
+    fn test_expand_leaves_shell_syntax_alone() {
+        let ctx = TemplateContext::default();
+
+        assert_eq!(
+            expand("${CC} {a,b} { true; } {{name}}", &ctx).unwrap(),
+            "${CC} {a,b} { true; } {name}"
+        );
+        assert_eq!(
+            expand("awk '{print}' x | sed -n '/a/{p}'", &ctx).unwrap(),
+            "awk '{print}' x | sed -n '/a/{p}'"
+        );
+        assert_eq!(expand_arg("{print}", &ctx).unwrap(), vec!["{print}"]);
+    }
+
+    #[test]
+    fn test_expand_rejects_unknown_and_non_deps() {
+        let ctx = TemplateContext::default();
+
+        assert_eq!(
+            expand("echo {deps.utils}", &ctx),
+            Err(TemplateError::UnknownPlaceholder("deps.utils".to_string()))
+        );
+        assert_eq!(
+            expand("echo {matrix.arch}", &ctx),
+            Err(TemplateError::UnknownPlaceholder("matrix.arch".to_string()))
+        );
+        assert_eq!(
+            expand("echo {deps.other.outputs}", &ctx),
+            Err(TemplateError::NotADependency(
+                "deps.other.outputs".to_string(),
+                "other".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("src/main.c"), "src/main.c");
+        assert_eq!(shell_quote("my file.c"), "'my file.c'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
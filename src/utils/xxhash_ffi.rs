@@ -1,13 +1,216 @@
+use std::io::Read;
+
+#[repr(C)]
+struct XXH64State {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct XXH3State {
+    _private: [u8; 0],
+}
+
 unsafe extern "C" {
     // *const u8 is the Rust equivalent of const void*
     fn XXH64(input: *const u8, length: usize, seed: u64) -> u64;
+
+    fn XXH64_createState() -> *mut XXH64State;
+    fn XXH64_freeState(state: *mut XXH64State) -> i32;
+    fn XXH64_reset(state: *mut XXH64State, seed: u64) -> i32;
+    fn XXH64_update(state: *mut XXH64State, input: *const u8, length: usize) -> i32;
+    fn XXH64_digest(state: *const XXH64State) -> u64;
+
+    fn XXH3_64bits(input: *const u8, length: usize) -> u64;
+
+    fn XXH3_createState() -> *mut XXH3State;
+    fn XXH3_freeState(state: *mut XXH3State) -> i32;
+    fn XXH3_64bits_reset(state: *mut XXH3State) -> i32;
+    fn XXH3_64bits_update(state: *mut XXH3State, input: *const u8, length: usize) -> i32;
+    fn XXH3_64bits_digest(state: *const XXH3State) -> u64;
 }
 
 pub fn xxhash64(data: &[u8]) -> u64 {
-    unsafe { XXH64(data.as_ptr(), data.len(), 0) } 
+    unsafe { XXH64(data.as_ptr(), data.len(), 0) }
+}
+
+/**
+ * Incremental XXH64 over data fed in chunks; yields the same digest as
+ * `xxhash64` over the concatenated input
+ */
+pub struct Xxh64 {
+    state: *mut XXH64State,
+}
+
+impl Xxh64 {
+    pub fn new() -> Self {
+        let state = unsafe { XXH64_createState() };
+        assert!(!state.is_null(), "failed to allocate XXH64 state");
+        unsafe { XXH64_reset(state, 0) };
+        Self { state }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        unsafe { XXH64_update(self.state, data.as_ptr(), data.len()) };
+    }
+
+    pub fn digest(&self) -> u64 {
+        unsafe { XXH64_digest(self.state) }
+    }
+}
+
+impl Default for Xxh64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Xxh64 {
+    fn drop(&mut self) {
+        unsafe { XXH64_freeState(self.state) };
+    }
+}
+
+// The state is owned exclusively by this handle
+unsafe impl Send for Xxh64 {}
+
+pub fn xxh3_64(data: &[u8]) -> u64 {
+    unsafe { XXH3_64bits(data.as_ptr(), data.len()) }
+}
+
+/**
+ * Incremental 64-bit XXH3 over data fed in chunks; yields the same digest as
+ * `xxh3_64` over the concatenated input
+ */
+pub struct Xxh3 {
+    state: *mut XXH3State,
+}
+
+impl Xxh3 {
+    pub fn new() -> Self {
+        let state = unsafe { XXH3_createState() };
+        assert!(!state.is_null(), "failed to allocate XXH3 state");
+        unsafe { XXH3_64bits_reset(state) };
+        Self { state }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        unsafe { XXH3_64bits_update(self.state, data.as_ptr(), data.len()) };
+    }
+
+    pub fn digest(&self) -> u64 {
+        unsafe { XXH3_64bits_digest(self.state) }
+    }
+}
+
+impl Default for Xxh3 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+impl Drop for Xxh3 {
+    fn drop(&mut self) {
+        unsafe { XXH3_freeState(self.state) };
+    }
+}
+
+// The state is owned exclusively by this handle
+unsafe impl Send for Xxh3 {}
+
+/**
+ * Feed a file to `update` in fixed-size chunks, without reading it into memory
+ */
+fn stream_file(path: &std::path::Path, mut update: impl FnMut(&[u8])) -> std::io::Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = [0u8; 64 * 1024];
+
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        update(&buffer[..bytes_read]);
+    }
+}
+
+/**
+ * XXH64 of a file's contents
+ */
 pub fn xxhash_file(path: &std::path::Path) -> std::io::Result<u64> {
-    let data = std::fs::read(path)?;
-    Ok(xxhash64(&data))
-}
\ No newline at end of file
+    let mut hasher = Xxh64::new();
+    stream_file(path, |chunk| hasher.update(chunk))?;
+    Ok(hasher.digest())
+}
+
+/**
+ * 64-bit XXH3 of a file's contents
+ */
+pub fn xxh3_file(path: &std::path::Path) -> std::io::Result<u64> {
+    let mut hasher = Xxh3::new();
+    stream_file(path, |chunk| hasher.update(chunk))?;
+    Ok(hasher.digest())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxhash64_reference_values() {
+        assert_eq!(xxhash64(b""), 0xEF46DB3751D8E999);
+        assert_eq!(xxhash64(b"abc"), 0x44BC2CF5AD770999);
+    }
+
+    #[test]
+    fn test_streaming_matches_one_shot() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        for chunk_size in [1, 7, 31, 32, 33, 500] {
+            let mut hasher = Xxh64::new();
+            for chunk in data.chunks(chunk_size) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.digest(), xxhash64(&data), "chunk size {chunk_size}");
+        }
+    }
+
+    #[test]
+    fn test_xxh3_matches_reference() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        // Every size class, up to several blocks of stripes
+        for len in (0..=260).chain([511, 512, 1024, 1025, 2048, 4096, 5000]) {
+            let input = &data[..len];
+            let expected = xxhash_rust::xxh3::xxh3_64(input);
+            assert_eq!(xxh3_64(input), expected, "length {len}");
+
+            for chunk_size in [1, 63, 64, 65, 256, 257, 1000] {
+                let mut hasher = Xxh3::new();
+                for chunk in input.chunks(chunk_size) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(
+                    hasher.digest(),
+                    expected,
+                    "length {len}, chunk size {chunk_size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_files_hash_like_their_contents() {
+        let dir = std::env::temp_dir().join("bagel_test_xxhash_stream");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Spans several read chunks and ends part-way through one
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let path = dir.join("big.bin");
+        std::fs::write(&path, &content).unwrap();
+
+        assert_eq!(xxhash_file(&path).unwrap(), xxhash64(&content));
+        assert_eq!(xxh3_file(&path).unwrap(), xxh3_64(&content));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
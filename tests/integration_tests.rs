@@ -1,4 +1,5 @@
 use bagel::core::{BuildSpec, BuildSpecError, TargetKind};
+use std::path::Path;
 
 #[test]
 fn test_empty_toml_succeeds_with_no_targets() {
@@ -118,3 +119,64 @@ inputs = ["file.c"]
 "#;
     assert!(BuildSpec::from_toml(toml).is_err());
 }
+
+#[test]
+fn test_unknown_config_fails() {
+    let toml = r#"
+[app]
+cmd = "gcc -o app main.c"
+inputs = ["main.c"]
+outputs = ["app"]
+
+[configs.release.env]
+CFLAGS = "-O2"
+"#;
+
+    let result = BuildSpec::load(toml, Path::new("."), Some("relase"));
+    match result {
+        Err(BuildSpecError::InvalidTarget(msg)) => {
+            assert!(msg.contains("Unknown config 'relase' (available: release)"));
+        }
+        _ => panic!("Expected InvalidTarget error"),
+    }
+}
+
+#[test]
+fn test_runnable_output() {
+    let toml = r#"
+[app]
+cmd = "gcc -o {out} main.c"
+inputs = ["main.c"]
+outputs = ["bin/app"]
+
+[tools]
+cmd = "make tools"
+inputs = ["tools.c"]
+outputs = ["bin/a", "bin/b"]
+
+[utils]
+cmd = "ar rcs {out} utils.o"
+inputs = ["utils.o"]
+outputs = ["libutils.a"]
+kind = "lib"
+
+[app_test]
+cmd = "bin/app --self-test"
+kind = "test"
+deps = ["app"]
+"#;
+
+    let spec = BuildSpec::from_toml(toml).unwrap();
+    let runnable = |name: &str| spec.get_target(name).unwrap().runnable_output(name);
+
+    assert_eq!(runnable("app").unwrap(), "bin/app");
+
+    let err = runnable("tools").unwrap_err().to_string();
+    assert!(err.contains("has 2 outputs"));
+
+    let err = runnable("utils").unwrap_err().to_string();
+    assert!(err.contains("is a lib"));
+
+    let err = runnable("app_test").unwrap_err().to_string();
+    assert!(err.contains("bagel test app_test"));
+}